@@ -1,7 +1,38 @@
-use serde::Deserialize;
+use std::fmt;
 
-#[derive(Deserialize, Clone, Debug)]
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(tag = "action")]
 pub enum InternalAction {
     RefreshController,
+    PushProfile {
+        profile: String,
+    },
+    PopProfile {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        profile: Option<String>,
+    },
+    Learn {
+        profile: String,
+    },
+    Explain,
+}
+
+impl fmt::Display for InternalAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InternalAction::RefreshController => write!(f, "refresh the controller"),
+            InternalAction::PushProfile { profile } => write!(f, "push profile {profile}"),
+            InternalAction::PopProfile {
+                profile: Some(profile),
+            } => write!(f, "pop profile {profile}"),
+            InternalAction::PopProfile { profile: None } => {
+                write!(f, "pop the last pushed profile")
+            }
+            InternalAction::Learn { profile } => write!(f, "learn a control into {profile}"),
+            InternalAction::Explain => write!(f, "explain the next control"),
+        }
+    }
 }
@@ -2,8 +2,11 @@ use std::{
     env::{consts::EXE_EXTENSION, current_exe},
     error::Error,
     process::Command,
+    time::Duration,
 };
 
+use midi_ctrl::{instance::Instance, utils::default_settings_dir};
+
 fn run() -> Result<(), Box<dyn Error>> {
     let mut path = match current_exe()?.parent() {
         Some(dir) => dir.to_path_buf(),
@@ -16,6 +19,20 @@ fn run() -> Result<(), Box<dyn Error>> {
     path.push("midi-ctrl");
     path.set_extension(EXE_EXTENSION);
 
+    // A daemon reconnects to the plugin by itself, anything else is restarted so that only one
+    // instance is using the devices.
+    match Instance::running(&default_settings_dir()?) {
+        Some(instance) if instance.daemon => {
+            println!(
+                "Using the running midi-ctrl daemon (process {}).",
+                instance.pid
+            );
+            return Ok(());
+        }
+        Some(instance) => instance.stop(Duration::from_secs(5))?,
+        None => (),
+    }
+
     Command::new(path).arg("embedded").spawn()?;
 
     Ok(())
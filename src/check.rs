@@ -0,0 +1,153 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::Path,
+};
+
+use crate::{
+    actions::InternalAction,
+    midi::device::{read_devices, Device},
+    profile::{read_profiles, Action, Profile},
+    state::params::StringParam,
+    utils::Problems,
+};
+
+/// Loads every device and profile without connecting to any MIDI ports and reports the problems
+/// found.
+pub fn check(root: &Path) -> Problems {
+    let mut problems = Problems::default();
+
+    let devices: HashMap<String, Device> = read_devices(root, &mut problems)
+        .into_iter()
+        .map(|(id, config)| (id, Device::offline(config)))
+        .collect();
+
+    let profiles = read_profiles(root, &devices, &mut problems);
+
+    check_parameters(&profiles, &mut problems);
+    check_reachability(&devices, &profiles, &mut problems);
+
+    problems
+}
+
+/// Unknown parameter names in conditions are treated as custom parameters so look for any that
+/// nothing ever sets.
+fn check_parameters(profiles: &BTreeMap<String, Profile>, problems: &mut Problems) {
+    let mut set = BTreeSet::new();
+    for profile in profiles.values() {
+        for action in profile.actions() {
+            if let Action::SetStringParameter {
+                parameter: StringParam::Custom(name),
+                ..
+            } = action
+            {
+                set.insert(name.as_str());
+            }
+        }
+    }
+
+    let mut unknown: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for profile in profiles.values() {
+        for condition in profile.conditions() {
+            for parameter in condition.string_parameters() {
+                if let StringParam::Custom(name) = parameter {
+                    if !set.contains(name.as_str()) {
+                        unknown
+                            .entry(name.as_str())
+                            .or_default()
+                            .insert(profile.id.as_str());
+                    }
+                }
+            }
+        }
+    }
+
+    for (name, profiles) in unknown {
+        let profiles: Vec<&str> = profiles.into_iter().collect();
+        problems.warning(format!(
+            "Parameter {} tested in profiles {} is not a known parameter and is never set by any profile",
+            name,
+            profiles.join(", ")
+        ));
+    }
+}
+
+fn check_reachability(
+    devices: &HashMap<String, Device>,
+    profiles: &BTreeMap<String, Profile>,
+    problems: &mut Problems,
+) {
+    let mut selected = BTreeSet::new();
+    for profile in profiles.values() {
+        for action in profile.actions() {
+            match action {
+                Action::SetStringParameter {
+                    parameter: StringParam::Profile,
+                    value,
+                } => {
+                    selected.insert(value.as_str());
+                }
+                Action::Internal(InternalAction::PushProfile { profile }) => {
+                    selected.insert(profile.as_str());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    for id in &selected {
+        if !profiles.contains_key(*id) {
+            problems.error(format!("An action selects unknown profile {}", id));
+        }
+    }
+
+    let extended: BTreeSet<&str> = profiles.values().filter_map(Profile::extends).collect();
+
+    for profile in profiles.values() {
+        if selected.contains(profile.id.as_str()) || extended.contains(profile.id.as_str()) {
+            continue;
+        }
+
+        let mut device_ids: Vec<&String> = devices
+            .keys()
+            .filter(|device| profile.applies_to(device))
+            .collect();
+        device_ids.sort();
+
+        if device_ids.is_empty() {
+            problems.warning(format!(
+                "Profile {} does not apply to any known device",
+                profile.id
+            ));
+            continue;
+        }
+
+        // A profile can never be automatically selected for a device if some other profile for
+        // the device is always available and would always be chosen over it.
+        let blockers: Vec<Option<&str>> = device_ids
+            .iter()
+            .map(|device| {
+                profiles
+                    .values()
+                    .find(|other| {
+                        other.id != profile.id
+                            && other.applies_to(device)
+                            && other.is_unconditional()
+                            && (other.priority() > profile.priority()
+                                || (other.priority() == profile.priority()
+                                    && other.id < profile.id))
+                    })
+                    .map(|other| other.id.as_str())
+            })
+            .collect();
+
+        if blockers.iter().all(Option::is_some) {
+            let blockers: BTreeSet<&str> = blockers.into_iter().flatten().collect();
+            let blockers: Vec<&str> = blockers.into_iter().collect();
+            problems.warning(format!(
+                "Profile {} can never be selected, it is always superseded by {} and no action selects it",
+                profile.id,
+                blockers.join(", ")
+            ));
+        }
+    }
+}
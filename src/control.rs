@@ -0,0 +1,250 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+
+use crate::profile::Action;
+use crate::state::params::{BoolParam, FloatParam};
+
+#[cfg(any(unix, feature = "http"))]
+use std::sync::mpsc::{channel, Sender};
+#[cfg(unix)]
+use std::{
+    fs::remove_file,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    thread,
+};
+
+#[cfg(unix)]
+use crate::events::EventBus;
+#[cfg(any(unix, feature = "http"))]
+use crate::ControlMessage;
+
+const SOCKET_FILE: &str = "midi-ctrl.sock";
+
+/// A request sent to the control socket, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "camelCase")]
+pub enum Request {
+    GetState,
+    GetProfile,
+    /// Sets an internal parameter.
+    Set {
+        parameter: String,
+        value: JsonValue,
+    },
+    Perform {
+        action: Action,
+    },
+    /// Reads the profiles again. Device configurations are only read at startup.
+    ReloadProfiles,
+    /// Streams every event until the connection is closed.
+    Events,
+    /// Asks MidiCtrl to exit.
+    Quit,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Response {
+    Ok(JsonValue),
+    Error(String),
+}
+
+impl Request {
+    /// Builds a `Set` request from a value typed on the command line, read as the parameter's type.
+    /// Custom parameters are strings so their values are never read as numbers.
+    pub fn set(parameter: String, value: &str) -> Result<Request, String> {
+        let name = json!(parameter);
+        let value = if serde_json::from_value::<FloatParam>(name.clone()).is_ok() {
+            json!(value
+                .parse::<f64>()
+                .map_err(|_| format!("{parameter} needs a number, not {value}"))?)
+        } else if serde_json::from_value::<BoolParam>(name).is_ok() {
+            json!(value
+                .parse::<bool>()
+                .map_err(|_| format!("{parameter} needs true or false, not {value}"))?)
+        } else {
+            json!(value)
+        };
+
+        Ok(Request::Set { parameter, value })
+    }
+}
+
+pub fn socket_path(root: &Path) -> PathBuf {
+    root.join(SOCKET_FILE)
+}
+
+/// Listens for connections to the control socket. The socket is removed when this is dropped.
+#[cfg(unix)]
+pub struct ControlServer {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+fn write_line<T: Serialize>(stream: &mut UnixStream, value: &T) -> Result<(), String> {
+    let mut data =
+        serde_json::to_vec(value).map_err(|e| format!("Failed to encode message: {e}"))?;
+    data.push(0x0a);
+
+    stream
+        .write_all(&data)
+        .and_then(|_| stream.flush())
+        .map_err(|e| format!("Failed writing to control socket: {e}"))
+}
+
+/// Passes a request to the controller and waits for its response.
+#[cfg(any(unix, feature = "http"))]
+pub(crate) fn answer(request: Request, sender: &Sender<ControlMessage>) -> Response {
+    let (reply, response) = channel();
+    if let Err(e) = sender.send(ControlMessage::Request { request, reply }) {
+        return Response::Error(format!("Controller is not running: {e}"));
+    }
+
+    response
+        .recv()
+        .unwrap_or_else(|e| Response::Error(format!("Controller did not respond: {e}")))
+}
+
+#[cfg(unix)]
+fn client(stream: UnixStream, sender: Sender<ControlMessage>, events: EventBus) {
+    let mut write_stream = match stream.try_clone() {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::error!("Failed to clone control stream: {}", e);
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str(&line) {
+            Ok(Request::Events) => {
+                for event in events.subscribe() {
+                    if write_line(&mut write_stream, &event).is_err() {
+                        return;
+                    }
+                }
+                return;
+            }
+            Ok(request) => answer(request, &sender),
+            Err(e) => Response::Error(format!("Failed to parse request: {e}")),
+        };
+
+        if let Err(e) = write_line(&mut write_stream, &response) {
+            log::debug!("{}", e);
+            return;
+        }
+    }
+}
+
+#[cfg(unix)]
+impl ControlServer {
+    pub(crate) fn start(
+        root: &Path,
+        sender: Sender<ControlMessage>,
+        events: EventBus,
+    ) -> Result<ControlServer, String> {
+        let path = socket_path(root);
+
+        // Only one instance runs for a settings directory so any existing socket is left over.
+        match remove_file(&path) {
+            Ok(()) => log::debug!("Removed old control socket {}", path.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("Failed to remove {}: {}", path.display(), e)),
+        }
+
+        let listener = UnixListener::bind(&path)
+            .map_err(|e| format!("Failed to listen on {}: {}", path.display(), e))?;
+        log::info!("Listening for commands on {}", path.display());
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let sender = sender.clone();
+                        let events = events.clone();
+                        thread::spawn(move || client(stream, sender, events));
+                    }
+                    Err(e) => log::error!("Failed to accept control connection: {}", e),
+                }
+            }
+        });
+
+        Ok(ControlServer { path })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        if let Err(e) = remove_file(&self.path) {
+            log::warn!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn connect(root: &Path, request: &Request) -> Result<BufReader<UnixStream>, String> {
+    let path = socket_path(root);
+    let mut stream = UnixStream::connect(&path).map_err(|e| {
+        format!(
+            "Failed to connect to {}, is MidiCtrl running? {}",
+            path.display(),
+            e
+        )
+    })?;
+
+    write_line(&mut stream, request)?;
+    Ok(BufReader::new(stream))
+}
+
+/// Sends a request other than `Request::Events` to the MidiCtrl running for the settings
+/// directory and returns its response.
+#[cfg(unix)]
+pub fn request(root: &Path, request: &Request) -> Result<Response, String> {
+    let mut line = String::new();
+    connect(root, request)?
+        .read_line(&mut line)
+        .map_err(|e| format!("Failed reading from control socket: {e}"))?;
+
+    serde_json::from_str(&line).map_err(|e| format!("Failed to parse response: {e}"))
+}
+
+/// Sends a request to the MidiCtrl running for the settings directory and prints the response,
+/// or every event for `Request::Events`. Returns whether the request succeeded.
+#[cfg(unix)]
+pub fn send_request(root: &Path, request: &Request) -> Result<bool, String> {
+    if let Request::Events = request {
+        for line in connect(root, request)?.lines() {
+            let line = line.map_err(|e| format!("Failed reading from control socket: {e}"))?;
+            println!("{line}");
+        }
+
+        return Ok(true);
+    }
+
+    match self::request(root, request)? {
+        Response::Ok(JsonValue::Null) => Ok(true),
+        Response::Ok(value) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&value).unwrap_or_default()
+            );
+            Ok(true)
+        }
+        Response::Error(e) => {
+            eprintln!("{e}");
+            Ok(false)
+        }
+    }
+}
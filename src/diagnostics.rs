@@ -0,0 +1,1014 @@
+use schemars::{JsonSchema, SchemaGenerator};
+use serde::de::DeserializeOwned;
+use serde_json::{error::Category, Map, Value};
+use std::{cmp::Reverse, fmt, path::Path};
+
+/// How close a string must be to a known value before it is suggested as a correction.
+const SUGGESTION_THRESHOLD: f64 = 0.65;
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Clone, Debug)]
+enum Problem {
+    Type {
+        expected: Vec<String>,
+        found: &'static str,
+    },
+    Value {
+        found: Value,
+        allowed: Vec<Value>,
+    },
+    Missing {
+        property: String,
+        similar: Option<String>,
+    },
+    Unknown {
+        property: String,
+        similar: Option<String>,
+    },
+    Range {
+        found: f64,
+        minimum: Option<f64>,
+        maximum: Option<f64>,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Issue {
+    path: Vec<Segment>,
+    problem: Problem,
+    variant: Option<String>,
+}
+
+impl Issue {
+    fn cost(&self) -> usize {
+        match self.problem {
+            Problem::Value {
+                ref found,
+                ref allowed,
+            } if suggest_value(found, allowed).is_some() => 1,
+            Problem::Missing {
+                similar: Some(_), ..
+            }
+            | Problem::Unknown {
+                similar: Some(_), ..
+            } => 1,
+            _ => 2,
+        }
+    }
+
+    fn message(&self) -> String {
+        let message = match self.problem {
+            Problem::Type {
+                ref expected,
+                found,
+            } => format!("expected {} but found {}", expected.join(" or "), found),
+            Problem::Value {
+                ref found,
+                ref allowed,
+            } => match suggest_value(found, allowed) {
+                Some(suggestion) => {
+                    format!("unknown value {}, did you mean {}?", found, suggestion)
+                }
+                None if allowed.len() == 1 => {
+                    format!("expected {} but found {}", allowed[0], found)
+                }
+                None if allowed.len() <= 8 => {
+                    let allowed: Vec<String> = allowed.iter().map(Value::to_string).collect();
+                    format!(
+                        "unknown value {}, expected one of {}",
+                        found,
+                        allowed.join(", ")
+                    )
+                }
+                None => format!("unknown value {}", found),
+            },
+            Problem::Missing {
+                ref property,
+                ref similar,
+            } => match similar {
+                Some(similar) => format!(
+                    "missing property \"{}\", found \"{}\", did you mean \"{}\"?",
+                    property, similar, property
+                ),
+                None => format!("missing property \"{}\"", property),
+            },
+            Problem::Unknown {
+                ref property,
+                ref similar,
+            } => match similar {
+                Some(similar) => format!(
+                    "unknown property \"{}\", did you mean \"{}\"?",
+                    property, similar
+                ),
+                None => format!("unknown property \"{}\"", property),
+            },
+            Problem::Range {
+                found,
+                minimum,
+                maximum,
+            } => match (minimum, maximum) {
+                (Some(minimum), Some(maximum)) => format!(
+                    "{} is out of range, expected a value from {} to {}",
+                    found, minimum, maximum
+                ),
+                (Some(minimum), None) => {
+                    format!("{} is out of range, expected at least {}", found, minimum)
+                }
+                (None, Some(maximum)) => {
+                    format!("{} is out of range, expected at most {}", found, maximum)
+                }
+                (None, None) => format!("{} is out of range", found),
+            },
+        };
+
+        match self.variant {
+            Some(ref variant) => format!("{} (closest match: {})", message, variant),
+            None => message,
+        }
+    }
+}
+
+/// The result of checking a value against a schema.
+#[derive(Default)]
+struct Fit {
+    issues: Vec<Issue>,
+    /// The number of object properties that the schema recognised.
+    matched: usize,
+    /// Whether the value was of entirely the wrong type.
+    type_mismatch: bool,
+}
+
+impl Fit {
+    fn merge(&mut self, other: Fit) {
+        self.issues.extend(other.issues);
+        self.matched += other.matched;
+        self.type_mismatch |= other.type_mismatch;
+    }
+
+    fn cost(&self) -> usize {
+        self.issues.iter().map(Issue::cost).sum()
+    }
+
+    fn depth(&self) -> usize {
+        self.issues
+            .iter()
+            .map(|issue| issue.path.len())
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+pub(crate) fn suggest<'a, I>(found: &str, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let found = found.to_lowercase();
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            (
+                strsim::normalized_damerau_levenshtein(&found, &candidate.to_lowercase()),
+                candidate,
+            )
+        })
+        .filter(|(score, _)| *score >= SUGGESTION_THRESHOLD)
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, candidate)| candidate)
+}
+
+fn suggest_value<'a>(found: &Value, allowed: &'a [Value]) -> Option<&'a Value> {
+    let found = found.as_str()?;
+    let suggestion = suggest(found, allowed.iter().filter_map(Value::as_str))?;
+    allowed
+        .iter()
+        .find(|value| value.as_str() == Some(suggestion))
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        name => type_name(value) == name,
+    }
+}
+
+struct Validator<'a> {
+    defs: Option<&'a Map<String, Value>>,
+}
+
+impl<'a> Validator<'a> {
+    fn resolve(&self, reference: &str) -> Option<&'a Value> {
+        let name = reference.strip_prefix("#/$defs/")?;
+        self.defs?.get(name)
+    }
+
+    fn issue(&self, fit: &mut Fit, path: &[Segment], problem: Problem) {
+        fit.issues.push(Issue {
+            path: path.to_vec(),
+            problem,
+            variant: None,
+        });
+    }
+
+    /// Checks a value against a schema. `inherited` holds the properties that an enclosing schema
+    /// for the same value knows about, for example those of a struct that flattens an enum.
+    fn validate(
+        &self,
+        schema: &'a Value,
+        value: &Value,
+        path: &mut Vec<Segment>,
+        inherited: &[&'a str],
+    ) -> Fit {
+        let mut fit = Fit::default();
+
+        let schema = match schema.as_object() {
+            Some(schema) => schema,
+            None => return fit,
+        };
+
+        let mut known = inherited.to_vec();
+        if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+            known.extend(properties.keys().map(String::as_str));
+        }
+
+        if let Some(target) = schema
+            .get("$ref")
+            .and_then(Value::as_str)
+            .and_then(|reference| self.resolve(reference))
+        {
+            fit.merge(self.validate(target, value, path, &known));
+        }
+
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(variants) = schema.get(keyword).and_then(Value::as_array) {
+                fit.merge(self.any_of(variants, value, path, &known));
+            }
+        }
+
+        if let Some(types) = schema.get("type") {
+            let expected: Vec<String> = match types {
+                Value::String(name) => vec![name.clone()],
+                Value::Array(names) => names
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(String::from)
+                    .collect(),
+                _ => Vec::new(),
+            };
+
+            if !expected.is_empty() && !expected.iter().any(|name| is_type(value, name)) {
+                fit.type_mismatch = true;
+                self.issue(
+                    &mut fit,
+                    path,
+                    Problem::Type {
+                        expected,
+                        found: type_name(value),
+                    },
+                );
+                return fit;
+            }
+        }
+
+        if let Some(constant) = schema.get("const") {
+            if constant != value {
+                self.issue(
+                    &mut fit,
+                    path,
+                    Problem::Value {
+                        found: value.clone(),
+                        allowed: vec![constant.clone()],
+                    },
+                );
+            }
+        }
+
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                self.issue(
+                    &mut fit,
+                    path,
+                    Problem::Value {
+                        found: value.clone(),
+                        allowed: allowed.clone(),
+                    },
+                );
+            }
+        }
+
+        if let Some(number) = value.as_f64() {
+            let minimum = schema.get("minimum").and_then(Value::as_f64);
+            let maximum = schema.get("maximum").and_then(Value::as_f64);
+            if minimum.is_some_and(|minimum| number < minimum)
+                || maximum.is_some_and(|maximum| number > maximum)
+            {
+                self.issue(
+                    &mut fit,
+                    path,
+                    Problem::Range {
+                        found: number,
+                        minimum,
+                        maximum,
+                    },
+                );
+            }
+        }
+
+        match value {
+            Value::Object(object) => {
+                self.validate_object(schema, object, path, inherited, &mut fit)
+            }
+            Value::Array(items) => {
+                if let Some(item_schema) = schema.get("items") {
+                    for (index, item) in items.iter().enumerate() {
+                        path.push(Segment::Index(index));
+                        fit.merge(self.validate(item_schema, item, path, &[]));
+                        path.pop();
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        fit
+    }
+
+    fn validate_object(
+        &self,
+        schema: &'a Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &mut Vec<Segment>,
+        inherited: &[&str],
+        fit: &mut Fit,
+    ) {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let additional = schema
+            .get("additionalProperties")
+            .filter(|additional| additional.is_object());
+
+        let mut unknown = Vec::new();
+        for (key, value) in object {
+            let property_schema = match properties.and_then(|properties| properties.get(key)) {
+                Some(property_schema) => property_schema,
+                None => match additional {
+                    Some(additional) => additional,
+                    None => {
+                        if !inherited.contains(&key.as_str()) {
+                            unknown.push(key.as_str());
+                        }
+                        continue;
+                    }
+                },
+            };
+
+            fit.matched += 1;
+            path.push(Segment::Key(key.clone()));
+            let property_fit = self.validate(property_schema, value, path, &[]);
+            path.pop();
+            fit.issues.extend(property_fit.issues);
+            fit.matched += property_fit.matched;
+        }
+
+        let mut explained = Vec::new();
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for property in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(property) {
+                    let similar = suggest(property, unknown.iter().copied()).map(String::from);
+                    explained.extend(similar.clone());
+                    self.issue(
+                        fit,
+                        path,
+                        Problem::Missing {
+                            property: String::from(property),
+                            similar,
+                        },
+                    );
+                }
+            }
+        }
+
+        // Serde ignores unknown properties but they are usually misspellings of optional ones. When
+        // the schema refers to others for the same value those check the properties instead.
+        let delegated = ["$ref", "anyOf", "oneOf"]
+            .iter()
+            .any(|keyword| schema.contains_key(*keyword));
+        let open = schema
+            .get("additionalProperties")
+            .is_some_and(|additional| additional != &Value::Bool(false));
+        if properties.is_some() && !delegated && !open {
+            let missing: Vec<&str> = properties
+                .into_iter()
+                .flat_map(|properties| properties.keys())
+                .map(String::as_str)
+                .filter(|property| !object.contains_key(*property))
+                .collect();
+
+            for property in unknown {
+                if explained.iter().any(|similar| similar == property) {
+                    continue;
+                }
+
+                path.push(Segment::Key(String::from(property)));
+                self.issue(
+                    fit,
+                    path,
+                    Problem::Unknown {
+                        property: String::from(property),
+                        similar: suggest(property, missing.iter().copied()).map(String::from),
+                    },
+                );
+                path.pop();
+            }
+        }
+    }
+
+    /// Finds the variant that best matches the value. If none match exactly then the issues from
+    /// the closest variant are reported.
+    fn any_of(
+        &self,
+        variants: &'a [Value],
+        value: &Value,
+        path: &mut Vec<Segment>,
+        inherited: &[&'a str],
+    ) -> Fit {
+        let mut fits: Vec<(Option<&str>, Fit)> = Vec::new();
+
+        for variant in variants {
+            let fit = self.validate(variant, value, path, inherited);
+            if fit.issues.is_empty() {
+                return fit;
+            }
+
+            let title = variant.get("title").and_then(Value::as_str);
+            fits.push((title, fit));
+        }
+
+        if fits.is_empty() {
+            return Fit::default();
+        }
+
+        // When the variants only differ by the value of a single field, such as the tag of an
+        // internally tagged enum, report all the possible values together.
+        if let Some(merged) = merge_values(&fits) {
+            return merged;
+        }
+
+        let (title, mut fit) = fits
+            .into_iter()
+            .min_by_key(|(_, fit)| {
+                (
+                    fit.type_mismatch,
+                    Reverse(fit.matched),
+                    fit.cost(),
+                    Reverse(fit.depth()),
+                )
+            })
+            .unwrap();
+
+        if let Some(title) = title {
+            for issue in fit.issues.iter_mut() {
+                if issue.variant.is_none() {
+                    issue.variant = Some(String::from(title));
+                }
+            }
+        }
+
+        fit
+    }
+}
+
+fn merge_values(fits: &[(Option<&str>, Fit)]) -> Option<Fit> {
+    let mut found = None;
+    let mut path: Option<&[Segment]> = None;
+    let mut allowed: Vec<Value> = Vec::new();
+
+    for (_, fit) in fits {
+        if fit.issues.len() != 1 {
+            return None;
+        }
+
+        let issue = &fit.issues[0];
+        if let Problem::Value {
+            found: ref value,
+            allowed: ref values,
+        } = issue.problem
+        {
+            if path.is_some_and(|path| path_string(path) != path_string(&issue.path)) {
+                return None;
+            }
+            path = Some(&issue.path);
+            found = Some(value.clone());
+            for value in values {
+                if !allowed.contains(value) {
+                    allowed.push(value.clone());
+                }
+            }
+        } else {
+            return None;
+        }
+    }
+
+    Some(Fit {
+        issues: vec![Issue {
+            path: path?.to_vec(),
+            problem: Problem::Value {
+                found: found?,
+                allowed,
+            },
+            variant: None,
+        }],
+        matched: fits
+            .iter()
+            .map(|(_, fit)| fit.matched)
+            .max()
+            .unwrap_or_default(),
+        type_mismatch: false,
+    })
+}
+
+fn path_string(path: &[Segment]) -> String {
+    let mut result = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(key) => {
+                if !result.is_empty() {
+                    result.push('.');
+                }
+                result.push_str(key);
+            }
+            Segment::Index(index) => result.push_str(&format!("[{}]", index)),
+        }
+    }
+    result
+}
+
+/// Finds where a value lives in the source text.
+struct Locator<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Locator<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn whitespace(&mut self) {
+        loop {
+            let rest = self.text.as_bytes().get(self.pos..).unwrap_or_default();
+            if rest.starts_with(b"//") {
+                self.pos += rest
+                    .iter()
+                    .position(|byte| *byte == b'\n')
+                    .unwrap_or(rest.len());
+            } else if rest.starts_with(b"/*") {
+                self.pos += rest
+                    .windows(2)
+                    .position(|pair| pair == b"*/")
+                    .map_or(rest.len(), |end| end + 2);
+            } else if matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+                self.pos += 1;
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        self.whitespace();
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Reads a string in either double quotes or, for JSON5, single quotes.
+    fn string(&mut self) -> Option<String> {
+        self.whitespace();
+        let start = self.pos;
+        let quote = self.peek().filter(|byte| matches!(byte, b'"' | b'\''))?;
+        self.pos += 1;
+        loop {
+            match self.peek()? {
+                b'\\' => self.pos += 2,
+                byte if byte == quote => {
+                    self.pos += 1;
+                    let string = &self.text[start..self.pos];
+                    return if quote == b'"' {
+                        serde_json::from_str(string).ok()
+                    } else {
+                        json5::from_str(string).ok()
+                    };
+                }
+                _ => self.pos += 1,
+            }
+        }
+    }
+
+    /// Reads an object key, JSON5 allows these to be bare identifiers.
+    fn key(&mut self) -> Option<String> {
+        self.whitespace();
+        if matches!(self.peek()?, b'"' | b'\'') {
+            return self.string();
+        }
+
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'$'))
+        {
+            self.pos += 1;
+        }
+
+        if self.pos == start {
+            None
+        } else {
+            Some(String::from(&self.text[start..self.pos]))
+        }
+    }
+
+    fn skip_value(&mut self) -> Option<()> {
+        self.whitespace();
+        match self.peek()? {
+            b'"' | b'\'' => {
+                self.string()?;
+            }
+            b'{' | b'[' => {
+                let mut depth = 0;
+                loop {
+                    match self.peek()? {
+                        b'"' | b'\'' => {
+                            self.string()?;
+                            continue;
+                        }
+                        b'{' | b'[' => depth += 1,
+                        b'}' | b']' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                self.pos += 1;
+                                return Some(());
+                            }
+                        }
+                        _ => (),
+                    }
+                    self.pos += 1;
+                }
+            }
+            _ => {
+                while !matches!(
+                    self.peek(),
+                    None | Some(b',' | b']' | b'}' | b' ' | b'\t' | b'\n' | b'\r')
+                ) {
+                    self.pos += 1;
+                }
+            }
+        }
+
+        Some(())
+    }
+
+    fn find(&mut self, path: &[Segment]) -> Option<usize> {
+        self.whitespace();
+        let (segment, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return Some(self.pos),
+        };
+
+        match segment {
+            Segment::Key(key) => {
+                self.expect(b'{')?;
+                loop {
+                    if self.key()? == *key {
+                        self.expect(b':')?;
+                        return self.find(rest);
+                    }
+                    self.expect(b':')?;
+                    self.skip_value()?;
+                    self.expect(b',')?;
+                }
+            }
+            Segment::Index(index) => {
+                self.expect(b'[')?;
+                for _ in 0..*index {
+                    self.skip_value()?;
+                    self.expect(b',')?;
+                }
+                self.find(rest)
+            }
+        }
+    }
+}
+
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = match before.rfind('\n') {
+        Some(start) => before[start + 1..].chars().count() + 1,
+        None => before.chars().count() + 1,
+    };
+    (line, column)
+}
+
+/// The formats that configuration files can be written in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Json,
+    /// Also covers JSON with comments.
+    Json5,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Format::Json),
+            "json5" | "jsonc" => Some(Format::Json5),
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Reads the text into a generic value, the positions of syntax errors are reported where
+    /// the parser provides them.
+    fn read(&self, text: &str) -> Result<Value, Diagnostic> {
+        match self {
+            Format::Json => serde_json::from_str(text).map_err(|e| Diagnostic {
+                location: Some((e.line(), e.column())),
+                path: String::new(),
+                message: e.to_string(),
+            }),
+            Format::Json5 => json5::from_str(text).map_err(|e| match e {
+                json5::Error::Message { msg, location } => Diagnostic {
+                    location: location.map(|location| (location.line, location.column)),
+                    path: String::new(),
+                    // Syntax errors include a rendering of the source, keep just the summary.
+                    message: msg
+                        .lines()
+                        .rev()
+                        .find_map(|line| line.trim().strip_prefix("= "))
+                        .map(String::from)
+                        .unwrap_or(msg),
+                },
+            }),
+            Format::Toml => toml::from_str(text).map_err(|e| Diagnostic {
+                location: e.span().map(|span| line_column(text, span.start)),
+                path: String::new(),
+                message: String::from(e.message()),
+            }),
+            Format::Yaml => serde_yaml::from_str(text).map_err(|e| {
+                let location = e
+                    .location()
+                    .map(|location| (location.line(), location.column()));
+                let mut message = e.to_string();
+                if let Some((line, column)) = location {
+                    message = message.replace(&format!(" at line {} column {}", line, column), "");
+                }
+
+                Diagnostic {
+                    location,
+                    path: String::new(),
+                    message,
+                }
+            }),
+        }
+    }
+}
+
+/// A single problem found in a configuration file.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub location: Option<(usize, usize)>,
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.location, self.path.is_empty()) {
+            (Some((line, column)), true) => write!(f, "at line {}, column {}", line, column)?,
+            (Some((line, column)), false) => {
+                write!(f, "at line {}, column {} ({})", line, column, self.path)?
+            }
+            (None, false) => write!(f, "at {}", self.path)?,
+            (None, true) => write!(f, "at the top level")?,
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let diagnostics: Vec<String> = self.diagnostics.iter().map(|d| d.to_string()).collect();
+        write!(f, "{}", diagnostics.join("; and "))
+    }
+}
+
+/// Source positions can only be found for the JSON style formats.
+fn diagnose<T>(value: &Value, text: Option<&str>) -> Vec<Diagnostic>
+where
+    T: JsonSchema,
+{
+    let schema = SchemaGenerator::default().into_root_schema_for::<T>();
+    let validator = Validator {
+        defs: schema.get("$defs").and_then(Value::as_object),
+    };
+
+    let fit = validator.validate(schema.as_value(), value, &mut Vec::new(), &[]);
+
+    let mut diagnostics: Vec<Diagnostic> = fit
+        .issues
+        .iter()
+        .map(|issue| Diagnostic {
+            location: text.and_then(|text| {
+                Locator { text, pos: 0 }
+                    .find(&issue.path)
+                    .map(|offset| line_column(text, offset))
+            }),
+            path: path_string(&issue.path),
+            message: issue.message(),
+        })
+        .collect();
+
+    diagnostics.sort_by_key(|diagnostic| diagnostic.location);
+    diagnostics
+}
+
+/// Parses a configuration file. Serde can only say that an untagged enum did not match any of its
+/// variants so on failure the file is checked against its schema to find out what is wrong.
+pub fn parse<T>(text: &str, format: Format) -> Result<T, ParseError>
+where
+    T: DeserializeOwned + JsonSchema,
+{
+    if format == Format::Json {
+        let error = match serde_json::from_str(text) {
+            Ok(result) => return Ok(result),
+            Err(e) => e,
+        };
+
+        let diagnostics = match serde_json::from_str(text) {
+            Ok(value) if error.classify() == Category::Data => diagnose::<T>(&value, Some(text)),
+            _ => Vec::new(),
+        };
+
+        return Err(ParseError {
+            diagnostics: if diagnostics.is_empty() {
+                vec![Diagnostic {
+                    location: Some((error.line(), error.column())),
+                    path: String::new(),
+                    message: error.to_string(),
+                }]
+            } else {
+                diagnostics
+            },
+        });
+    }
+
+    let value = format.read(text).map_err(|diagnostic| ParseError {
+        diagnostics: vec![diagnostic],
+    })?;
+
+    let error = match serde_json::from_value(value.clone()) {
+        Ok(result) => return Ok(result),
+        Err(e) => e,
+    };
+
+    let text = if format == Format::Json5 {
+        Some(text)
+    } else {
+        None
+    };
+
+    let diagnostics = diagnose::<T>(&value, text);
+    Err(ParseError {
+        diagnostics: if diagnostics.is_empty() {
+            vec![Diagnostic {
+                location: None,
+                path: String::new(),
+                message: error.to_string(),
+            }]
+        } else {
+            diagnostics
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+
+    use super::{parse, Format, ParseError};
+
+    #[derive(Deserialize, JsonSchema, Debug)]
+    #[allow(dead_code)]
+    struct Knob {
+        name: String,
+        #[serde(default)]
+        colour: Option<String>,
+        #[serde(default)]
+        steps: Vec<u8>,
+    }
+
+    fn messages(result: Result<Knob, ParseError>) -> Vec<String> {
+        result
+            .unwrap_err()
+            .diagnostics
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn json() {
+        let text = "{\n  \"name\": \"pan\",\n  \"steps\": [1, 300]\n}";
+        assert_eq!(
+            messages(parse(text, Format::Json)),
+            vec!["at line 3, column 16 (steps[1]): 300 is out of range, expected a value from 0 to 255"]
+        );
+    }
+
+    #[test]
+    fn unknown_property() {
+        let text = "{\n  \"name\": 5,\n  \"color\": \"red\"\n}";
+        assert_eq!(
+            messages(parse(text, Format::Json)),
+            vec![
+                "at line 2, column 11 (name): expected string but found number",
+                "at line 3, column 12 (color): unknown property \"color\", did you mean \"colour\"?",
+            ]
+        );
+    }
+
+    #[test]
+    fn json5() {
+        let text = "{\n  // The knob on the left.\n  name: 'pan',\n  steps: [1, 'two'],\n}";
+        assert_eq!(
+            messages(parse(text, Format::Json5)),
+            vec!["at line 4, column 14 (steps[1]): expected integer but found string"]
+        );
+    }
+
+    #[test]
+    fn json5_syntax() {
+        let errors = messages(parse("{\n  name: 'pan',,\n}", Format::Json5));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("at line 2, column"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn yaml() {
+        let text = "name: pan\ncolor: red\nsteps: [1, 300]\n";
+        assert_eq!(
+            messages(parse(text, Format::Yaml)),
+            vec![
+                "at steps[1]: 300 is out of range, expected a value from 0 to 255",
+                "at color: unknown property \"color\", did you mean \"colour\"?",
+            ]
+        );
+    }
+
+    #[test]
+    fn toml() {
+        let text = "steps = [1, 2]\n";
+        assert_eq!(
+            messages(parse(text, Format::Toml)),
+            vec!["at the top level: missing property \"name\""]
+        );
+
+        let errors = messages(parse("name = \n", Format::Toml));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("at line 1, column"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn valid() {
+        let knob: Knob = parse("name = \"pan\"\nsteps = [1]\n", Format::Toml).unwrap();
+        assert_eq!(knob.name, "pan");
+        assert_eq!(knob.steps, vec![1]);
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+use crate::{midi::controls::KeyState, profile::Action};
+
+/// Something that happened in the controller, for anything watching it.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
+    /// The parameters from Lightroom were cleared.
+    Reset,
+    /// Parameters changed, removed parameters are null.
+    State {
+        values: BTreeMap<String, JsonValue>,
+    },
+    Profile {
+        profile: Option<String>,
+    },
+    ContinuousChange {
+        device: String,
+        control: String,
+        layer: String,
+        value: f64,
+    },
+    KeyChange {
+        device: String,
+        control: String,
+        layer: String,
+        state: KeyState,
+    },
+    Action {
+        action: Action,
+    },
+}
+
+/// Passes events to every subscriber.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        Default::default()
+    }
+
+    /// Returns a receiver for every event published from now on. Dropping it unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        match self.subscribers.lock() {
+            Ok(mut subscribers) => subscribers.push(sender),
+            Err(e) => log::error!("Failed to lock event subscribers: {}", e),
+        }
+        receiver
+    }
+
+    pub fn publish(&self, event: Event) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::{collections::BTreeMap, fs::read_to_string, path::Path};
+
+use serde_json::Value as JsonValue;
+
+use crate::{
+    diagnostics::{parse, Format},
+    scenario::{simulated_devices, state_values},
+    state::persist::PersistedState,
+    ControlMessage, Controller,
+};
+
+/// Prints which profile would be selected for the device with the parameters in the state file
+/// and what the control would do.
+pub fn explain(
+    root: &Path,
+    path: &Path,
+    device: &str,
+    control: &str,
+    layer: &str,
+) -> Result<(), String> {
+    let text =
+        read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let format = Format::from_path(path).unwrap_or(Format::Json);
+    let state: BTreeMap<String, JsonValue> = parse(&text, format).map_err(|e| {
+        format!(
+            "Failed to parse {} {}",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            e
+        )
+    })?;
+
+    let (mut controller, _) = Controller::detached(root, PersistedState::default(), |sender| {
+        simulated_devices(root, sender)
+    })?;
+    controller.handle(ControlMessage::StateChange {
+        values: state_values(&state)?,
+    });
+
+    for line in controller.explain(device, control, layer) {
+        println!("{line}");
+    }
+
+    Ok(())
+}
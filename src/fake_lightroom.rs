@@ -0,0 +1,362 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::mpsc::{channel, Sender},
+    thread,
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+/// The number of photos that can be moved between with the NextPhoto and PreviousPhoto actions.
+const PHOTOS: usize = 5;
+
+#[derive(Deserialize)]
+struct ParamInfo {
+    parameter: String,
+    setting: Option<String>,
+    min: f64,
+    max: f64,
+}
+
+#[derive(Clone)]
+struct Photo {
+    develop: BTreeMap<String, f64>,
+    rating: f64,
+    pick_status: i8,
+}
+
+/// A stand-in for the parts of Lightroom that the plugin exposes. Develop parameters are held as
+/// values between 0 and 1 in the same way that the plugin reports them.
+pub struct Model {
+    photos: Vec<Photo>,
+    current: usize,
+    module: String,
+    undo: Vec<Vec<Photo>>,
+    redo: Vec<Vec<Photo>>,
+    sent: BTreeMap<String, JsonValue>,
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Model {
+    pub fn new() -> Model {
+        let params: Vec<ParamInfo> =
+            serde_json::from_str(include_str!("../MidiCtrl.lrplugin/params.json"))
+                .expect("The plugin's params.json should be valid");
+
+        let develop: BTreeMap<String, f64> = params
+            .into_iter()
+            .map(|info| {
+                // The plugin names parameters after their develop setting without the process
+                // version.
+                let name = match info.setting {
+                    Some(setting) => setting.trim_end_matches("2012").to_string(),
+                    None => info.parameter,
+                };
+                let value = (0.0_f64.clamp(info.min, info.max) - info.min) / (info.max - info.min);
+                (name, value)
+            })
+            .collect();
+
+        Model {
+            photos: vec![
+                Photo {
+                    develop,
+                    rating: 0.0,
+                    pick_status: 0,
+                };
+                PHOTOS
+            ],
+            current: 0,
+            module: String::from("library"),
+            undo: Vec::new(),
+            redo: Vec::new(),
+            sent: BTreeMap::new(),
+        }
+    }
+
+    /// The current value of every parameter, as `Params.getParams` returns them.
+    pub fn params(&self) -> BTreeMap<String, JsonValue> {
+        let mut params = BTreeMap::new();
+        let photo = &self.photos[self.current];
+
+        for (name, value) in &photo.develop {
+            params.insert(name.clone(), json!(value));
+        }
+        params.insert(String::from("Rating"), json!(photo.rating));
+        params.insert(String::from("Picked"), json!(photo.pick_status == 1));
+        params.insert(String::from("Rejected"), json!(photo.pick_status == -1));
+        params.insert(String::from("Module"), json!(self.module));
+        params.insert(String::from("CanUndo"), json!(!self.undo.is_empty()));
+        params.insert(String::from("CanRedo"), json!(!self.redo.is_empty()));
+
+        params
+    }
+
+    /// Forgets what has been sent so the next delta includes every parameter.
+    pub fn rebuild(&mut self) {
+        self.sent.clear();
+    }
+
+    /// Returns the parameters that have changed since the last call, as `State.lua` does.
+    pub fn delta(&mut self) -> Vec<JsonValue> {
+        let params = self.params();
+        let mut values = Vec::new();
+
+        for (name, value) in &params {
+            if self.sent.get(name) != Some(value) {
+                values.push(json!({ "parameter": name, "value": value }));
+            }
+        }
+
+        for name in self.sent.keys() {
+            if !params.contains_key(name) {
+                values.push(json!({ "parameter": name, "value": JsonValue::Null }));
+            }
+        }
+
+        self.sent = params;
+        values
+    }
+
+    fn change(&mut self) -> &mut Photo {
+        self.undo.push(self.photos.clone());
+        self.redo.clear();
+        &mut self.photos[self.current]
+    }
+
+    fn set_value(&mut self, parameter: &str, value: &JsonValue) -> Result<(), String> {
+        let current = &self.photos[self.current];
+
+        if current.develop.contains_key(parameter) {
+            let value = value
+                .as_f64()
+                .ok_or_else(|| format!("Expected a number for {parameter} but got {value}"))?
+                .clamp(0.0, 1.0);
+            self.module = String::from("develop");
+            self.change().develop.insert(String::from(parameter), value);
+            return Ok(());
+        }
+
+        match parameter {
+            "Module" => {
+                self.module = value
+                    .as_str()
+                    .ok_or_else(|| format!("Expected a string for Module but got {value}"))?
+                    .to_string();
+            }
+            "Rating" => {
+                let rating = value
+                    .as_f64()
+                    .ok_or_else(|| format!("Expected a number for Rating but got {value}"))?;
+                self.change().rating = rating.clamp(0.0, 5.0).round();
+            }
+            "Picked" | "Rejected" => {
+                let set = value
+                    .as_bool()
+                    .ok_or_else(|| format!("Expected a boolean for {parameter} but got {value}"))?;
+                let status = if parameter == "Picked" { 1 } else { -1 };
+                if set {
+                    self.change().pick_status = status;
+                } else if current.pick_status == status {
+                    self.change().pick_status = 0;
+                }
+            }
+            "CanUndo" | "CanRedo" => {
+                return Err(format!("Attempt to set a readonly parameter {parameter}"))
+            }
+            _ => return Err(format!("Attempt to set an unknown parameter {parameter}")),
+        }
+
+        Ok(())
+    }
+
+    fn perform_action(&mut self, action: &str) -> Result<(), String> {
+        match action {
+            "NextPhoto" => self.current = (self.current + 1).min(self.photos.len() - 1),
+            "PreviousPhoto" => self.current = self.current.saturating_sub(1),
+            "Undo" => {
+                if let Some(photos) = self.undo.pop() {
+                    self.redo.push(std::mem::replace(&mut self.photos, photos));
+                }
+            }
+            "Redo" => {
+                if let Some(photos) = self.redo.pop() {
+                    self.undo.push(std::mem::replace(&mut self.photos, photos));
+                }
+            }
+            _ => return Err(format!("Unknown action {action}")),
+        }
+
+        Ok(())
+    }
+
+    /// Applies a message sent by MidiCtrl to the plugin.
+    pub fn apply(&mut self, message: &JsonValue) -> Result<(), String> {
+        let field = |name: &str| {
+            message
+                .get(name)
+                .ok_or_else(|| format!("Message is missing {name}: {message}"))
+        };
+
+        match field("type")?.as_str() {
+            Some("setValue") => {
+                let parameter = field("parameter")?
+                    .as_str()
+                    .ok_or_else(|| format!("Expected a parameter name: {message}"))?;
+                self.set_value(parameter, field("value")?)
+            }
+            Some("action") => {
+                let action = field("action")?
+                    .as_str()
+                    .ok_or_else(|| format!("Expected an action name: {message}"))?;
+                self.perform_action(action)
+            }
+            Some("notification") => Ok(()),
+            _ => Err(format!("Unknown message: {message}")),
+        }
+    }
+}
+
+enum Event {
+    Message(JsonValue),
+    Closed,
+}
+
+fn accept(listener: &TcpListener) -> Result<TcpStream, String> {
+    listener
+        .accept()
+        .map(|(stream, _)| stream)
+        .map_err(|e| format!("Failed to accept connection: {e}"))
+}
+
+fn send_state(stream: &mut TcpStream, values: Vec<JsonValue>) -> Result<(), String> {
+    if values.is_empty() {
+        return Ok(());
+    }
+
+    for value in &values {
+        println!("< {} = {}", value["parameter"], value["value"]);
+    }
+
+    send(stream, &json!({ "type": "state", "values": values }))
+}
+
+fn send(stream: &mut TcpStream, message: &JsonValue) -> Result<(), String> {
+    let mut data =
+        serde_json::to_vec(message).map_err(|e| format!("Failed to encode IPC message: {e}"))?;
+    data.push(0x0a);
+
+    stream
+        .write_all(&data)
+        .and_then(|_| stream.flush())
+        .map_err(|e| format!("Failed writing to IPC stream: {e}"))
+}
+
+fn read_messages(stream: TcpStream, sender: Sender<Event>) {
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        match serde_json::from_str(&line) {
+            Ok(message) => {
+                if sender.send(Event::Message(message)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => log::error!("Failed to parse IPC message: {}", e),
+        }
+    }
+
+    let _ = sender.send(Event::Closed);
+}
+
+/// Drains the acknowledgements that MidiCtrl sends back for each state message.
+fn read_acknowledgements(mut stream: TcpStream, sender: Sender<Event>) {
+    let mut buffer = [0; 256];
+    while let Ok(count) = stream.read(&mut buffer) {
+        if count == 0 {
+            break;
+        }
+    }
+
+    let _ = sender.send(Event::Closed);
+}
+
+fn session(
+    model: &mut Model,
+    mut sender_stream: TcpStream,
+    receiver_stream: TcpStream,
+) -> Result<(), String> {
+    let (sender, events) = channel();
+
+    let ack_stream = sender_stream
+        .try_clone()
+        .map_err(|e| format!("Failed to clone TCP stream: {e}"))?;
+    let ack_sender = sender.clone();
+    thread::spawn(move || read_acknowledgements(ack_stream, ack_sender));
+    thread::spawn(move || read_messages(receiver_stream, sender));
+
+    send(&mut sender_stream, &json!({ "type": "reset" }))?;
+    model.rebuild();
+    send_state(&mut sender_stream, model.delta())?;
+
+    for event in events {
+        match event {
+            Event::Message(message) => {
+                println!("> {message}");
+                if let Some(text) = message.get("message").and_then(JsonValue::as_str) {
+                    println!("Notification: {text}");
+                }
+
+                if let Err(e) = model.apply(&message) {
+                    log::error!("{}", e);
+                }
+                send_state(&mut sender_stream, model.delta())?;
+            }
+            Event::Closed => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Pretends to be the Lightroom plugin. Listens on the ports that the plugin uses and responds to
+/// MidiCtrl in the same way.
+pub fn fake_lightroom(sender_port: u16, receiver_port: u16) -> Result<(), String> {
+    let sender_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, sender_port))
+        .map_err(|e| format!("Failed to listen on port {sender_port}: {e}"))?;
+    let receiver_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, receiver_port))
+        .map_err(|e| format!("Failed to listen on port {receiver_port}: {e}"))?;
+
+    let mut model = Model::new();
+
+    loop {
+        println!("Waiting for MidiCtrl to connect");
+
+        let receiver_thread = {
+            let listener = receiver_listener
+                .try_clone()
+                .map_err(|e| format!("Failed to clone listener: {e}"))?;
+            thread::spawn(move || accept(&listener))
+        };
+        let sender_stream = accept(&sender_listener)?;
+        let receiver_stream = receiver_thread
+            .join()
+            .map_err(|_| String::from("Failed to accept connection"))??;
+
+        println!("MidiCtrl connected");
+        if let Err(e) = session(&mut model, sender_stream, receiver_stream) {
+            log::error!("{}", e);
+        }
+        println!("MidiCtrl disconnected");
+    }
+}
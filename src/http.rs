@@ -0,0 +1,470 @@
+use std::{
+    fs::{read_to_string, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::{mpsc::Sender, Arc},
+    thread,
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+use crate::{
+    control::{answer, Request, Response},
+    events::EventBus,
+    profile::Action,
+    ControlMessage,
+};
+
+/// Where the server listens unless told otherwise. Only reachable from this machine.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:61330";
+
+/// How long a WebSocket waits for a message before checking for events to send.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Holds the token that clients must send, only readable by the user running MidiCtrl.
+const TOKEN_FILE: &str = "midi-ctrl.token";
+
+/// The largest request body accepted, anything larger is rejected without reading it.
+const MAX_BODY: usize = 64 * 1024;
+
+/// The longest request or header line accepted.
+const MAX_LINE: usize = 8 * 1024;
+
+/// The most headers accepted in a request.
+const MAX_HEADERS: usize = 100;
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Option<String>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// A request that is refused before it is handled.
+struct Rejection {
+    status: &'static str,
+    message: String,
+}
+
+impl Rejection {
+    fn new(status: &'static str, message: &str) -> Rejection {
+        Rejection {
+            status,
+            message: String::from(message),
+        }
+    }
+
+    fn bad_request(message: String) -> Rejection {
+        Rejection {
+            status: "400 Bad Request",
+            message,
+        }
+    }
+}
+
+/// What a request has to match to be handled.
+struct Server {
+    address: SocketAddr,
+    token: String,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The token from the `Authorization` header, or the `token` query parameter for browsers
+    /// which cannot add headers to a WebSocket.
+    fn token(&self) -> Option<&str> {
+        if let Some(token) = self
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            return Some(token.trim());
+        }
+
+        self.query
+            .as_deref()?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    }
+}
+
+/// Reads the token from the settings directory, creating it the first time.
+fn read_token(root: &Path) -> Result<String, String> {
+    let path = root.join(TOKEN_FILE);
+    match read_to_string(&path) {
+        Ok(token) if !token.trim().is_empty() => return Ok(token.trim().to_string()),
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+
+    let token = format!("{:032x}", rand::random::<u128>());
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options
+        .open(&path)
+        .and_then(|mut file| file.write_all(token.as_bytes()))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    Ok(token)
+}
+
+/// Whether the host is this machine, either by name or address.
+fn is_loopback(host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .parse::<IpAddr>()
+            .map(|ip| ip.is_loopback())
+            .unwrap_or(false)
+}
+
+/// Compares every byte so that the time taken doesn't reveal how much of a guessed token was
+/// right.
+fn tokens_match(token: &str, expected: &str) -> bool {
+    token.len() == expected.len()
+        && token
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Splits a `Host` header or the authority of an origin into the host and port.
+fn split_host(authority: &str) -> (&str, Option<u16>) {
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse().ok()),
+        _ => (authority, None),
+    }
+}
+
+impl Server {
+    /// Browsers send requests to any address a page asks for, so only requests naming the
+    /// address being listened on, from pages on this machine or served from that address, that
+    /// carry the token are handled.
+    fn check(&self, request: &HttpRequest) -> Result<(), Rejection> {
+        let authority = request.header("Host").unwrap_or_default();
+        let (host, port) = split_host(authority);
+        let host_matches = match host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            Ok(ip) => ip == self.address.ip() || self.address.ip().is_unspecified(),
+            Err(_) => is_loopback(host) && self.address.ip().is_loopback(),
+        };
+        if !host_matches || port.unwrap_or(80) != self.address.port() {
+            return Err(Rejection::new("403 Forbidden", "Unexpected Host header"));
+        }
+
+        if let Some(origin) = request.header("Origin") {
+            // The Host has already been checked so a page from the same origin is allowed.
+            let allowed = origin.split_once("://").is_some_and(|(_, origin)| {
+                is_loopback(split_host(origin).0) || origin.eq_ignore_ascii_case(authority)
+            });
+            if !allowed {
+                return Err(Rejection::new(
+                    "403 Forbidden",
+                    "Only pages on this machine or served from this address may connect",
+                ));
+            }
+        }
+
+        if !request
+            .token()
+            .is_some_and(|token| tokens_match(token, &self.token))
+        {
+            return Err(Rejection::new(
+                "401 Unauthorized",
+                "Missing or wrong token, it is in midi-ctrl.token in the settings directory",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a line of the request, refusing any longer than `MAX_LINE`.
+fn read_line(reader: &mut BufReader<TcpStream>, line: &mut String) -> Result<(), Rejection> {
+    line.clear();
+    reader
+        .by_ref()
+        .take(MAX_LINE as u64 + 1)
+        .read_line(line)
+        .map_err(|e| Rejection::bad_request(format!("Failed to read HTTP request: {e}")))?;
+
+    if line.len() > MAX_LINE {
+        return Err(Rejection::new(
+            "431 Request Header Fields Too Large",
+            "Request line is too long",
+        ));
+    }
+
+    Ok(())
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> Result<HttpRequest, Rejection> {
+    let mut line = String::new();
+    read_line(reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => {
+            return Err(Rejection::bad_request(format!(
+                "Bad HTTP request: {}",
+                line.trim()
+            )))
+        }
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target, None),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        read_line(reader, &mut line)?;
+        let header = line.trim();
+        if header.is_empty() {
+            break;
+        }
+
+        if headers.len() == MAX_HEADERS {
+            return Err(Rejection::new(
+                "431 Request Header Fields Too Large",
+                "Request has too many headers",
+            ));
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    Ok(HttpRequest {
+        method,
+        path,
+        query,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+fn read_body(reader: &mut BufReader<TcpStream>, request: &mut HttpRequest) -> Result<(), String> {
+    let length = request
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    request.body.resize(length, 0);
+    reader
+        .read_exact(&mut request.body)
+        .map_err(|e| format!("Failed to read HTTP request: {e}"))
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response) -> Result<(), String> {
+    let status = match response {
+        Response::Ok(_) => "200 OK",
+        Response::Error(_) => "400 Bad Request",
+    };
+    write_status(stream, status, response)
+}
+
+fn write_status(stream: &mut TcpStream, status: &str, response: &Response) -> Result<(), String> {
+    let body =
+        serde_json::to_vec(response).map_err(|e| format!("Failed to encode response: {e}"))?;
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )
+    .and_then(|_| stream.write_all(&body))
+    .and_then(|_| stream.flush())
+    .map_err(|e| format!("Failed to write HTTP response: {e}"))
+}
+
+fn perform(body: &[u8], sender: &Sender<ControlMessage>) -> Response {
+    match serde_json::from_slice::<Action>(body) {
+        Ok(action) => answer(Request::Perform { action }, sender),
+        Err(e) => Response::Error(format!("Failed to parse action: {e}")),
+    }
+}
+
+/// Streams every event to the client and performs the actions that it sends. Anything the client
+/// sent after the handshake may already be in the reader's buffer.
+fn websocket(
+    mut stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    request: &HttpRequest,
+    sender: &Sender<ControlMessage>,
+    events: &EventBus,
+) -> Result<(), String> {
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) => key,
+        None => {
+            return write_response(
+                &mut stream,
+                &Response::Error(String::from("Expected a WebSocket connection")),
+            )
+        }
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    )
+    .and_then(|_| stream.flush())
+    .map_err(|e| format!("Failed to accept WebSocket: {e}"))?;
+
+    stream
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .map_err(|e| format!("Failed to configure WebSocket: {e}"))?;
+
+    let events = events.subscribe();
+    let mut socket =
+        WebSocket::from_partially_read(stream, reader.buffer().to_vec(), Role::Server, None);
+
+    loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                if let Response::Error(e) = perform(text.as_bytes(), sender) {
+                    let message = serde_json::to_string(&Response::Error(e)).unwrap_or_default();
+                    socket
+                        .send(Message::Text(message))
+                        .map_err(|e| format!("Failed to write to WebSocket: {e}"))?;
+                }
+            }
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(ref e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(e) => return Err(format!("Failed to read from WebSocket: {e}")),
+        }
+
+        for event in events.try_iter() {
+            let message = serde_json::to_string(&event)
+                .map_err(|e| format!("Failed to encode event: {e}"))?;
+            socket
+                .send(Message::Text(message))
+                .map_err(|e| format!("Failed to write to WebSocket: {e}"))?;
+        }
+    }
+}
+
+fn client(
+    mut stream: TcpStream,
+    server: &Server,
+    sender: Sender<ControlMessage>,
+    events: EventBus,
+) -> Result<(), String> {
+    let mut reader = BufReader::new(
+        stream
+            .try_clone()
+            .map_err(|e| format!("Failed to clone HTTP stream: {e}"))?,
+    );
+    let mut request = match read_request(&mut reader) {
+        Ok(request) => request,
+        Err(rejection) => {
+            log::warn!("Refused HTTP request: {}", rejection.message);
+            return write_status(
+                &mut stream,
+                rejection.status,
+                &Response::Error(rejection.message),
+            );
+        }
+    };
+    log::debug!("HTTP {} {}", request.method, request.path);
+
+    let length = request
+        .header("Content-Length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(0);
+    let rejection = match server.check(&request) {
+        Err(rejection) => Some(rejection),
+        Ok(()) if length > MAX_BODY => Some(Rejection::new(
+            "413 Payload Too Large",
+            "Request body is too large",
+        )),
+        Ok(()) => None,
+    };
+    if let Some(rejection) = rejection {
+        log::warn!(
+            "Refused HTTP {} {}: {}",
+            request.method,
+            request.path,
+            rejection.message
+        );
+        return write_status(
+            &mut stream,
+            rejection.status,
+            &Response::Error(rejection.message),
+        );
+    }
+
+    read_body(&mut reader, &mut request)?;
+
+    let response = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/events") => return websocket(stream, reader, &request, &sender, &events),
+        ("GET", "/state") => answer(Request::GetState, &sender),
+        ("GET", "/profile") => answer(Request::GetProfile, &sender),
+        ("POST", "/action") => perform(&request.body, &sender),
+        ("POST", "/reload-profiles") => answer(Request::ReloadProfiles, &sender),
+        (method, path) => Response::Error(format!("Unknown request {method} {path}")),
+    };
+
+    write_response(&mut stream, &response)
+}
+
+/// Serves the current state over HTTP and streams events over a WebSocket at `/events`.
+pub(crate) fn serve(
+    root: &Path,
+    address: &str,
+    sender: Sender<ControlMessage>,
+    events: EventBus,
+) -> Result<(), String> {
+    let token = read_token(root)?;
+    let listener =
+        TcpListener::bind(address).map_err(|e| format!("Failed to listen on {address}: {e}"))?;
+    let server = Arc::new(Server {
+        address: listener
+            .local_addr()
+            .map_err(|e| format!("Failed to listen on {address}: {e}"))?,
+        token,
+    });
+    log::info!("Serving HTTP on {}", address);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let server = server.clone();
+                    let sender = sender.clone();
+                    let events = events.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = client(stream, &server, sender, events) {
+                            log::debug!("{}", e);
+                        }
+                    });
+                }
+                Err(e) => log::error!("Failed to accept HTTP connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use std::{
+    fs::{read_to_string, remove_file, write, File, OpenOptions, TryLockError},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(unix)]
+use crate::control::{request, Request, Response};
+
+/// Held locked by the running MidiCtrl, the operating system releases the lock however it exits.
+const LOCK_FILE: &str = "midi-ctrl.lock";
+/// Describes the running MidiCtrl. Kept apart from the lock file as Windows doesn't allow other
+/// processes to read a locked file.
+const INSTANCE_FILE: &str = "midi-ctrl.instance";
+
+/// A running MidiCtrl, as described in its settings directory.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Instance {
+    pub pid: u32,
+    /// Whether it keeps running when Lightroom disconnects.
+    #[serde(default)]
+    pub daemon: bool,
+    #[serde(skip)]
+    root: PathBuf,
+}
+
+/// Whether some process holds the lock for the settings directory.
+fn is_locked(root: &Path) -> bool {
+    let file = match File::open(root.join(LOCK_FILE)) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+
+    match file.try_lock_shared() {
+        Ok(()) => false,
+        Err(TryLockError::WouldBlock) => true,
+        Err(TryLockError::Error(e)) => {
+            log::warn!("Failed to check the lock for {}: {}", root.display(), e);
+            false
+        }
+    }
+}
+
+fn terminate(pid: u32) -> Result<(), String> {
+    #[cfg(unix)]
+    let mut command = process::Command::new("kill");
+    #[cfg(unix)]
+    command.args(["-TERM", &pid.to_string()]);
+
+    #[cfg(windows)]
+    let mut command = process::Command::new("taskkill");
+    #[cfg(windows)]
+    command.args(["/F", "/PID", &pid.to_string()]);
+
+    let status = command
+        .status()
+        .map_err(|e| format!("Failed to stop process {pid}: {e}"))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Failed to stop process {pid}: {status}"))
+    }
+}
+
+impl Instance {
+    /// Finds the MidiCtrl running for the settings directory, if any.
+    pub fn running(root: &Path) -> Option<Instance> {
+        if !is_locked(root) {
+            return None;
+        }
+
+        let path = root.join(INSTANCE_FILE);
+        let mut instance = match read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| serde_json::from_str::<Instance>(&text).map_err(|e| e.to_string()))
+        {
+            Ok(instance) => instance,
+            Err(e) => {
+                // The lock is taken before the file is written so it may not be there yet.
+                log::warn!("Failed to read {}: {}", path.display(), e);
+                Instance {
+                    pid: 0,
+                    daemon: false,
+                    root: PathBuf::new(),
+                }
+            }
+        };
+
+        instance.root = root.to_path_buf();
+        Some(instance)
+    }
+
+    /// Asks the instance to exit, through the control socket where there is one and otherwise by
+    /// terminating its process, and waits for it to release its lock.
+    pub fn stop(&self, timeout: Duration) -> Result<(), String> {
+        if self.pid == 0 {
+            return Err(format!(
+                "Unable to tell which process is running for {}",
+                self.root.display()
+            ));
+        }
+
+        #[cfg(unix)]
+        match request(&self.root, &Request::Quit) {
+            Ok(Response::Ok(_)) => (),
+            Ok(Response::Error(e)) => {
+                log::warn!("Process {} refused to exit: {}", self.pid, e);
+                terminate(self.pid)?;
+            }
+            Err(e) => {
+                log::warn!("{}", e);
+                terminate(self.pid)?;
+            }
+        }
+
+        #[cfg(windows)]
+        terminate(self.pid)?;
+
+        let start = Instant::now();
+        while is_locked(&self.root) {
+            if start.elapsed() > timeout {
+                return Err(format!("Process {} did not exit", self.pid));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(())
+    }
+}
+
+/// Stops more than one MidiCtrl using the same settings directory, and so the same devices, at
+/// once. The lock is held until this is dropped or the process exits.
+pub struct InstanceLock {
+    _file: File,
+    instance_path: PathBuf,
+}
+
+impl InstanceLock {
+    pub fn acquire(root: &Path, daemon: bool) -> Result<InstanceLock, String> {
+        let path = root.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                return Err(match Instance::running(root) {
+                    Some(running) if running.pid != 0 => format!(
+                        "MidiCtrl is already running for {} as process {}",
+                        root.display(),
+                        running.pid
+                    ),
+                    _ => format!("MidiCtrl is already running for {}", root.display()),
+                })
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(format!("Failed to lock {}: {}", path.display(), e))
+            }
+        }
+
+        let instance = Instance {
+            pid: process::id(),
+            daemon,
+            root: root.to_path_buf(),
+        };
+        let data = serde_json::to_vec(&instance)
+            .map_err(|e| format!("Failed to encode instance file: {e}"))?;
+        let instance_path = root.join(INSTANCE_FILE);
+        write(&instance_path, data)
+            .map_err(|e| format!("Failed to write {}: {}", instance_path.display(), e))?;
+
+        Ok(InstanceLock {
+            _file: file,
+            instance_path,
+        })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // The lock itself is released when the file is closed. The lock file stays so that
+        // everything always locks the same file.
+        match remove_file(&self.instance_path) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => log::warn!("Failed to remove {}: {}", self.instance_path.display(), e),
+        }
+    }
+}
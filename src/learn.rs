@@ -0,0 +1,594 @@
+use std::{
+    fmt,
+    fs::{create_dir_all, write},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use midi_control::{Channel, MidiMessage};
+
+use crate::{
+    diagnostics::Format,
+    midi::{
+        controls::{
+            ContinuousControl, ContinuousLayer, Control, KeyControl, KeyLayer, LayerControl,
+            Relative,
+        },
+        device::DeviceConfig,
+        port::PortSelector,
+        transport::{MidirTransport, Transport},
+    },
+    utils::iter_config,
+};
+
+/// How long a control must be left alone before it is considered done with.
+const IDLE: Duration = Duration::from_millis(1000);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Source {
+    Note(Channel, u8),
+    Control(Channel, u8),
+    PitchBend(Channel),
+}
+
+impl Source {
+    fn from_message(message: &MidiMessage) -> Option<(Source, u8)> {
+        match message {
+            MidiMessage::NoteOn(channel, event) | MidiMessage::NoteOff(channel, event) => {
+                Some((Source::Note(*channel, event.key), event.value))
+            }
+            MidiMessage::ControlChange(channel, event) => {
+                Some((Source::Control(*channel, event.control), event.value))
+            }
+            MidiMessage::PitchBend(channel, _, msb) => Some((Source::PitchBend(*channel), *msb)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Note(channel, note) => {
+                write!(f, "note {} on channel {}", note, *channel as u8 + 1)
+            }
+            Source::Control(channel, control) => {
+                write!(f, "cc {} on channel {}", control, *channel as u8 + 1)
+            }
+            Source::PitchBend(channel) => write!(f, "pitch bend on channel {}", *channel as u8 + 1),
+        }
+    }
+}
+
+enum Event {
+    Midi(Source, u8),
+    Line(String),
+    Closed,
+}
+
+enum Learned {
+    Key {
+        channel: Channel,
+        note: u8,
+    },
+    Continuous {
+        channel: Channel,
+        control: u8,
+        min: u8,
+        max: u8,
+        relative: Option<Relative>,
+    },
+    PitchBend {
+        channel: Channel,
+        min: u8,
+        max: u8,
+    },
+}
+
+impl Learned {
+    fn is_key(&self) -> bool {
+        matches!(self, Learned::Key { .. })
+    }
+
+    fn matches(&self, control: &Control, layer: &str) -> bool {
+        match (self, control) {
+            (Learned::Key { channel, note }, Control::Key(key)) => {
+                key.layers.get(layer).is_some_and(|key_layer| {
+                    key_layer.channel == Some(*channel) && key_layer.note == Some(*note)
+                })
+            }
+            (
+                Learned::Continuous {
+                    channel, control, ..
+                },
+                Control::Continuous(continuous),
+            ) => continuous
+                .layers
+                .get(layer)
+                .is_some_and(|continuous_layer| {
+                    !continuous_layer.pitch_bend
+                        && continuous_layer.channel == Some(*channel)
+                        && continuous_layer.control == Some(*control)
+                }),
+            (Learned::PitchBend { channel, .. }, Control::Continuous(continuous)) => continuous
+                .layers
+                .get(layer)
+                .is_some_and(|continuous_layer| {
+                    continuous_layer.pitch_bend && continuous_layer.channel == Some(*channel)
+                }),
+            _ => false,
+        }
+    }
+
+    fn into_layer(self) -> LayerControl {
+        let (channel, control, min, max, relative, pitch_bend) = match self {
+            Learned::Key { channel, note } => {
+                return LayerControl::Key(KeyLayer {
+                    channel: Some(channel),
+                    note: Some(note),
+                    off: 0,
+                    on: 127,
+                    address: None,
+                    label: None,
+                    state: Default::default(),
+                    sent_label: Default::default(),
+                })
+            }
+            Learned::Continuous {
+                channel,
+                control,
+                min,
+                max,
+                relative,
+            } => (channel, Some(control), min, max, relative, false),
+            Learned::PitchBend { channel, min, max } => (channel, None, min, max, None, true),
+        };
+
+        LayerControl::Continuous(ContinuousLayer {
+            channel: Some(channel),
+            control,
+            relative,
+            pitch_bend,
+            min,
+            max,
+            address: None,
+            label: None,
+            state: Default::default(),
+            sent_label: Default::default(),
+        })
+    }
+}
+
+/// Relative encoders send the same small offset from 0 or 64 repeatedly while absolute controls
+/// only send a value when it changes. The offsets seen tell which encoding is used, if the encoder
+/// was only turned up then two's complement and sign bit look the same.
+fn relative_encoding(values: &[u8]) -> Option<Relative> {
+    if values.len() < 3 {
+        return None;
+    }
+
+    let repeats = values.windows(2).filter(|pair| pair[0] == pair[1]).count();
+    let offsets = values
+        .iter()
+        .all(|value| *value <= 15 || *value >= 113 || (49..=79).contains(value));
+
+    if !offsets || repeats * 2 < values.len() - 1 {
+        return None;
+    }
+
+    let low = values.iter().any(|value| *value <= 15);
+    let high = values.iter().any(|value| *value >= 113);
+    let middle = values.iter().any(|value| (49..=79).contains(value));
+
+    Some(match (low, high, middle) {
+        (true, false, true) => Relative::SignBit,
+        (false, false, true) => Relative::BinaryOffset,
+        _ => Relative::TwosComplement,
+    })
+}
+
+/// The lowest and highest values seen, a control must be moved through more than one value.
+fn range(source: Source, values: &[u8]) -> Result<(u8, u8), String> {
+    let min = values.iter().copied().min().unwrap_or(0);
+    let max = values.iter().copied().max().unwrap_or(127);
+    if min == max {
+        return Err(format!(
+            "{} only sent the value {}, move it through its full range",
+            source, min
+        ));
+    }
+
+    Ok((min, max))
+}
+
+fn classify(source: Source, values: &[u8]) -> Result<Learned, String> {
+    match source {
+        Source::Note(channel, note) => Ok(Learned::Key { channel, note }),
+        Source::Control(channel, control) => {
+            if let Some(relative) = relative_encoding(values) {
+                println!(
+                    "{} looks like a relative encoder using {:?} offsets",
+                    source, relative
+                );
+
+                return Ok(Learned::Continuous {
+                    channel,
+                    control,
+                    min: 0,
+                    max: 127,
+                    relative: Some(relative),
+                });
+            }
+
+            let (min, max) = range(source, values)?;
+            if values.iter().all(|value| *value == min || *value == max) {
+                println!(
+                    "{} looks like a button that sends control changes, only buttons that send notes can be used as keys so it will be set up as a continuous control",
+                    source
+                );
+            }
+
+            Ok(Learned::Continuous {
+                channel,
+                control,
+                min,
+                max,
+                relative: None,
+            })
+        }
+        Source::PitchBend(channel) => {
+            let (min, max) = range(source, values)?;
+            Ok(Learned::PitchBend { channel, min, max })
+        }
+    }
+}
+
+fn prompt(events: &Receiver<Event>, question: &str) -> Option<String> {
+    print!("{}", question);
+    let _ = io::stdout().flush();
+
+    loop {
+        match events.recv() {
+            Ok(Event::Line(line)) => return Some(String::from(line.trim())),
+            Ok(Event::Midi(..)) => (),
+            Ok(Event::Closed) | Err(_) => return None,
+        }
+    }
+}
+
+fn prompt_default(events: &Receiver<Event>, question: &str, default: &str) -> Option<String> {
+    let answer = prompt(events, &format!("{} [{}]: ", question, default))?;
+    if answer.is_empty() {
+        Some(String::from(default))
+    } else {
+        Some(answer)
+    }
+}
+
+/// Waits for the next control to be used and collects its values until it is left alone.
+fn listen(events: &Receiver<Event>) -> Option<(Source, Vec<u8>)> {
+    let (source, value) = loop {
+        match events.recv() {
+            Ok(Event::Midi(source, value)) => break (source, value),
+            Ok(Event::Line(line)) if line.trim().is_empty() => return None,
+            Ok(Event::Line(_)) => (),
+            Ok(Event::Closed) | Err(_) => return None,
+        }
+    };
+
+    println!(
+        "Heard {}, keep going through its full range then leave it alone",
+        source
+    );
+
+    let mut values = vec![value];
+    loop {
+        match events.recv_timeout(IDLE) {
+            Ok(Event::Midi(next, value)) if next == source => values.push(value),
+            Ok(_) => (),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    Some((source, values))
+}
+
+fn default_id(port: &str) -> String {
+    let mut id = String::new();
+    for c in port.chars() {
+        if c.is_ascii_alphanumeric() {
+            id.push(c.to_ascii_lowercase());
+        } else if !id.is_empty() && !id.ends_with('-') {
+            id.push('-');
+        }
+    }
+
+    String::from(id.trim_end_matches('-'))
+}
+
+/// Suggests the next existing control that doesn't have this layer yet, otherwise a new name.
+fn default_name(config: &DeviceConfig, layer: &str, learned: &Learned) -> String {
+    for control in &config.controls {
+        match control {
+            Control::Key(key) if learned.is_key() && !key.layers.contains_key(layer) => {
+                return key.name.clone()
+            }
+            Control::Continuous(continuous)
+                if !learned.is_key() && !continuous.layers.contains_key(layer) =>
+            {
+                return continuous.name.clone()
+            }
+            _ => (),
+        }
+    }
+
+    let prefix = if learned.is_key() {
+        "Button"
+    } else {
+        "Encoder"
+    };
+    (1..)
+        .map(|index| format!("{} {}", prefix, index))
+        .find(|name| !config.controls.iter().any(|control| control.name() == name))
+        .unwrap()
+}
+
+/// The configuration with its template applied, which is what controls are matched against.
+fn resolved(config: &DeviceConfig) -> DeviceConfig {
+    config.clone().resolve().unwrap_or_else(|_| config.clone())
+}
+
+fn add_layer(
+    config: &mut DeviceConfig,
+    resolved: &DeviceConfig,
+    name: &str,
+    layer: &str,
+    learned: Learned,
+) -> Result<(), String> {
+    let same_type = |control: &Control| {
+        matches!(
+            (control, &learned),
+            (Control::Key(_), Learned::Key { .. })
+                | (Control::Continuous(_), Learned::Continuous { .. })
+                | (Control::Continuous(_), Learned::PitchBend { .. })
+        )
+    };
+
+    if resolved
+        .controls
+        .iter()
+        .any(|control| control.name() == name && !same_type(control))
+    {
+        return Err(format!("{} is a different type of control", name));
+    }
+
+    let existing = config
+        .controls
+        .iter_mut()
+        .find(|control| control.name() == name);
+
+    // Controls that only exist in the template are added with just the new layer, the template's
+    // layers are kept when it is applied.
+    match (existing, learned.into_layer()) {
+        (Some(Control::Key(key)), LayerControl::Key(key_layer)) => {
+            key.layers.insert(String::from(layer), key_layer);
+        }
+        (Some(Control::Continuous(continuous)), LayerControl::Continuous(continuous_layer)) => {
+            continuous
+                .layers
+                .insert(String::from(layer), continuous_layer);
+        }
+        (Some(_), _) => return Err(format!("{} is a different type of control", name)),
+        (None, LayerControl::Key(key_layer)) => {
+            let mut key = KeyControl {
+                name: String::from(name),
+                display: false,
+                layers: Default::default(),
+            };
+            key.layers.insert(String::from(layer), key_layer);
+            config.controls.push(Control::Key(key));
+        }
+        (None, LayerControl::Continuous(continuous_layer)) => {
+            let mut continuous = ContinuousControl {
+                name: String::from(name),
+                layers: Default::default(),
+            };
+            continuous
+                .layers
+                .insert(String::from(layer), continuous_layer);
+            config.controls.push(Control::Continuous(continuous));
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the configuration for a device that is already using the port.
+fn find_device(dir: &Path, port: &str) -> Result<Option<(PathBuf, DeviceConfig)>, String> {
+    let entries = match iter_config::<DeviceConfig>(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(None),
+    };
+
+    for (_, path, config) in entries.flatten() {
+        let matches = match config.clone().resolve() {
+            Ok(resolved) => resolved
+                .input()
+                .is_some_and(|selector| selector.matches(port)),
+            Err(_) => config
+                .input()
+                .is_some_and(|selector| selector.matches(port)),
+        };
+
+        if matches {
+            if Format::from_path(&path) != Some(Format::Json) {
+                return Err(format!(
+                    "{} uses port {} but only JSON device files can be updated",
+                    path.display(),
+                    port
+                ));
+            }
+
+            return Ok(Some((path, config)));
+        }
+    }
+
+    Ok(None)
+}
+
+fn save(path: &Path, config: &DeviceConfig) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+
+    let mut data = serde_json::to_string_pretty(config)
+        .map_err(|e| format!("Failed to encode device config: {e}"))?;
+    data.push('\n');
+
+    write(path, data).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Interactively builds a device configuration by listening to the controls on a MIDI port.
+/// Running it again for the same port adds layers to the existing configuration.
+pub fn learn(root: &Path, port: &str) -> Result<(), String> {
+    let transport = MidirTransport;
+    let names = transport.inputs()?;
+    let position = names.iter().position(|name| name == port).ok_or_else(|| {
+        format!(
+            "Unknown MIDI port {}, the available ports are: {}",
+            port,
+            names.join(", ")
+        )
+    })?;
+
+    let (sender, events) = channel();
+
+    let midi_sender = sender.clone();
+    let _connection = transport
+        .connect_input(
+            position,
+            port,
+            Box::new(move |buffer| {
+                if let Some((source, value)) = Source::from_message(&MidiMessage::from(buffer)) {
+                    let _ = midi_sender.send(Event::Midi(source, value));
+                }
+            }),
+        )?
+        .ok_or_else(|| format!("MIDI port {port} disappeared"))?;
+
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if sender.send(Event::Line(line)).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        let _ = sender.send(Event::Closed);
+    });
+
+    let dir = root.join("devices");
+    let (path, mut config) = match find_device(&dir, port)? {
+        Some((path, config)) => {
+            println!("Adding to {}", path.display());
+            (path, config)
+        }
+        None => {
+            let id = match prompt_default(&events, "Device ID", &default_id(port)) {
+                Some(id) => id,
+                None => return Ok(()),
+            };
+            let config = DeviceConfig {
+                template: None,
+                port: Some(PortSelector::Name(String::from(port))),
+                input_port: None,
+                output_port: None,
+                identity: None,
+                osc: None,
+                controls: Vec::new(),
+            };
+            (dir.join(format!("{}.json", id)), config)
+        }
+    };
+
+    let mut layers: Vec<String> = resolved(&config)
+        .controls
+        .into_iter()
+        .flat_map(|control| match control {
+            Control::Key(key) => key.layers.into_keys().collect::<Vec<_>>(),
+            Control::Continuous(continuous) => continuous.layers.into_keys().collect(),
+        })
+        .collect();
+    layers.sort_unstable();
+    layers.dedup();
+    if !layers.is_empty() {
+        println!("Existing layers: {}", layers.join(", "));
+    }
+
+    let layer = match prompt_default(&events, "Layer name", "A") {
+        Some(layer) => layer,
+        None => return Ok(()),
+    };
+
+    loop {
+        println!("Move or press the next control, or press enter to finish");
+        let (source, values) = match listen(&events) {
+            Some(heard) => heard,
+            None => break,
+        };
+
+        let learned = match classify(source, &values) {
+            Ok(learned) => learned,
+            Err(e) => {
+                println!("Skipping: {}", e);
+                continue;
+            }
+        };
+
+        let current = resolved(&config);
+        if let Some(control) = current
+            .controls
+            .iter()
+            .find(|control| learned.matches(control, &layer))
+        {
+            println!("Already learned as {} in layer {}", control.name(), layer);
+            continue;
+        }
+
+        if let Learned::Continuous {
+            min,
+            max,
+            relative: None,
+            ..
+        }
+        | Learned::PitchBend { min, max, .. } = learned
+        {
+            println!("Saw values from {} to {}", min, max);
+        }
+
+        let name = match prompt_default(
+            &events,
+            "Control name",
+            &default_name(&current, &layer, &learned),
+        ) {
+            Some(name) => name,
+            None => break,
+        };
+
+        if let Err(e) = add_layer(&mut config, &current, &name, &layer, learned) {
+            println!("Skipping: {}", e);
+            continue;
+        }
+
+        save(&path, &config)?;
+        println!("Saved {} in layer {} to {}", name, layer, path.display());
+    }
+
+    Ok(())
+}
@@ -1,29 +1,51 @@
 pub mod actions;
+pub mod check;
+pub mod control;
+mod diagnostics;
+pub mod events;
+pub mod explain;
+pub mod fake_lightroom;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod instance;
+pub mod learn;
 mod lightroom;
 mod midi;
+pub mod monitor;
 mod profile;
+pub mod replay;
+pub mod scenario;
+pub mod schema;
 mod state;
 pub mod utils;
 
-use serde_json::Value as JsonValue;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs::metadata,
     io::ErrorKind,
-    path::Path,
-    sync::mpsc::{channel, Receiver},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
 };
 
 use actions::InternalAction;
+#[cfg(unix)]
+use control::ControlServer;
+use control::{Request, Response};
+use events::{Event, EventBus};
 use lightroom::Lightroom;
+pub use midi::transport::{MemoryTransport, MidirTransport, Transport};
 use midi::{
-    controls::KeyState,
+    controls::{KeyState, LayerControl},
     device::{devices, get_layer_control, Device},
 };
-use profile::{Action, Profile, Profiles};
+use profile::{write_control, Action, Profile, Profiles};
+use replay::Recorder;
 use state::{
     param_module,
     params::{BoolParam, FloatParam, StringParam},
+    persist::{PersistedState, StatePersister},
     Param, SetMapEntry, State, StateValue,
 };
 
@@ -31,17 +53,20 @@ use crate::lightroom::OutgoingMessage;
 
 use self::state::Module;
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
 pub enum ControlMessage {
     Disconnect,
     Reset,
     ContinuousChange {
+        #[serde(rename = "deviceId")]
         device_id: String,
         control: String,
         layer: String,
         value: f64,
     },
     KeyChange {
+        #[serde(rename = "deviceId")]
         device_id: String,
         control: String,
         layer: String,
@@ -50,39 +75,111 @@ pub enum ControlMessage {
     StateChange {
         values: Vec<StateValue>,
     },
+    /// A request from the control socket, the response is sent back through `reply`.
+    #[serde(skip)]
+    Request {
+        request: Request,
+        reply: Sender<Response>,
+    },
+}
+
+/// An in progress learn. The parameter is chosen by changing it in Lightroom, moving a control
+/// then binds the control to it.
+struct Learning {
+    profile: String,
+    parameter: Option<Param>,
+}
+
+/// An in progress explain, started by the Explain action.
+enum Explaining {
+    /// Waiting for a control to move.
+    Next,
+    /// A key was explained, its release should be ignored.
+    Release {
+        device_id: String,
+        control: String,
+        layer: String,
+    },
 }
 
 pub struct Controller {
+    root: PathBuf,
+    learning: Option<Learning>,
+    explaining: Option<Explaining>,
     receiver: Receiver<ControlMessage>,
     lightroom: Lightroom,
     devices: HashMap<String, Device>,
     profiles: Profiles,
     state: State,
+    /// The device whose control was used most recently.
+    active_device: Option<String>,
+    /// The device whose control's actions are being performed.
+    acting_device: Option<String>,
+    persister: StatePersister,
+    recorder: Option<Recorder>,
+    sender: Sender<ControlMessage>,
+    events: EventBus,
+    #[cfg(unix)]
+    control_server: Option<ControlServer>,
+    /// Keep running when Lightroom disconnects.
+    daemon: bool,
+    /// Set when Lightroom disconnects so every control is updated when it returns.
+    resync: bool,
+    /// When set every action performed is also added here.
+    performed: Option<Vec<Action>>,
+    /// Set once asked to exit.
+    quitting: bool,
 }
 
-impl Controller {
-    pub fn new(root: &Path) -> Result<Controller, String> {
-        match metadata(root) {
-            Ok(metadata) => {
-                if !metadata.is_dir() {
-                    return Err(format!("{} is not a directory", root.display()));
-                }
+fn check_root(root: &Path) -> Result<(), String> {
+    match metadata(root) {
+        Ok(metadata) => {
+            if !metadata.is_dir() {
+                return Err(format!("{} is not a directory", root.display()));
             }
-            Err(e) => {
-                if e.kind() == ErrorKind::NotFound {
-                    return Err(format!("Path {} not found", root.display()));
-                }
-                return Err(format!(
-                    "Error accessing settings path {}: {}",
-                    root.display(),
-                    e
-                ));
+        }
+        Err(e) => {
+            if e.kind() == ErrorKind::NotFound {
+                return Err(format!("Path {} not found", root.display()));
             }
+            return Err(format!(
+                "Error accessing settings path {}: {}",
+                root.display(),
+                e
+            ));
         }
+    }
+
+    Ok(())
+}
+
+impl Controller {
+    pub fn new(root: &Path, daemon: bool) -> Result<Controller, String> {
+        Controller::with_transport(root, &MidirTransport, daemon)
+    }
+
+    /// Creates a controller that finds its MIDI devices using the given transport. As a daemon it
+    /// keeps running when Lightroom disconnects and waits for it to return.
+    pub fn with_transport(
+        root: &Path,
+        transport: &dyn Transport,
+        daemon: bool,
+    ) -> Result<Controller, String> {
+        check_root(root)?;
+
+        let persisted = match PersistedState::read(root) {
+            Ok(persisted) => persisted.unwrap_or_default(),
+            Err(e) => {
+                log::error!("{}", e);
+                PersistedState::default()
+            }
+        };
 
         let (sender, receiver) = channel();
+        let lightroom = Lightroom::new(sender.clone(), 61327, 61328, daemon);
+        let persister = StatePersister::new(root, persisted.clone());
 
-        let devices = devices(sender.clone(), root);
+        let devices = devices(sender.clone(), root, transport);
         let connected = devices
             .values()
             .filter(|device| device.is_connected())
@@ -93,46 +190,143 @@ impl Controller {
             log::info!("Connected to {} MIDI devices", connected);
         }
 
-        let profiles = Profiles::new(root, &devices);
+        let mut controller = Controller::create(
+            root, devices, sender, receiver, lightroom, persisted, persister, false,
+        );
+        controller.daemon = daemon;
+        Ok(controller)
+    }
+
+    /// Creates a controller that is not connected to Lightroom and does not persist its state.
+    /// The messages it would send to Lightroom are returned through the receiver.
+    fn detached<F>(
+        root: &Path,
+        persisted: PersistedState,
+        connect: F,
+    ) -> Result<(Controller, Receiver<OutgoingMessage>), String>
+    where
+        F: FnOnce(Sender<ControlMessage>) -> HashMap<String, Device>,
+    {
+        check_root(root)?;
+
+        let (sender, receiver) = channel();
+        let (lightroom, outgoing) = Lightroom::detached(sender.clone());
+        let persister = StatePersister::disabled(persisted.clone());
+        let devices = connect(sender.clone());
+
+        // There is always something standing in for Lightroom.
+        let controller = Controller::create(
+            root, devices, sender, receiver, lightroom, persisted, persister, true,
+        );
+        Ok((controller, outgoing))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create(
+        root: &Path,
+        devices: HashMap<String, Device>,
+        sender: Sender<ControlMessage>,
+        receiver: Receiver<ControlMessage>,
+        lightroom: Lightroom,
+        persisted: PersistedState,
+        persister: StatePersister,
+        connected: bool,
+    ) -> Controller {
+        let mut profiles = Profiles::new(root, &devices);
+
+        let mut state = State::new();
+        persisted.apply(&mut state);
+        state.bools.insert(BoolParam::Connected, connected);
+        if let Some(ref id) = persisted.profile {
+            if profiles.restore_profile(id) {
+                log::info!("Restored profile {}", id);
+            } else {
+                log::warn!("Previously selected profile {} no longer exists", id);
+            }
+        }
+        for (device_id, id) in &persisted.profiles {
+            if profiles.restore_device_profile(device_id, id) {
+                log::info!("Restored profile {} for device {}", id, device_id);
+            } else {
+                log::warn!(
+                    "Previously selected profile {} no longer exists for device {}",
+                    id,
+                    device_id
+                );
+            }
+        }
+        for id in &persisted.overlays {
+            if profiles.restore_overlay(id) {
+                log::info!("Restored pushed profile {}", id);
+            } else {
+                log::warn!("Previously pushed profile {} no longer exists", id);
+            }
+        }
 
         // We expect that the first thing Lightroom will do is send a state update which will
         // trigger updates to the device.
 
         let mut controller = Controller {
+            root: root.to_path_buf(),
+            learning: None,
+            explaining: None,
             receiver,
-            lightroom: Lightroom::new(sender, 61327, 61328),
+            lightroom,
             devices,
             profiles,
-            state: State::new(),
+            state,
+            active_device: None,
+            acting_device: None,
+            persister,
+            recorder: None,
+            sender,
+            events: EventBus::new(),
+            #[cfg(unix)]
+            control_server: None,
+            daemon: false,
+            resync: false,
+            performed: None,
+            quitting: false,
         };
 
-        if let Some(profile) = controller.profiles.current_profile() {
-            controller
-                .state
-                .strings
-                .insert(StringParam::Profile, profile.id.clone());
+        controller.update_profile_param();
 
+        for profile in controller
+            .profiles
+            .active_profiles()
+            .into_iter()
+            .chain(controller.profiles.overlay_profiles())
+        {
             if let Some(actions) = profile.enter_actions(&controller.state) {
                 controller.perform_actions(actions);
             }
         }
 
-        Ok(controller)
+        controller
     }
 
-    fn profile_changed(&mut self, previous_profile: Option<Profile>, profile: &Option<Profile>) {
-        if let Some(profile) = previous_profile {
-            if let Some(actions) = profile.leave_actions(&self.state) {
-                self.perform_actions(actions);
+    fn profiles_changed(&mut self, previous_profiles: Vec<Profile>) {
+        let profiles = self.profiles.active_profiles();
+        if profiles == previous_profiles {
+            return;
+        }
+
+        for profile in previous_profiles.iter() {
+            if !profiles.contains(profile) {
+                if let Some(actions) = profile.leave_actions(&self.state) {
+                    self.perform_actions(actions);
+                }
             }
         }
 
-        self.state.strings.set(
-            StringParam::Profile,
-            profile.as_ref().map(|profile| profile.id.clone()),
-        );
+        let entered: Vec<&Profile> = profiles
+            .iter()
+            .filter(|profile| !previous_profiles.contains(profile))
+            .collect();
 
-        if let Some(profile) = profile {
+        self.update_profile_param();
+
+        for profile in entered {
             if let Some(actions) = profile.enter_actions(&self.state) {
                 self.perform_actions(actions);
             }
@@ -140,60 +334,133 @@ impl Controller {
             self.lightroom.send(OutgoingMessage::Notification {
                 message: format!("Changed to profile {}", profile.name()),
             });
-        } else {
+        }
+
+        if profiles.is_empty() {
             self.lightroom.send(OutgoingMessage::Notification {
                 message: "Lost profile".to_string(),
             });
         }
     }
 
+    fn update_devices(&mut self, force: bool) {
+        for (id, device) in self.devices.iter_mut() {
+            if let Some(profile) = self.profiles.current_profile(id) {
+                profile.update_device(id, device, &self.state, force);
+            }
+        }
+    }
+
     fn update_profile(&mut self) {
-        // Select the new profile.
-        let previous_profile = self.profiles.current_profile();
-        let new_profile = self.profiles.state_update(&self.state);
+        // Select the new profiles.
+        let previous_profiles = self.profiles.active_profiles();
+        self.profiles.state_update(&self.state);
+        self.profiles_changed(previous_profiles);
+        self.pop_disabled_overlays();
 
-        if previous_profile != new_profile {
-            self.profile_changed(previous_profile, &new_profile);
+        self.update_devices(false);
+    }
+
+    fn pop_disabled_overlays(&mut self) {
+        for overlay in self.profiles.pop_disabled_overlays(&self.state) {
+            if let Some(actions) = overlay.leave_actions(&self.state) {
+                self.perform_actions(actions);
+            }
         }
+    }
+
+    /// Sets `Profile` to the profile of the device whose control was used most recently, or of
+    /// the first device with a profile if no control has been used yet.
+    fn update_profile_param(&mut self) {
+        let selections = self.profiles.selections();
+        let profile = self
+            .active_device
+            .as_ref()
+            .and_then(|device_id| selections.get(device_id))
+            .or_else(|| selections.values().next())
+            .cloned();
 
-        if let Some(profile) = new_profile {
-            profile.update_devices(&mut self.devices, &self.state, false);
+        if self.state.strings.get(&StringParam::Profile) != profile.as_ref() {
+            self.state
+                .strings
+                .set(StringParam::Profile, profile.clone());
+            self.events.publish(Event::Profile { profile });
         }
     }
 
     fn reset_state(&mut self) {
         log::trace!("Resetting state");
         self.state.clear();
+        self.events.publish(Event::Reset);
+        self.set_connected(true);
         self.update_profile();
     }
 
+    fn set_connected(&mut self, connected: bool) {
+        self.state.bools.insert(BoolParam::Connected, connected);
+        self.publish_value(BoolParam::Connected.into(), JsonValue::from(connected));
+    }
+
+    fn publish_value(&self, param: Param, value: JsonValue) {
+        let mut values = BTreeMap::new();
+        values.insert(param.to_string(), value);
+        self.events.publish(Event::State { values });
+    }
+
     fn update_state(&mut self, values: Vec<StateValue>) {
         log::trace!("Updating state");
 
+        self.learn_parameter(&values);
+
+        self.events.publish(Event::State {
+            values: values.iter().map(StateValue::to_json).collect(),
+        });
         self.state.update(values);
         self.update_profile();
+
+        if self.resync {
+            self.resync = false;
+            self.update_devices(true);
+        }
+    }
+
+    /// Forgets Lightroom's state and turns off the controls until Lightroom returns. Profiles can
+    /// use the `Connected` parameter to show something else while disconnected.
+    fn disconnect(&mut self) {
+        log::info!("Lightroom disconnected, waiting for it to return");
+
+        self.learning = None;
+        self.explaining = None;
+        self.state.clear();
+        self.events.publish(Event::Reset);
+        self.set_connected(false);
+        self.update_profile();
+
+        for device in self.devices.values_mut() {
+            device.clear();
+        }
+        self.update_devices(false);
+        self.resync = true;
     }
 
     fn set_internal_string_parameter(&mut self, param: StringParam, value: String) {
         match param {
             StringParam::Profile => {
-                let previous_profile = self.profiles.current_profile();
-                if let Some(ref current_profile) = previous_profile {
-                    if current_profile.id == value {
-                        return;
-                    }
-                }
-
-                let new_profile = self.profiles.set_profile(&value, &self.state);
-                if new_profile.is_some() {
-                    self.profile_changed(previous_profile, &new_profile);
+                let previous_profiles = self.profiles.active_profiles();
+                if self
+                    .profiles
+                    .set_profile(&value, self.acting_device.as_deref(), &self.state)
+                {
+                    self.profiles_changed(previous_profiles);
+                    self.update_profile_param();
+                    self.update_devices(false);
                 }
-
-                if let Some(profile) = new_profile {
-                    profile.update_devices(&mut self.devices, &self.state, false)
-                };
             }
             StringParam::Custom(str) => {
+                self.publish_value(
+                    StringParam::Custom(str.clone()).into(),
+                    JsonValue::from(value.clone()),
+                );
                 self.state
                     .strings
                     .set(StringParam::Custom(str), Some(value));
@@ -203,15 +470,284 @@ impl Controller {
         }
     }
 
+    fn push_profile(&mut self, id: &str) {
+        if let Some(overlay) = self.profiles.push_overlay(id, &self.state) {
+            if let Some(actions) = overlay.enter_actions(&self.state) {
+                self.perform_actions(actions);
+            }
+
+            self.update_devices(false);
+        }
+    }
+
+    fn pop_profile(&mut self, id: Option<&str>) {
+        if let Some(overlay) = self.profiles.pop_overlay(id) {
+            if let Some(actions) = overlay.leave_actions(&self.state) {
+                self.perform_actions(actions);
+            }
+
+            self.update_devices(false);
+        }
+    }
+
+    fn reload_profiles(&mut self) {
+        let previous_profiles = self.profiles.active_profiles();
+        self.profiles.reload(&self.root, &self.devices, &self.state);
+        self.profiles_changed(previous_profiles);
+        self.pop_disabled_overlays();
+
+        self.update_devices(true);
+    }
+
+    fn start_learning(&mut self, profile: String) {
+        let message = if self.learning.take().is_some() {
+            String::from("Cancelled learning")
+        } else {
+            self.learning = Some(Learning {
+                profile,
+                parameter: None,
+            });
+            String::from("Change a setting in Lightroom then move the control to bind to it")
+        };
+
+        self.lightroom
+            .send(OutgoingMessage::Notification { message });
+    }
+
+    fn learn_parameter(&mut self, values: &[StateValue]) {
+        let learning = match self.learning {
+            Some(ref mut learning) if learning.parameter.is_none() => learning,
+            _ => return,
+        };
+
+        let mut parameters = values.iter().filter_map(|value| match value {
+            StateValue::Float { parameter, .. } => Some(Param::Float(parameter.clone())),
+            StateValue::Bool { parameter, .. } => Some(Param::Bool(parameter.clone())),
+            _ => None,
+        });
+
+        // Changing a single setting only sends that setting while switching photos sends many.
+        if let (Some(parameter), None) = (parameters.next(), parameters.next()) {
+            let message = format!("Move a control to bind it to {}", parameter);
+            learning.parameter = Some(parameter);
+            self.lightroom
+                .send(OutgoingMessage::Notification { message });
+        }
+    }
+
+    /// Binds the control to the parameter being learnt. Returns true if the change was used for
+    /// learning and should not be handled as normal.
+    fn learn_control(&mut self, device_id: &str, control: &str, layer: &str, key: bool) -> bool {
+        let parameter = match self.learning {
+            Some(Learning {
+                parameter: Some(ref parameter),
+                ..
+            }) => parameter,
+            _ => return false,
+        };
+
+        let binding = match parameter {
+            Param::Float(_) if !key => json!({
+                "device": device_id,
+                "control": control,
+                "layer": layer,
+                "onChange": parameter,
+            }),
+            Param::Bool(_) if key => json!({
+                "device": device_id,
+                "control": control,
+                "layer": layer,
+                "onPress": { "toggle": parameter },
+            }),
+            _ => {
+                let message = format!(
+                    "{} cannot be bound to {}, try a {}",
+                    parameter,
+                    control,
+                    if key { "knob or slider" } else { "button" }
+                );
+                self.lightroom
+                    .send(OutgoingMessage::Notification { message });
+                return true;
+            }
+        };
+
+        let name = parameter.to_string();
+        let learning = match self.learning.take() {
+            Some(learning) => learning,
+            None => return true,
+        };
+
+        let file = match self.profiles.file(&learning.profile) {
+            Some(file) => file.to_path_buf(),
+            None => self
+                .root
+                .join("profiles")
+                .join(format!("{}.json", learning.profile)),
+        };
+
+        let message = match write_control(&file, binding) {
+            Ok(()) => {
+                log::info!(
+                    "Bound {} on device {} to {} in {}",
+                    control,
+                    device_id,
+                    name,
+                    file.display()
+                );
+                format!(
+                    "Bound {} to {} in profile {}",
+                    control, name, learning.profile
+                )
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                format!("Failed to save the binding for {}", control)
+            }
+        };
+
+        self.lightroom
+            .send(OutgoingMessage::Notification { message });
+        self.reload_profiles();
+
+        true
+    }
+
+    fn start_explaining(&mut self) {
+        let message = if self.explaining.take().is_some() {
+            String::from("Cancelled explaining")
+        } else {
+            self.explaining = Some(Explaining::Next);
+            String::from("Move a control to explain what it does")
+        };
+
+        self.lightroom
+            .send(OutgoingMessage::Notification { message });
+    }
+
+    /// Describes which profile is selected for the device and what the control does in it.
+    pub(crate) fn explain(&self, device_id: &str, control: &str, layer: &str) -> Vec<String> {
+        let mut lines = vec![format!(
+            "Explaining {} in layer {} on device {}",
+            control, layer, device_id
+        )];
+        lines.extend(self.profiles.explain_selection(device_id, &self.state));
+
+        let layer_control = match get_layer_control(&self.devices, device_id, control, layer) {
+            Some(layer_control) => layer_control,
+            None => {
+                lines.push(format!(
+                    "Device {} has no control {} in layer {}",
+                    device_id, control, layer
+                ));
+                return lines;
+            }
+        };
+
+        if let Some(profile) = self.profiles.current_profile(device_id) {
+            lines.extend(profile.explain_control(
+                &self.state,
+                device_id,
+                control,
+                layer,
+                &layer_control,
+            ));
+        }
+
+        lines
+    }
+
+    /// Explains the control if an explain is in progress. Returns true if the change was used and
+    /// should not be handled as normal.
+    fn explain_control(
+        &mut self,
+        device_id: &str,
+        control: &str,
+        layer: &str,
+        key: Option<KeyState>,
+    ) -> bool {
+        match self.explaining {
+            Some(Explaining::Next) => {}
+            Some(Explaining::Release {
+                device_id: ref explained_device,
+                control: ref explained_control,
+                layer: ref explained_layer,
+            }) => {
+                if key == Some(KeyState::Off)
+                    && explained_device == device_id
+                    && explained_control == control
+                    && explained_layer == layer
+                {
+                    self.explaining = None;
+                    return true;
+                }
+                return false;
+            }
+            None => return false,
+        }
+
+        // The release of the key that started explaining.
+        if key == Some(KeyState::Off) {
+            return false;
+        }
+
+        let lines = self.explain(device_id, control, layer);
+        for line in &lines {
+            log::info!("{}", line);
+        }
+
+        self.explaining = if key.is_some() {
+            Some(Explaining::Release {
+                device_id: String::from(device_id),
+                control: String::from(control),
+                layer: String::from(layer),
+            })
+        } else {
+            None
+        };
+
+        let message = format!(
+            "Explained {} in layer {}, the details are in the log",
+            control, layer
+        );
+        self.lightroom
+            .send(OutgoingMessage::Notification { message });
+
+        true
+    }
+
     fn set_internal_bool_parameter(&mut self, param: BoolParam, _: bool) {
-        log::warn!("Attempting to set unknown parameter {:?}", param);
+        match param {
+            BoolParam::Connected => {
+                log::warn!("Attempting to set readonly parameter {:?}", param)
+            }
+            _ => log::warn!("Attempting to set unknown parameter {:?}", param),
+        }
     }
 
     fn set_internal_float_parameter(&mut self, param: FloatParam, _: f64) {
         log::warn!("Attempting to set unknown parameter {:?}", param);
     }
 
+    /// Performs the actions of one of the device's controls, a profile they select is only
+    /// selected for that device.
+    fn perform_device_actions(&mut self, device_id: &str, actions: Vec<Action>) {
+        self.acting_device = Some(String::from(device_id));
+        self.perform_actions(actions);
+        self.acting_device = None;
+    }
+
     fn perform_actions(&mut self, actions: Vec<Action>) {
+        if let Some(ref mut performed) = self.performed {
+            performed.extend(actions.iter().cloned());
+        }
+
+        for action in &actions {
+            self.events.publish(Event::Action {
+                action: action.clone(),
+            });
+        }
+
         for action in actions {
             match action {
                 Action::SetBoolParameter { parameter, value } => match param_module(&parameter) {
@@ -239,10 +775,16 @@ impl Controller {
                     self.lightroom.send(OutgoingMessage::Action(action));
                 }
                 Action::Internal(InternalAction::RefreshController) => {
-                    if let Some(profile) = self.profiles.current_profile() {
-                        profile.update_devices(&mut self.devices, &self.state, true);
-                    };
+                    self.update_devices(true);
+                }
+                Action::Internal(InternalAction::PushProfile { profile }) => {
+                    self.push_profile(&profile)
                 }
+                Action::Internal(InternalAction::PopProfile { profile }) => {
+                    self.pop_profile(profile.as_deref())
+                }
+                Action::Internal(InternalAction::Learn { profile }) => self.start_learning(profile),
+                Action::Internal(InternalAction::Explain) => self.start_explaining(),
             }
         }
     }
@@ -255,11 +797,25 @@ impl Controller {
             device_id,
             value
         );
-        if let Some(profile) = self.profiles.current_profile() {
+        self.active_device = Some(device_id.clone());
+        self.update_profile_param();
+        self.events.publish(Event::ContinuousChange {
+            device: device_id.clone(),
+            control: control.clone(),
+            layer: layer.clone(),
+            value,
+        });
+        if self.explain_control(&device_id, &control, &layer, None)
+            || self.learn_control(&device_id, &control, &layer, false)
+        {
+            return;
+        }
+
+        if let Some(profile) = self.profiles.current_profile(&device_id) {
             if let Some(action) =
                 profile.continuous_actions(&self.state, &device_id, &control, &layer, value)
             {
-                self.perform_actions(action);
+                self.perform_device_actions(&device_id, action);
             }
         }
     }
@@ -278,18 +834,32 @@ impl Controller {
             device_id,
             key_state
         );
-        if let Some(profile) = self.profiles.current_profile() {
+        self.active_device = Some(device_id.clone());
+        self.update_profile_param();
+        self.events.publish(Event::KeyChange {
+            device: device_id.clone(),
+            control: control.clone(),
+            layer: layer.clone(),
+            state: key_state,
+        });
+        if self.explain_control(&device_id, &control, &layer, Some(key_state))
+            || (key_state == KeyState::On && self.learn_control(&device_id, &control, &layer, true))
+        {
+            return;
+        }
+
+        if let Some(profile) = self.profiles.current_profile(&device_id) {
             if let Some(action) =
                 profile.key_actions(&self.state, &device_id, &control, &layer, key_state)
             {
-                self.perform_actions(action);
+                self.perform_device_actions(&device_id, action);
             } else if let Some(layer_control) =
                 get_layer_control(&self.devices, &device_id, &control, &layer)
             {
                 if let Some(device) = self.devices.get_mut(&device_id) {
                     if let Some(ref mut connection) = device.output {
                         profile.update_layer_control(
-                            connection,
+                            connection.as_mut(),
                             &self.state,
                             &device_id,
                             &control,
@@ -303,32 +873,302 @@ impl Controller {
         }
     }
 
+    /// Answers a request from the control socket or HTTP.
+    fn respond(&mut self, request: Request) -> Response {
+        match request {
+            Request::GetState => Response::Ok(json!(self.state.to_json())),
+            Request::GetProfile => {
+                let devices: BTreeMap<&String, Option<String>> = self
+                    .devices
+                    .keys()
+                    .map(|id| {
+                        (
+                            id,
+                            self.profiles.current_profile(id).map(|profile| profile.id),
+                        )
+                    })
+                    .collect();
+
+                Response::Ok(json!({
+                    "profile": self.state.strings.get(&StringParam::Profile),
+                    "devices": devices,
+                    "overlays": self.profiles.overlays(),
+                }))
+            }
+            Request::Set { parameter, value } => {
+                let action: Action =
+                    match serde_json::from_value(json!({ "parameter": parameter, "value": value }))
+                    {
+                        Ok(action) => action,
+                        Err(_) => {
+                            return Response::Error(format!(
+                                "Unknown parameter {parameter} or bad value {value}"
+                            ))
+                        }
+                    };
+
+                let module = match action {
+                    Action::SetBoolParameter { ref parameter, .. } => param_module(parameter),
+                    Action::SetFloatParameter { ref parameter, .. } => param_module(parameter),
+                    Action::SetStringParameter { ref parameter, .. } => param_module(parameter),
+                    _ => Module::Lightroom,
+                };
+                if let Module::Lightroom = module {
+                    return Response::Error(format!(
+                        "{parameter} is a Lightroom parameter, perform an action to change it"
+                    ));
+                }
+
+                self.perform_actions(vec![action]);
+                Response::Ok(JsonValue::Null)
+            }
+            Request::Perform { action } => {
+                self.perform_actions(vec![action]);
+                Response::Ok(JsonValue::Null)
+            }
+            Request::ReloadProfiles => {
+                self.reload_profiles();
+                Response::Ok(JsonValue::Null)
+            }
+            Request::Events => Response::Error(String::from(
+                "Events are only available from the control socket",
+            )),
+            Request::Quit => {
+                log::info!("Asked to exit");
+                self.quitting = true;
+                Response::Ok(JsonValue::Null)
+            }
+        }
+    }
+
+    /// Listens for requests on the control socket in the settings directory.
+    #[cfg(unix)]
+    pub fn serve(&mut self) -> Result<(), String> {
+        self.control_server = Some(ControlServer::start(
+            &self.root,
+            self.sender.clone(),
+            self.events.clone(),
+        )?);
+        Ok(())
+    }
+
+    /// Serves the state and events over HTTP and a WebSocket.
+    #[cfg(feature = "http")]
+    pub fn serve_http(&self, address: &str) -> Result<(), String> {
+        http::serve(
+            &self.root,
+            address,
+            self.sender.clone(),
+            self.events.clone(),
+        )
+    }
+
+    /// Writes every message the controller receives from now on to the recorder.
+    pub fn record(&mut self, mut recorder: Recorder) -> Result<(), String> {
+        recorder.start(self.persisted())?;
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    /// Devices store the new state of a control before sending the change to the controller. Does
+    /// the same for changes that did not come from a device.
+    fn sync_control(&self, message: &ControlMessage) {
+        match message {
+            ControlMessage::ContinuousChange {
+                device_id,
+                control,
+                layer,
+                value,
+            } => {
+                if let Some(LayerControl::Continuous(layer_control)) =
+                    get_layer_control(&self.devices, device_id, control, layer)
+                {
+                    layer_control.set_value(layer_control.state_from_value(*value));
+                }
+            }
+            ControlMessage::KeyChange {
+                device_id,
+                control,
+                layer,
+                state,
+            } => {
+                if let Some(LayerControl::Key(layer_control)) =
+                    get_layer_control(&self.devices, device_id, control, layer)
+                {
+                    layer_control.set_value(*state);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Handles a single message, returns false once the controller should stop.
+    fn handle(&mut self, message: ControlMessage) -> bool {
+        match message {
+            ControlMessage::Reset => self.reset_state(),
+            ControlMessage::Disconnect if self.daemon => self.disconnect(),
+            ControlMessage::Disconnect => {
+                log::info!("Service disconnecting");
+                return false;
+            }
+            ControlMessage::StateChange { values } => self.update_state(values),
+            ControlMessage::ContinuousChange {
+                device_id: device,
+                control,
+                layer,
+                value,
+            } => self.continuous_change(device, control, layer, value),
+            ControlMessage::KeyChange {
+                device_id: device,
+                control,
+                layer,
+                state,
+            } => self.key_change(device, control, layer, state),
+            ControlMessage::Request { request, reply } => {
+                let response = self.respond(request);
+                if reply.send(response).is_err() {
+                    log::debug!("Control client went away before the response was sent");
+                }
+            }
+        }
+
+        self.persister.update(self.persisted());
+        !self.quitting
+    }
+
+    fn persisted(&self) -> PersistedState {
+        PersistedState::new(
+            &self.state,
+            self.profiles.selections(),
+            self.profiles.overlays(),
+        )
+    }
+
     pub fn run(&mut self) -> Result<(), String> {
         loop {
             let message = self
                 .receiver
                 .recv()
                 .map_err(|e| format!("Control message channel failed: {e}"))?;
-            match message {
-                ControlMessage::Reset => self.reset_state(),
-                ControlMessage::Disconnect => {
-                    log::info!("Service disconnecting");
-                    return Ok(());
+
+            if let Some(ref mut recorder) = self.recorder {
+                if let Err(e) = recorder.record(&message) {
+                    log::error!("{}", e);
+                    self.recorder = None;
                 }
-                ControlMessage::StateChange { values } => self.update_state(values),
-                ControlMessage::ContinuousChange {
-                    device_id: device,
-                    control,
-                    layer,
-                    value,
-                } => self.continuous_change(device, control, layer, value),
-                ControlMessage::KeyChange {
-                    device_id: device,
-                    control,
-                    layer,
-                    state,
-                } => self.key_change(device, control, layer, state),
             }
+
+            if !self.handle(message) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{create_dir_all, remove_dir_all, write},
+    };
+
+    use super::*;
+
+    /// A settings directory with one device and a profile to push over its base profile.
+    struct Settings {
+        root: PathBuf,
+    }
+
+    impl Settings {
+        fn new(name: &str) -> Settings {
+            let root =
+                env::temp_dir().join(format!("midi-ctrl-lib-{}-{}", name, std::process::id()));
+            let _ = remove_dir_all(&root);
+            create_dir_all(root.join("devices")).unwrap();
+            create_dir_all(root.join("profiles")).unwrap();
+
+            let device = json!({
+                "port": "Test Controller",
+                "controls": [
+                    { "type": "key", "name": "Button", "layers": { "A": { "channel": 1, "note": 8 } } }
+                ]
+            });
+            write(root.join("devices/test.json"), device.to_string()).unwrap();
+
+            let base = json!({
+                "controls": [
+                    { "device": "test", "layer": "A", "control": "Button", "onPress": { "toggle": "Picked" } }
+                ]
+            });
+            write(root.join("profiles/base.json"), base.to_string()).unwrap();
+
+            let reject = json!({
+                "controls": [
+                    { "device": "test", "layer": "A", "control": "Button", "onPress": { "toggle": "Rejected" } }
+                ]
+            });
+            write(root.join("profiles/reject.json"), reject.to_string()).unwrap();
+
+            Settings { root }
+        }
+
+        /// Creates a controller the way `Controller::new` does, restoring and persisting its state.
+        fn controller(&self) -> (Controller, Receiver<OutgoingMessage>) {
+            let persisted = PersistedState::read(&self.root)
+                .unwrap()
+                .unwrap_or_default();
+            let (sender, receiver) = channel();
+            let (lightroom, outgoing) = Lightroom::detached(sender.clone());
+            let persister = StatePersister::new(&self.root, persisted.clone());
+            let devices = devices(sender.clone(), &self.root, &MemoryTransport::new());
+
+            let controller = Controller::create(
+                &self.root, devices, sender, receiver, lightroom, persisted, persister, true,
+            );
+            (controller, outgoing)
+        }
+    }
+
+    impl Drop for Settings {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn persisted_round_trip() {
+        let settings = Settings::new("persisted");
+
+        {
+            let (mut controller, _outgoing) = settings.controller();
+            controller.perform_actions(vec![
+                Action::SetStringParameter {
+                    parameter: StringParam::Custom(String::from("Mode")),
+                    value: String::from("masking"),
+                },
+                Action::Internal(InternalAction::PushProfile {
+                    profile: String::from("reject"),
+                }),
+            ]);
+            controller.persister.update(controller.persisted());
+            // Dropping the controller flushes the state file.
         }
+
+        let (controller, _outgoing) = settings.controller();
+        assert_eq!(controller.profiles.overlays(), ["reject"]);
+        assert_eq!(
+            controller
+                .profiles
+                .current_profile("test")
+                .map(|profile| profile.id),
+            Some(String::from("base"))
+        );
+        assert_eq!(
+            controller
+                .state
+                .strings
+                .get(&StringParam::Custom(String::from("Mode"))),
+            Some(&String::from("masking"))
+        );
     }
 }
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use std::{
-    cmp::max,
+    cmp::min,
     io::{BufRead, BufReader, ErrorKind, Write},
     net::{Ipv4Addr, TcpStream},
     sync::{
@@ -16,6 +16,11 @@ use crate::state::{Param, StateValue};
 
 use super::LightroomAction;
 
+/// How long to wait before giving up on connecting, unless running as a daemon.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest to wait between attempts to connect.
+const MAX_BACKOFF: u64 = 5000;
+
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
@@ -43,21 +48,22 @@ impl Iterator for Incoming {
     type Item = IncomingMessage;
 
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            let received = self.receiver.recv();
-            match received {
-                Ok(IncomingMessage::Disconnect) => return None,
-                Ok(message) => {
-                    log::trace!("Received message: {:?}", message);
-                    return Some(message);
-                }
-                Err(e) => log::error!("Bad IPC message: {}", e),
+        match self.receiver.recv() {
+            Ok(message) => {
+                log::trace!("Received message: {:?}", message);
+                Some(message)
+            }
+            Err(e) => {
+                log::error!("IPC message channel closed: {}", e);
+                None
             }
         }
     }
 }
 
-fn open_stream(port: u16) -> Result<Option<TcpStream>, String> {
+/// Connects to the plugin, retrying with an increasing delay. Unless `daemon` is set this gives up
+/// if the plugin cannot be reached for a while.
+fn open_stream(port: u16, daemon: bool) -> Result<Option<TcpStream>, String> {
     let now = Instant::now();
     let mut backoff = 100;
     loop {
@@ -65,12 +71,12 @@ fn open_stream(port: u16) -> Result<Option<TcpStream>, String> {
             Ok(stream) => return Ok(Some(stream)),
             Err(e) => match e.kind() {
                 ErrorKind::ConnectionRefused | ErrorKind::TimedOut => {
-                    thread::sleep(Duration::from_millis(backoff));
-                    backoff = max(1000, backoff + 100);
-
-                    if now.elapsed().as_secs() > 10 {
+                    if !daemon && now.elapsed() > CONNECT_TIMEOUT {
                         return Ok(None);
                     }
+
+                    thread::sleep(Duration::from_millis(backoff));
+                    backoff = min(MAX_BACKOFF, backoff * 2);
                 }
                 _ => return Err(format!("Failed opening TCP stream: {e}")),
             },
@@ -80,9 +86,10 @@ fn open_stream(port: u16) -> Result<Option<TcpStream>, String> {
 
 fn open_outgoing_stream(
     port: u16,
+    daemon: bool,
     outgoing_stream: &Arc<Mutex<Option<TcpStream>>>,
 ) -> Result<bool, String> {
-    let stream = match open_stream(port)? {
+    let stream = match open_stream(port, daemon)? {
         Some(stream) => stream,
         None => return Ok(false),
     };
@@ -120,15 +127,22 @@ fn open_outgoing_stream(
 
     log::debug!("IPC outgoing stream closed");
 
-    Ok(false)
+    Ok(daemon)
 }
 
-fn open_incoming_stream(port: u16, sender: Sender<IncomingMessage>) -> Result<bool, String> {
-    let stream = match open_stream(port)? {
+/// Reads messages from the plugin until it disconnects. `connected` is set once the stream is
+/// open so that callers know the plugin went away even when this fails.
+fn open_incoming_stream(
+    port: u16,
+    daemon: bool,
+    connected: &mut bool,
+    sender: Sender<IncomingMessage>,
+) -> Result<(), String> {
+    let stream = match open_stream(port, daemon)? {
         Some(stream) => stream,
         None => {
             log::warn!("Unable to connect for 10 seconds, giving up");
-            return Ok(false);
+            return Ok(());
         }
     };
 
@@ -136,6 +150,7 @@ fn open_incoming_stream(port: u16, sender: Sender<IncomingMessage>) -> Result<bo
         .try_clone()
         .map_err(|e| format!("Failed to clone incoming stream: {e}"))?;
 
+    *connected = true;
     log::debug!("IPC incoming stream connected");
 
     let reader = BufReader::new(stream);
@@ -155,7 +170,7 @@ fn open_incoming_stream(port: u16, sender: Sender<IncomingMessage>) -> Result<bo
         .map_err(|e| format!("Failed to parse incoming IPC message: {e}"))?;
 
         match message {
-            IncomingMessage::Disconnect => return Ok(false),
+            IncomingMessage::Disconnect => return Ok(()),
             message => sender
                 .send(message)
                 .map_err(|e| format!("Failed to pass incoming IPC message: {e}"))?,
@@ -164,23 +179,41 @@ fn open_incoming_stream(port: u16, sender: Sender<IncomingMessage>) -> Result<bo
 
     log::debug!("IPC incoming stream closed");
 
-    Ok(false)
+    Ok(())
 }
 
-pub fn connect(incoming_port: u16, outgoing_port: u16) -> (Incoming, Sender<OutgoingMessage>) {
+/// Connects to the plugin. When the plugin goes away a `Disconnect` message is passed on and,
+/// if `daemon` is set, the connection is retried until the plugin returns.
+pub fn connect(
+    incoming_port: u16,
+    outgoing_port: u16,
+    daemon: bool,
+) -> (Incoming, Sender<OutgoingMessage>) {
     let (incoming_sender, incoming_receiver) = channel();
     let (outgoing_sender, outgoing_receiver) = channel();
 
     thread::spawn(move || loop {
-        match open_incoming_stream(incoming_port, incoming_sender.clone()) {
-            Ok(false) => {
-                if let Err(e) = incoming_sender.send(IncomingMessage::Disconnect) {
-                    log::error!("Unable to send disconnect message: {}", e);
-                }
+        let mut connected = false;
+        let result = open_incoming_stream(
+            incoming_port,
+            daemon,
+            &mut connected,
+            incoming_sender.clone(),
+        );
+        if let Err(ref e) = result {
+            log::error!("IPC incoming stream error: {}", e);
+        }
+
+        // A stream that failed after connecting has still gone away.
+        if result.is_ok() || connected {
+            if let Err(e) = incoming_sender.send(IncomingMessage::Disconnect) {
+                log::error!("Unable to send disconnect message: {}", e);
+                return;
+            }
+
+            if !daemon {
                 return;
             }
-            Ok(true) => (),
-            Err(e) => log::error!("IPC incoming stream error: {}", e),
         }
 
         thread::sleep(Duration::from_millis(200));
@@ -190,7 +223,7 @@ pub fn connect(incoming_port: u16, outgoing_port: u16) -> (Incoming, Sender<Outg
     let receiving_stream = outgoing_stream.clone();
 
     thread::spawn(move || loop {
-        match open_outgoing_stream(outgoing_port, &outgoing_stream) {
+        match open_outgoing_stream(outgoing_port, daemon, &outgoing_stream) {
             Ok(false) => return,
             Ok(true) => (),
             Err(e) => log::error!("IPC outgoing stream error: {}", e),
@@ -217,6 +250,8 @@ pub fn connect(incoming_port: u16, outgoing_port: u16) -> (Incoming, Sender<Outg
                 stream
                     .flush()
                     .map_err(|e| format!("Failed writing to outgoing IPC stream: {e}"))?;
+            } else if daemon {
+                log::debug!("Dropping message while Lightroom is disconnected");
             } else {
                 log::warn!("Attempt to send message while not connected");
             }
@@ -1,7 +1,10 @@
 mod actions;
 mod ipc;
 
-use std::{sync::mpsc::Sender, thread};
+use std::{
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
 
 use crate::ControlMessage;
 
@@ -20,8 +23,9 @@ impl Lightroom {
         control_sender: Sender<ControlMessage>,
         incoming_port: u16,
         outgoing_port: u16,
+        daemon: bool,
     ) -> Lightroom {
-        let (incoming, sender) = connect(incoming_port, outgoing_port);
+        let (incoming, sender) = connect(incoming_port, outgoing_port, daemon);
 
         let thread_sender = control_sender.clone();
         thread::spawn(move || {
@@ -33,7 +37,12 @@ impl Lightroom {
 
             for message in incoming {
                 match message {
-                    IncomingMessage::Disconnect => break,
+                    IncomingMessage::Disconnect => {
+                        send_control_message(ControlMessage::Disconnect);
+                        if !daemon {
+                            return;
+                        }
+                    }
                     IncomingMessage::Test => (),
                     IncomingMessage::Reset => send_control_message(ControlMessage::Reset),
                     IncomingMessage::State { values } => {
@@ -51,6 +60,22 @@ impl Lightroom {
         }
     }
 
+    /// Creates a connection that never reaches Lightroom, the messages sent to Lightroom are
+    /// returned through the receiver instead.
+    pub fn detached(
+        control_sender: Sender<ControlMessage>,
+    ) -> (Lightroom, Receiver<OutgoingMessage>) {
+        let (sender, receiver) = channel();
+
+        (
+            Lightroom {
+                _control_sender: control_sender,
+                sender,
+            },
+            receiver,
+        )
+    }
+
     pub fn send(&self, message: OutgoingMessage) {
         if let Err(e) = self.sender.send(message) {
             log::error!("Failed to send IPC message: {}", e);
@@ -3,52 +3,306 @@ use std::{
     env::{self, current_dir},
     fs::canonicalize,
     path::PathBuf,
+    process::ExitCode,
 };
 
-use midi_ctrl::Controller;
+use serde_json::json;
 
-fn run() -> Result<(), String> {
+#[cfg(unix)]
+use midi_ctrl::control::send_request;
+#[cfg(feature = "http")]
+use midi_ctrl::http::DEFAULT_ADDRESS;
+use midi_ctrl::{
+    check::check,
+    control::Request,
+    explain::explain,
+    fake_lightroom::fake_lightroom,
+    instance::InstanceLock,
+    learn::learn,
+    monitor::{monitor, ports},
+    replay::{replay, Recorder},
+    scenario::run_scenario,
+    schema::write_schemas,
+    utils::default_settings_dir,
+    Controller, MidirTransport,
+};
+
+enum Command {
+    Run {
+        embedded: bool,
+        daemon: bool,
+    },
+    Replay {
+        file: PathBuf,
+    },
+    Test {
+        file: PathBuf,
+    },
+    Explain {
+        file: PathBuf,
+        device: String,
+        control: String,
+        layer: String,
+    },
+    Check {
+        /// Fail on warnings as well as errors.
+        strict: bool,
+    },
+    Learn {
+        port: String,
+    },
+    Ports,
+    Monitor {
+        port: Option<String>,
+    },
+    FakeLightroom,
+    Ctl {
+        request: Request,
+    },
+}
+
+const CTL_USAGE: &str = "Usage: midi-ctrl ctl <state | profile | set <parameter> <value> | action <action> | reload-profiles | events | quit> [settings directory]";
+
+#[cfg(feature = "http")]
+fn serve_http(controller: &Controller, address: Option<String>) -> Result<(), String> {
+    controller.serve_http(address.as_deref().unwrap_or(DEFAULT_ADDRESS))
+}
+
+#[cfg(not(feature = "http"))]
+fn serve_http(_: &Controller, _: Option<String>) -> Result<(), String> {
+    Err(String::from(
+        "MidiCtrl was built without HTTP support, build it with `--features http`",
+    ))
+}
+
+fn ctl_request(args: &mut Vec<String>) -> Result<Request, String> {
+    if args.is_empty() {
+        return Err(String::from(CTL_USAGE));
+    }
+
+    let request = match args.remove(0).as_str() {
+        "state" => Request::GetState,
+        "profile" => Request::GetProfile,
+        "reload-profiles" => Request::ReloadProfiles,
+        "events" => Request::Events,
+        "quit" => Request::Quit,
+        "set" if args.len() >= 2 => {
+            let parameter = args.remove(0);
+            let value = args.remove(0);
+            Request::set(parameter, &value)?
+        }
+        "action" if !args.is_empty() => {
+            // Either an action as it would appear in a profile or just the name of the action.
+            let action = args.remove(0);
+            let action = serde_json::from_str(&action).unwrap_or(json!({ "action": action }));
+            Request::Perform {
+                action: serde_json::from_value(action.clone())
+                    .map_err(|_| format!("Unknown action {action}"))?,
+            }
+        }
+        _ => return Err(String::from(CTL_USAGE)),
+    };
+
+    Ok(request)
+}
+
+fn settings_dir(arg: Option<&String>) -> Result<PathBuf, String> {
+    if let Some(arg) = arg {
+        match canonicalize(PathBuf::from(arg)) {
+            Ok(dir) => Ok(dir),
+            Err(e) => Err(format!("Failed to find settings directory: {e}")),
+        }
+    } else {
+        default_settings_dir()
+    }
+}
+
+fn run_check(dir: PathBuf, strict: bool) -> ExitCode {
+    let problems = check(&dir);
+
+    for error in &problems.errors {
+        println!("error: {error}");
+    }
+
+    for warning in &problems.warnings {
+        println!("warning: {warning}");
+    }
+
+    if problems.errors.is_empty() && problems.warnings.is_empty() {
+        println!("No problems found in {}", dir.display());
+        return ExitCode::SUCCESS;
+    }
+
+    println!(
+        "Found {} errors and {} warnings in {}",
+        problems.errors.len(),
+        problems.warnings.len(),
+        dir.display()
+    );
+
+    // Only warnings are left by now.
+    if problems.errors.is_empty() && !strict {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn run() -> Result<ExitCode, String> {
     let mut args: Vec<String> = env::args().collect();
 
     if !args.is_empty() {
         args.remove(0);
     }
 
-    let mut embedded = false;
+    // `--http` or `--http=<address>` anywhere serves the HTTP API.
+    let mut http = None;
+    if let Some(position) = args.iter().position(|arg| arg.starts_with("--http")) {
+        let arg = args.remove(position);
+        http = match arg.strip_prefix("--http") {
+            Some("") => Some(None),
+            Some(address) if address.starts_with('=') => Some(Some(address[1..].to_string())),
+            _ => return Err(format!("Unknown option {arg}")),
+        };
+    }
 
-    if let Some(arg) = args.get(0) {
-        if arg == "embedded" {
-            embedded = true;
-            args.remove(0);
-        }
+    // `--record <file>` or `--record=<file>` anywhere records the session when running.
+    let mut record = None;
+    if let Some(position) = args.iter().position(|arg| arg.starts_with("--record")) {
+        let arg = args.remove(position);
+        record = match arg.strip_prefix("--record") {
+            Some("") if position < args.len() => Some(PathBuf::from(args.remove(position))),
+            Some("") => return Err(String::from("Usage: --record <file>")),
+            Some(file) if file.starts_with('=') => Some(PathBuf::from(&file[1..])),
+            _ => return Err(format!("Unknown option {arg}")),
+        };
     }
 
-    let dir = if let Some(arg) = args.get(0) {
-        match canonicalize(PathBuf::from(arg)) {
-            Ok(dir) => dir,
-            Err(e) => {
-                return Err(format!("Failed to find settings directory: {e}"));
+    let mut command = Command::Run {
+        embedded: false,
+        daemon: false,
+    };
+
+    if let Some(arg) = args.first() {
+        match arg.as_str() {
+            "embedded" => {
+                command = Command::Run {
+                    embedded: true,
+                    daemon: false,
+                };
+                args.remove(0);
             }
-        }
-    } else {
-        match dirs::config_dir() {
-            Some(mut dir) => {
-                dir.push("midi-ctrl");
-                dir
-            }
-            None => match current_dir() {
-                Ok(dir) => dir,
-                Err(e) => {
-                    return Err(format!("Failed to find settings directory: {e}"));
+            "daemon" => {
+                command = Command::Run {
+                    embedded: false,
+                    daemon: true,
+                };
+                args.remove(0);
+            }
+            "check" => {
+                args.remove(0);
+                let strict = args.first().is_some_and(|arg| arg == "--strict");
+                if strict {
+                    args.remove(0);
+                }
+                command = Command::Check { strict };
+            }
+            "learn" => {
+                args.remove(0);
+                if args.is_empty() {
+                    return Err(String::from(
+                        "Usage: midi-ctrl learn <port> [settings directory]",
+                    ));
+                }
+                command = Command::Learn {
+                    port: args.remove(0),
+                };
+            }
+            "test" => {
+                args.remove(0);
+                if args.is_empty() {
+                    return Err(String::from(
+                        "Usage: midi-ctrl test <scenario> [settings directory]",
+                    ));
+                }
+                command = Command::Test {
+                    file: PathBuf::from(args.remove(0)),
+                };
+            }
+            "ctl" => {
+                args.remove(0);
+                command = Command::Ctl {
+                    request: ctl_request(&mut args)?,
+                };
+            }
+            "explain" => {
+                args.remove(0);
+                if args.len() < 4 {
+                    return Err(String::from(
+                        "Usage: midi-ctrl explain <state file> <device> <control> <layer> [settings directory]",
+                    ));
+                }
+                command = Command::Explain {
+                    file: PathBuf::from(args.remove(0)),
+                    device: args.remove(0),
+                    control: args.remove(0),
+                    layer: args.remove(0),
+                };
+            }
+            "record" | "replay" => {
+                let name = args.remove(0);
+                if args.is_empty() {
+                    return Err(format!(
+                        "Usage: midi-ctrl {name} <file> [settings directory]"
+                    ));
+                }
+                let file = PathBuf::from(args.remove(0));
+                if name == "record" {
+                    record = Some(file);
+                } else {
+                    command = Command::Replay { file };
                 }
-            },
+            }
+            "fake-lightroom" => {
+                command = Command::FakeLightroom;
+                args.remove(0);
+            }
+            "ports" => {
+                command = Command::Ports;
+                args.remove(0);
+            }
+            "monitor" => {
+                args.remove(0);
+                command = Command::Monitor {
+                    port: if args.is_empty() {
+                        None
+                    } else {
+                        Some(args.remove(0))
+                    },
+                };
+            }
+            "schema" => {
+                args.remove(0);
+                let dir = match args.first() {
+                    Some(dir) => PathBuf::from(dir),
+                    None => current_dir()
+                        .map_err(|e| format!("Failed to find output directory: {e}"))?,
+                };
+
+                write_schemas(&dir)?;
+                println!("Wrote schemas to {}", dir.display());
+                return Ok(ExitCode::SUCCESS);
+            }
+            _ => (),
         }
-    };
+    }
+
+    let dir = settings_dir(args.first())?;
 
     let logger = Logger::try_with_env_or_str("info")
         .map_err(|e| format!("Failed to initialize logging: {e}"))?;
 
-    let logger = if embedded {
+    let logger = if let Command::Run { embedded: true, .. } = command {
         let mut filename = dir.clone();
         filename.push("midi-ctrl.log");
         let spec = FileSpec::try_from(filename).unwrap();
@@ -62,12 +316,85 @@ fn run() -> Result<(), String> {
         .start()
         .map_err(|e| format!("Failed to start logging: {e}"))?;
 
-    let mut controller = Controller::new(&dir)?;
-    controller.run()
+    match command {
+        Command::Check { strict } => Ok(run_check(dir, strict)),
+        Command::Learn { port } => {
+            learn(&dir, &port)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::FakeLightroom => {
+            fake_lightroom(61327, 61328)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Ports => {
+            ports(&dir)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Monitor { port } => {
+            monitor(&dir, port.as_deref())?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Test { file } => {
+            if run_scenario(&dir, &file)? {
+                Ok(ExitCode::SUCCESS)
+            } else {
+                Ok(ExitCode::FAILURE)
+            }
+        }
+        Command::Explain {
+            file,
+            device,
+            control,
+            layer,
+        } => {
+            explain(&dir, &file, &device, &control, &layer)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Replay { file } => {
+            replay(&dir, &file)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        #[cfg(unix)]
+        Command::Ctl { request } => {
+            if send_request(&dir, &request)? {
+                Ok(ExitCode::SUCCESS)
+            } else {
+                Ok(ExitCode::FAILURE)
+            }
+        }
+        #[cfg(not(unix))]
+        Command::Ctl { .. } => Err(String::from(
+            "The control socket is only available on macOS and Linux",
+        )),
+        Command::Run { daemon, .. } => {
+            let _lock = InstanceLock::acquire(&dir, daemon)?;
+            // The recording notes the MIDI ports before the controller connects to them.
+            let recorder = match record {
+                Some(file) => Some((Recorder::create(&file, &MidirTransport)?, file)),
+                None => None,
+            };
+            let mut controller = Controller::new(&dir, daemon)?;
+            if let Some((recorder, file)) = recorder {
+                controller.record(recorder)?;
+                log::info!("Recording to {}", file.display());
+            }
+            #[cfg(unix)]
+            controller.serve()?;
+            if let Some(address) = http {
+                serve_http(&controller, address)?;
+            }
+            controller.run()?;
+            Ok(ExitCode::SUCCESS)
+        }
+    }
 }
 
-fn main() {
-    if let Err(e) = run() {
-        eprintln!("Error starting midi-ctrl: {e}");
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error starting midi-ctrl: {e}");
+            ExitCode::FAILURE
+        }
     }
 }
@@ -1,16 +1,20 @@
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     fmt::Display,
     sync::{Arc, Mutex},
 };
 
 use midi_control::note::MidiNote;
-use midi_control::transport::MidiMessageSend;
 use midi_control::Channel;
-use midir::MidiOutputConnection;
-use serde::{de, de::Visitor, Deserialize, Deserializer};
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::{de, de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 
-fn deserialize_channel<'de, D: Deserializer<'de>>(de: D) -> Result<Channel, D::Error> {
+use super::{
+    osc::{OscArg, OscMessage},
+    transport::OutputConnection,
+};
+
+fn deserialize_channel<'de, D: Deserializer<'de>>(de: D) -> Result<Option<Channel>, D::Error> {
     struct ChannelVisitor;
 
     impl<'de> Visitor<'de> for ChannelVisitor {
@@ -43,7 +47,22 @@ fn deserialize_channel<'de, D: Deserializer<'de>>(de: D) -> Result<Channel, D::E
         }
     }
 
-    de.deserialize_any(ChannelVisitor {})
+    de.deserialize_any(ChannelVisitor {}).map(Some)
+}
+
+fn serialize_channel<S: Serializer>(channel: &Option<Channel>, se: S) -> Result<S::Ok, S::Error> {
+    match channel {
+        Some(channel) => se.serialize_u8(*channel as u8 + 1),
+        None => se.serialize_none(),
+    }
+}
+
+fn channel_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "type": "integer",
+        "minimum": 1,
+        "maximum": 16
+    })
 }
 
 fn default0() -> u8 {
@@ -54,23 +73,111 @@ fn default127() -> u8 {
     127
 }
 
-#[derive(Deserialize, Clone, Debug)]
+fn is0(value: &u8) -> bool {
+    *value == 0
+}
+
+fn is127(value: &u8) -> bool {
+    *value == 127
+}
+
+/// Sends a label as an OSC string unless it is the label that was last sent.
+fn send_label(
+    connection: &mut dyn OutputConnection,
+    address: &str,
+    sent: &Mutex<Option<String>>,
+    label: &str,
+    force: bool,
+) {
+    let mut guard = match sent.lock() {
+        Ok(sent) => sent,
+        Err(e) => {
+            log::warn!("Failed to lock label for update: {}", e);
+            return;
+        }
+    };
+
+    if !force && guard.as_deref() == Some(label) {
+        return;
+    }
+
+    let data = OscMessage::new(address, vec![OscArg::String(String::from(label))]).encode();
+    match connection.send(&data) {
+        Ok(()) => *guard = Some(String::from(label)),
+        Err(e) => log::error!("{}", e),
+    }
+}
+
+/// How a relative encoder encodes the amount it was turned by.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Relative {
+    /// 1 to 63 turn up, 127 down to 65 turn down.
+    TwosComplement,
+    /// 65 and above turn up, 63 and below turn down.
+    BinaryOffset,
+    /// 1 to 63 turn up, 65 to 127 turn down.
+    SignBit,
+}
+
+impl Relative {
+    pub fn delta(&self, value: u8) -> i16 {
+        let value = i16::from(value & 0x7f);
+        match self {
+            Relative::TwosComplement if value >= 64 => value - 128,
+            Relative::TwosComplement => value,
+            Relative::BinaryOffset => value - 64,
+            Relative::SignBit if value >= 64 => 64 - value,
+            Relative::SignBit => value,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 pub struct ContinuousLayer {
-    #[serde(deserialize_with = "deserialize_channel")]
-    pub channel: Channel,
-    pub control: u8,
-    #[serde(default = "default0")]
+    /// Needed by MIDI devices, OSC devices use the address instead.
+    #[serde(
+        deserialize_with = "deserialize_channel",
+        serialize_with = "serialize_channel",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(schema_with = "channel_schema")]
+    pub channel: Option<Channel>,
+    /// Needed by MIDI devices unless they send pitch bend.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control: Option<u8>,
+    /// Set for encoders that send how far they were turned rather than their position.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relative: Option<Relative>,
+    /// Set for controls that send pitch bend rather than control changes, only the most
+    /// significant 7 bits are used.
+    #[serde(
+        rename = "pitchBend",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub pitch_bend: bool,
+    /// The OSC address of the control on an OSC device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// An OSC address to send the name of the parameter the control changes to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default = "default0", skip_serializing_if = "is0")]
     pub min: u8,
-    #[serde(default = "default127")]
+    #[serde(default = "default127", skip_serializing_if = "is127")]
     pub max: u8,
     #[serde(skip)]
     pub state: Arc<Mutex<u8>>,
+    #[serde(skip)]
+    pub sent_label: Arc<Mutex<Option<String>>>,
 }
 
 impl ContinuousLayer {
     pub fn value_from_state(&self, state: u8) -> f64 {
-        let value: f64 = (state - self.min).into();
-        let range: f64 = (self.max - self.min).into();
+        let value: f64 = state.saturating_sub(self.min).into();
+        let range: f64 = self.max.saturating_sub(self.min).into();
         value / range
     }
 
@@ -97,7 +204,26 @@ impl ContinuousLayer {
         *guard = state;
     }
 
-    pub fn update(&self, connection: &mut MidiOutputConnection, state: u8, force: bool) {
+    /// Converts the value sent by the control into its new state, relative encoders move on from
+    /// the last known state. Values outside of `min` and `max` are moved inside them.
+    pub fn state_from_message(&self, value: u8) -> u8 {
+        let relative = match self.relative {
+            Some(relative) => relative,
+            None => return value.max(self.min).min(self.max),
+        };
+
+        let current = match self.state.lock() {
+            Ok(state) => *state,
+            Err(e) => {
+                log::warn!("Failed to lock state for update: {}", e);
+                self.min
+            }
+        };
+
+        (i16::from(current) + relative.delta(value)).clamp(self.min.into(), self.max.into()) as u8
+    }
+
+    pub fn update(&self, connection: &mut dyn OutputConnection, state: u8, force: bool) {
         let mut guard = match self.state.lock() {
             Ok(state) => state,
             Err(e) => {
@@ -110,24 +236,45 @@ impl ContinuousLayer {
             return;
         }
 
-        let message = midi_control::control_change(self.channel, self.control, state);
+        let data: Vec<u8> = match (&self.address, self.channel, self.control) {
+            (Some(address), _, _) => OscMessage::new(
+                address,
+                vec![OscArg::Float(self.value_from_state(state) as f32)],
+            )
+            .encode(),
+            (None, Some(channel), _) if self.pitch_bend => {
+                midi_control::pitch_bend(channel, u16::from(state) << 7).into()
+            }
+            (None, Some(channel), Some(control)) => {
+                midi_control::control_change(channel, control, state).into()
+            }
+            // Reported when the device is validated.
+            _ => return,
+        };
 
-        match connection.send_message(message) {
+        match connection.send(&data) {
             Ok(()) => *guard = state,
-            Err(e) => log::error!("Failed to send MIDI message: {}", e),
+            Err(e) => log::error!("{}", e),
+        }
+    }
+
+    pub fn update_label(&self, connection: &mut dyn OutputConnection, label: &str, force: bool) {
+        if let Some(address) = &self.label {
+            send_label(connection, address, &self.sent_label, label, force);
         }
     }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 pub struct ContinuousControl {
     pub name: String,
-    pub layers: HashMap<String, ContinuousLayer>,
+    pub layers: BTreeMap<String, ContinuousLayer>,
 }
 
-#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
-#[serde(from = "bool")]
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(from = "bool", into = "bool")]
 pub enum KeyState {
+    #[default]
     Off,
     On,
 }
@@ -150,23 +297,40 @@ impl From<bool> for KeyState {
     }
 }
 
-impl Default for KeyState {
-    fn default() -> Self {
-        KeyState::Off
+impl From<KeyState> for bool {
+    fn from(val: KeyState) -> Self {
+        val == KeyState::On
     }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 pub struct KeyLayer {
-    #[serde(deserialize_with = "deserialize_channel")]
-    pub channel: Channel,
-    pub note: MidiNote,
-    #[serde(default = "default0")]
+    /// Needed by MIDI devices, OSC devices use the address instead.
+    #[serde(
+        deserialize_with = "deserialize_channel",
+        serialize_with = "serialize_channel",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(schema_with = "channel_schema")]
+    pub channel: Option<Channel>,
+    /// Needed by MIDI devices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<MidiNote>,
+    /// The OSC address of the control on an OSC device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// An OSC address to send the name of what the key does to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default = "default0", skip_serializing_if = "is0")]
     pub off: u8,
-    #[serde(default = "default127")]
+    #[serde(default = "default127", skip_serializing_if = "is127")]
     pub on: u8,
     #[serde(skip)]
     pub state: Arc<Mutex<KeyState>>,
+    #[serde(skip)]
+    pub sent_label: Arc<Mutex<Option<String>>>,
 }
 
 impl KeyLayer {
@@ -182,7 +346,7 @@ impl KeyLayer {
         *guard = state;
     }
 
-    pub fn update(&self, connection: &mut MidiOutputConnection, state: KeyState, force: bool) {
+    pub fn update(&self, connection: &mut dyn OutputConnection, state: KeyState, force: bool) {
         let mut guard = match self.state.lock() {
             Ok(state) => state,
             Err(e) => {
@@ -195,27 +359,47 @@ impl KeyLayer {
             return;
         }
 
-        let message = match state {
-            KeyState::On => midi_control::note_on(self.channel, self.note, self.on),
-            KeyState::Off => midi_control::note_off(self.channel, self.note, self.off),
+        let data: Vec<u8> = match (&self.address, self.channel, self.note, state) {
+            (Some(address), _, _, _) => {
+                let velocity = match state {
+                    KeyState::On => self.on,
+                    KeyState::Off => self.off,
+                };
+                OscMessage::new(address, vec![OscArg::Float(f32::from(velocity) / 127.0)]).encode()
+            }
+            (None, Some(channel), Some(note), KeyState::On) => {
+                midi_control::note_on(channel, note, self.on).into()
+            }
+            (None, Some(channel), Some(note), KeyState::Off) => {
+                midi_control::note_off(channel, note, self.off).into()
+            }
+            // Reported when the device is validated.
+            _ => return,
         };
 
-        match connection.send_message(message) {
+        match connection.send(&data) {
             Ok(()) => *guard = state,
-            Err(e) => log::error!("Failed to send MIDI message: {}", e),
+            Err(e) => log::error!("{}", e),
+        }
+    }
+
+    pub fn update_label(&self, connection: &mut dyn OutputConnection, label: &str, force: bool) {
+        if let Some(address) = &self.label {
+            send_label(connection, address, &self.sent_label, label, force);
         }
     }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 pub struct KeyControl {
     pub name: String,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[allow(dead_code)]
     pub display: bool,
-    pub layers: HashMap<String, KeyLayer>,
+    pub layers: BTreeMap<String, KeyLayer>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[serde(tag = "type")]
 pub enum Control {
     #[serde(rename = "cc")]
@@ -255,6 +439,25 @@ impl Control {
         }
     }
 
+    /// Drops the OSC addresses of every layer, they mean nothing on a MIDI device. Returns whether
+    /// there were any.
+    pub fn remove_osc(&mut self) -> bool {
+        let mut removed = false;
+        match self {
+            Control::Continuous(control) => {
+                for layer in control.layers.values_mut() {
+                    removed |= layer.address.take().is_some() | layer.label.take().is_some();
+                }
+            }
+            Control::Key(control) => {
+                for layer in control.layers.values_mut() {
+                    removed |= layer.address.take().is_some() | layer.label.take().is_some();
+                }
+            }
+        }
+        removed
+    }
+
     pub fn layer(&self, layer: &str) -> Option<LayerControl> {
         match self {
             Control::Continuous(control) => control
@@ -268,3 +471,45 @@ impl Control {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn layer(relative: Option<&str>) -> ContinuousLayer {
+        serde_json::from_value(json!({
+            "channel": 1,
+            "control": 1,
+            "relative": relative,
+            "min": 10,
+            "max": 100
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn absolute_outside_range() {
+        let layer = layer(None);
+
+        assert_eq!(layer.state_from_message(50), 50);
+        assert_eq!(layer.state_from_message(3), 10);
+        assert_eq!(layer.state_from_message(120), 100);
+        assert_eq!(layer.value_from_state(layer.state_from_message(3)), 0.0);
+        assert_eq!(layer.value_from_state(layer.state_from_message(120)), 1.0);
+        assert_eq!(layer.value_from_state(3), 0.0);
+    }
+
+    #[test]
+    fn relative_stays_in_range() {
+        let layer = layer(Some("twosComplement"));
+        layer.set_value(12);
+
+        assert_eq!(layer.state_from_message(1), 13);
+        assert_eq!(layer.state_from_message(124), 10);
+
+        layer.set_value(99);
+        assert_eq!(layer.state_from_message(5), 100);
+    }
+}
@@ -1,124 +1,362 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     error::Error,
+    fmt,
     path::Path,
     sync::mpsc::Sender,
 };
 
 use midi_control::MidiMessage;
-use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection, MidiOutputPort};
-use serde::Deserialize;
+use schemars::{schema_for, JsonSchema, Schema};
+use serde::{Deserialize, Serialize};
 
-use crate::{utils::iter_json, ControlMessage};
+use crate::{
+    utils::{iter_config, Problems},
+    ControlMessage,
+};
+
+use super::{
+    identity::{probe, Identity},
+    library::{template, template_schema, templates_for_port},
+    osc::{self, OscArg, OscConfig, OscMessage},
+    port::{counterpart, PortSelector},
+    transport::{InputConnection, OutputConnection, Transport},
+};
 
-use super::controls::{Control, KeyState, LayerControl};
+use super::controls::{
+    ContinuousControl, ContinuousLayer, Control, KeyControl, KeyLayer, KeyState, LayerControl,
+};
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 pub struct DeviceConfig {
-    pub port: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "template_schema")]
+    pub template: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<PortSelector>,
+    #[serde(rename = "inputPort", default, skip_serializing_if = "Option::is_none")]
+    pub input_port: Option<PortSelector>,
+    #[serde(
+        rename = "outputPort",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub output_port: Option<PortSelector>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Identity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub osc: Option<OscConfig>,
+    #[serde(default)]
     pub controls: Vec<Control>,
 }
 
-pub struct Device {
-    connection: Option<MidiInputConnection<()>>,
-    pub port: String,
-    pub output: Option<MidiOutputConnection>,
-    pub controls: HashMap<String, Control>,
-}
+impl DeviceConfig {
+    pub fn schema() -> Schema {
+        schema_for!(DeviceConfig)
+    }
 
-fn input_port(
-    midi_input: MidiInput,
-    device_id: &str,
-    port: &str,
-    controls: &[Control],
-    sender: Sender<ControlMessage>,
-) -> Result<Option<MidiInputConnection<()>>, String> {
-    for input_port in midi_input.ports() {
-        let port_name = midi_input
-            .port_name(&input_port)
-            .map_err(|e| format!("Failed to get MIDI port name: {e}"))?;
-        if port == port_name {
-            let device_id = device_id.to_string();
-            let receiver_controls = controls.to_vec();
-            return Ok(Some(
-                midi_input
-                    .connect(
-                        &input_port,
-                        "MidiCtrl",
-                        move |_, buffer, _| {
-                            let message = MidiMessage::from(buffer);
-                            if let Err(e) = Device::handle_message(
-                                device_id.clone(),
-                                message,
-                                &sender,
-                                &receiver_controls,
-                            ) {
-                                log::error!("Failed handling MIDI message: {}", e);
-                            }
-                        },
-                        (),
-                    )
-                    .map_err(|e| format!("Failed to connect to MIDI device {port_name}: {e}"))?,
-            ));
+    /// Applies this configuration on top of its template if it has one. Controls with the same name
+    /// as a control in the template replace the template's layers of the same name.
+    pub fn resolve(self) -> Result<DeviceConfig, String> {
+        let name = match self.template {
+            Some(name) => name,
+            None => {
+                if self.input().is_none() && self.identity.is_none() && self.osc.is_none() {
+                    return Err(String::from(
+                        "Device has no port, identity, OSC settings or template",
+                    ));
+                }
+                return Ok(self);
+            }
+        };
+
+        let mut config = template(&name)?;
+        if self.port.is_some() {
+            config.port = self.port;
         }
+        if self.input_port.is_some() {
+            config.input_port = self.input_port;
+        }
+        if self.output_port.is_some() {
+            config.output_port = self.output_port;
+        }
+        if self.identity.is_some() {
+            config.identity = self.identity;
+        }
+        if self.osc.is_some() {
+            config.osc = self.osc;
+        }
+
+        for control in self.controls {
+            let existing = config
+                .controls
+                .iter_mut()
+                .find(|existing| existing.name() == control.name());
+
+            match (existing, control) {
+                (Some(Control::Continuous(existing)), Control::Continuous(control)) => {
+                    existing.layers.extend(control.layers);
+                }
+                (Some(Control::Key(existing)), Control::Key(control)) => {
+                    existing.display |= control.display;
+                    existing.layers.extend(control.layers);
+                }
+                (Some(existing), control) => *existing = control,
+                (None, control) => config.controls.push(control),
+            }
+        }
+
+        Ok(config)
     }
 
-    Ok(None)
-}
+    pub fn input(&self) -> Option<&PortSelector> {
+        self.input_port.as_ref().or(self.port.as_ref())
+    }
+
+    pub fn output(&self) -> Option<&PortSelector> {
+        self.output_port.as_ref().or(self.port.as_ref())
+    }
 
-fn output_port(midi_output: &MidiOutput, name: &str) -> Result<Option<MidiOutputPort>, String> {
-    for port in midi_output.ports() {
-        let port_name = midi_output
-            .port_name(&port)
-            .map_err(|e| format!("Failed to get MIDI port name: {e}"))?;
-        if name == port_name {
-            return Ok(Some(port));
+    pub fn validate(&self, id: &str, problems: &mut Problems) {
+        let mut names = HashSet::new();
+        let mut messages: HashMap<(&str, u8, u8), (&str, &str)> = HashMap::new();
+        let mut addresses: HashMap<&str, (&str, &str)> = HashMap::new();
+
+        for selector in [
+            self.port.as_ref(),
+            self.input_port.as_ref(),
+            self.output_port.as_ref(),
+        ]
+        .iter()
+        .flatten()
+        {
+            if let Err(e) = selector.validate() {
+                problems.error(format!("Device {}: {}", id, e));
+            }
+        }
+
+        if let Some(identity) = &self.identity {
+            if identity.manufacturer.len() != 1
+                && !(identity.manufacturer.len() == 3 && identity.manufacturer[0] == 0)
+            {
+                problems.error(format!(
+                    "Device {} has a manufacturer ID that is not a single byte or three bytes starting with 0",
+                    id
+                ));
+            }
+        }
+
+        for control in &self.controls {
+            if !names.insert(control.name()) {
+                problems.warning(format!(
+                    "Device {} has more than one control named {}",
+                    id,
+                    control.name()
+                ));
+            }
+
+            let layers: Vec<_> = match control {
+                Control::Continuous(continuous) => continuous
+                    .layers
+                    .iter()
+                    .map(|(layer, continuous_layer)| {
+                        if continuous_layer.min >= continuous_layer.max {
+                            problems.error(format!(
+                                "Control {} in layer {} on device {} has a min of {} which is not less than its max of {}",
+                                control.name(),
+                                layer,
+                                id,
+                                continuous_layer.min,
+                                continuous_layer.max
+                            ));
+                        }
+
+                        // The MIDI message the control sends, or what is missing to know it.
+                        let message = match (
+                            continuous_layer.channel,
+                            continuous_layer.control,
+                            continuous_layer.pitch_bend,
+                        ) {
+                            (None, _, _) => Err("channel"),
+                            (Some(channel), _, true) => Ok(("pitch bend", channel as u8, 0)),
+                            (Some(channel), Some(number), false) => {
+                                Ok(("cc", channel as u8, number))
+                            }
+                            (Some(_), None, false) => Err("control"),
+                        };
+
+                        (
+                            layer,
+                            continuous_layer.address.as_ref(),
+                            continuous_layer.label.is_some(),
+                            message,
+                        )
+                    })
+                    .collect(),
+                Control::Key(key) => key
+                    .layers
+                    .iter()
+                    .map(|(layer, key_layer)| {
+                        let message = match (key_layer.channel, key_layer.note) {
+                            (Some(channel), Some(note)) => Ok(("note", channel as u8, note)),
+                            (None, _) => Err("channel"),
+                            (_, None) => Err("note"),
+                        };
+
+                        (layer, key_layer.address.as_ref(), key_layer.label.is_some(), message)
+                    })
+                    .collect(),
+            };
+
+            for (layer, address, label, message) in layers {
+                if self.osc.is_none() && (address.is_some() || label) {
+                    problems.error(format!(
+                        "Control {} in layer {} on device {} has an OSC {} but the device has no OSC settings",
+                        control.name(),
+                        layer,
+                        id,
+                        if address.is_some() { "address" } else { "label" }
+                    ));
+                    continue;
+                }
+
+                if let Some(address) = address {
+                    if let Some((other_control, other_layer)) = addresses.get(address.as_str()) {
+                        problems.warning(format!(
+                            "Control {} in layer {} on device {} uses the same OSC address as control {} in layer {}",
+                            control.name(),
+                            layer,
+                            id,
+                            other_control,
+                            other_layer
+                        ));
+                    } else {
+                        addresses.insert(address, (control.name(), layer));
+                    }
+                    continue;
+                }
+
+                if self.osc.is_some() {
+                    problems.warning(format!(
+                        "Control {} in layer {} on device {} has no OSC address",
+                        control.name(),
+                        layer,
+                        id
+                    ));
+                    continue;
+                }
+
+                let message = match message {
+                    Ok(message) => message,
+                    Err(missing) => {
+                        problems.error(format!(
+                            "Control {} in layer {} on device {} has no {}",
+                            control.name(),
+                            layer,
+                            id,
+                            missing
+                        ));
+                        continue;
+                    }
+                };
+
+                if let Some((other_control, other_layer)) = messages.get(&message) {
+                    problems.warning(format!(
+                        "Control {} in layer {} on device {} uses the same MIDI message as control {} in layer {}",
+                        control.name(),
+                        layer,
+                        id,
+                        other_control,
+                        other_layer
+                    ));
+                } else {
+                    messages.insert(message, (control.name(), layer));
+                }
+            }
         }
     }
+}
 
-    Ok(None)
+pub struct Device {
+    connection: Option<Box<dyn InputConnection>>,
+    pub output: Option<Box<dyn OutputConnection>>,
+    pub controls: HashMap<String, Control>,
 }
 
 impl Device {
+    /// Connects to the device using the input and output ports, given by their position in the
+    /// list of ports and their name.
     pub fn new(
         id: String,
         sender: Sender<ControlMessage>,
         mut config: DeviceConfig,
+        transport: &dyn Transport,
+        input: Option<(usize, &str)>,
+        output: Option<(usize, &str)>,
     ) -> Result<Device, String> {
-        let midi_input =
-            MidiInput::new("MidiCtrl").map_err(|e| format!("Failed to open MIDI input: {e}"))?;
-        let midi_output =
-            MidiOutput::new("MidiCtrl").map_err(|e| format!("Failed to open MIDI output: {e}"))?;
+        let mut output = match output {
+            Some((position, name)) => match transport.connect_output(position, name) {
+                Ok(output) => output,
+                Err(e) => {
+                    log::warn!("{}", e);
+                    None
+                }
+            },
+            None => None,
+        };
 
-        let mut output = output_port(&midi_output, &config.port)?
-            .and_then(|port| midi_output.connect(&port, "MidiCtrl").ok());
+        for control in config.controls.iter_mut() {
+            if control.remove_osc() {
+                log::warn!(
+                    "Ignoring the OSC addresses of control {} on MIDI device {}",
+                    control.name(),
+                    id
+                );
+            }
+        }
 
         if let Some(ref mut output) = output {
             for control in config.controls.iter_mut() {
                 match control {
                     Control::Continuous(ref mut continuous) => {
                         for continuous_layer in continuous.layers.values_mut() {
-                            continuous_layer.update(output, continuous_layer.min, true);
+                            continuous_layer.update(output.as_mut(), continuous_layer.min, true);
                         }
                     }
                     Control::Key(ref mut key) => {
                         for key_layer in key.layers.values_mut() {
-                            key_layer.update(output, KeyState::Off, true);
+                            key_layer.update(output.as_mut(), KeyState::Off, true);
                         }
                     }
                 }
             }
         }
 
-        let connection: Option<MidiInputConnection<()>> =
-            input_port(midi_input, &id, &config.port, &config.controls, sender)?;
+        let connection = match input {
+            Some((position, name)) => {
+                let device_id = id.clone();
+                let controls = config.controls.clone();
+                transport.connect_input(
+                    position,
+                    name,
+                    Box::new(move |buffer| {
+                        let message = MidiMessage::from(buffer);
+                        if let Err(e) =
+                            Device::handle_message(device_id.clone(), message, &sender, &controls)
+                        {
+                            log::error!("Failed handling MIDI message: {}", e);
+                        }
+                    }),
+                )?
+            }
+            None => None,
+        };
         if connection.is_some() {
             log::debug!("Connected to MIDI device {}", id);
         }
 
         Ok(Device {
             connection,
-            port: config.port.clone(),
             output,
             controls: config
                 .controls
@@ -128,6 +366,72 @@ impl Device {
         })
     }
 
+    /// Opens the sockets for a device that is controlled over OSC.
+    pub fn osc(
+        id: String,
+        sender: Sender<ControlMessage>,
+        config: DeviceConfig,
+        osc_config: &OscConfig,
+    ) -> Result<Device, String> {
+        let device_id = id.clone();
+        let controls = config.controls.clone();
+        let (connection, output) = osc::connect(
+            osc_config,
+            Box::new(move |message| {
+                if let Err(e) = Device::handle_osc(device_id.clone(), message, &sender, &controls) {
+                    log::error!("Failed handling OSC message: {}", e);
+                }
+            }),
+        )?;
+        if connection.is_some() {
+            log::debug!("Listening for OSC messages for device {}", id);
+        }
+
+        let mut device = Device {
+            connection,
+            output,
+            controls: config
+                .controls
+                .into_iter()
+                .map(|control| (String::from(control.name()), control))
+                .collect(),
+        };
+        device.clear();
+
+        Ok(device)
+    }
+
+    /// Creates a device from its configuration without connecting to any MIDI ports.
+    pub fn offline(config: DeviceConfig) -> Device {
+        Device {
+            connection: None,
+            output: None,
+            controls: config
+                .controls
+                .into_iter()
+                .map(|control| (String::from(control.name()), control))
+                .collect(),
+        }
+    }
+
+    /// Turns off every control on the device.
+    pub fn clear(&mut self) {
+        if let Some(ref mut output) = self.output {
+            for control in self.controls.values() {
+                for (_, layer_control) in control.layers() {
+                    match layer_control {
+                        LayerControl::Continuous(layer_control) => {
+                            layer_control.update(output.as_mut(), layer_control.min, true)
+                        }
+                        LayerControl::Key(layer_control) => {
+                            layer_control.update(output.as_mut(), KeyState::Off, true)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     pub fn is_connected(&self) -> bool {
         self.connection.is_some()
     }
@@ -138,116 +442,398 @@ impl Device {
         sender: &Sender<ControlMessage>,
         controls: &'a [Control],
     ) -> Result<(), Box<dyn Error + 'a>> {
-        match &message {
-            MidiMessage::ControlChange(channel, event) => {
-                for control in controls {
-                    if let Control::Continuous(continuous) = control {
-                        for (layer, continuous_layer) in &continuous.layers {
-                            if continuous_layer.channel == *channel
-                                && continuous_layer.control == event.control
-                            {
-                                continuous_layer.set_value(event.value);
-                                sender.send(ControlMessage::ContinuousChange {
-                                    device_id,
-                                    control: continuous.name.clone(),
-                                    layer: String::from(layer),
-                                    value: continuous_layer.value_from_state(event.value),
-                                })?;
-                                return Ok(());
-                            }
-                        }
-                    }
+        match resolve_message(&message, controls) {
+            Some(resolved) => Device::send_change(device_id, resolved, sender),
+            None => {
+                log::debug!(
+                    "Saw unmapped MIDI message from {}: {:?}",
+                    device_id,
+                    message
+                );
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_osc<'a>(
+        device_id: String,
+        message: OscMessage,
+        sender: &Sender<ControlMessage>,
+        controls: &'a [Control],
+    ) -> Result<(), Box<dyn Error + 'a>> {
+        match resolve_osc(&message, controls) {
+            Some(resolved) => Device::send_change(device_id, resolved, sender),
+            None => {
+                log::debug!(
+                    "Saw unmapped OSC message from {}: {} {:?}",
+                    device_id,
+                    message.address,
+                    message.args
+                );
+                Ok(())
+            }
+        }
+    }
+
+    fn send_change<'a>(
+        device_id: String,
+        resolved: Resolved<'a>,
+        sender: &Sender<ControlMessage>,
+    ) -> Result<(), Box<dyn Error + 'a>> {
+        match resolved {
+            Resolved::Continuous {
+                control,
+                layer,
+                layer_control,
+                state,
+            } => {
+                layer_control.set_value(state);
+                sender.send(ControlMessage::ContinuousChange {
+                    device_id,
+                    control: control.name.clone(),
+                    layer: String::from(layer),
+                    value: layer_control.value_from_state(state),
+                })?;
+            }
+            Resolved::Key {
+                control,
+                layer,
+                layer_control,
+                state,
+            } => {
+                layer_control.set_value(state);
+                sender.send(ControlMessage::KeyChange {
+                    device_id,
+                    control: control.name.clone(),
+                    layer: String::from(layer),
+                    state,
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The control and layer that a MIDI or OSC message is for.
+pub enum Resolved<'a> {
+    Continuous {
+        control: &'a ContinuousControl,
+        layer: &'a str,
+        layer_control: &'a ContinuousLayer,
+        state: u8,
+    },
+    Key {
+        control: &'a KeyControl,
+        layer: &'a str,
+        layer_control: &'a KeyLayer,
+        state: KeyState,
+    },
+}
+
+impl fmt::Display for Resolved<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resolved::Continuous {
+                control,
+                layer,
+                layer_control,
+                state,
+            } => write!(
+                f,
+                "{} in layer {} = {:.3}",
+                control.name,
+                layer,
+                layer_control.value_from_state(*state)
+            ),
+            Resolved::Key {
+                control,
+                layer,
+                state,
+                ..
+            } => write!(f, "{} in layer {} = {}", control.name, layer, state),
+        }
+    }
+}
+
+fn resolve_continuous<'a, F>(controls: &'a [Control], value: u8, matches: F) -> Option<Resolved<'a>>
+where
+    F: Fn(&ContinuousLayer) -> bool,
+{
+    for control in controls {
+        if let Control::Continuous(continuous) = control {
+            for (layer, continuous_layer) in &continuous.layers {
+                if matches(continuous_layer) {
+                    return Some(Resolved::Continuous {
+                        control: continuous,
+                        layer,
+                        layer_control: continuous_layer,
+                        state: continuous_layer.state_from_message(value),
+                    });
                 }
             }
-            MidiMessage::NoteOn(channel, event) => {
-                for control in controls {
-                    if let Control::Key(key) = control {
-                        for (layer, key_layer) in &key.layers {
-                            if key_layer.channel == *channel && key_layer.note == event.key {
-                                key_layer.set_value(KeyState::On);
-
-                                sender.send(ControlMessage::KeyChange {
-                                    device_id,
-                                    control: key.name.clone(),
-                                    layer: String::from(layer),
-                                    state: KeyState::On,
-                                })?;
-                                return Ok(());
-                            }
-                        }
+        }
+    }
+
+    None
+}
+
+pub fn resolve_message<'a>(message: &MidiMessage, controls: &'a [Control]) -> Option<Resolved<'a>> {
+    let (channel, note, state) = match message {
+        MidiMessage::ControlChange(channel, event) => {
+            return resolve_continuous(controls, event.value, |layer| {
+                !layer.pitch_bend
+                    && layer.channel == Some(*channel)
+                    && layer.control == Some(event.control)
+            });
+        }
+        MidiMessage::PitchBend(channel, _, msb) => {
+            return resolve_continuous(controls, *msb, |layer| {
+                layer.pitch_bend && layer.channel == Some(*channel)
+            });
+        }
+        MidiMessage::NoteOn(channel, event) => (channel, event.key, KeyState::On),
+        MidiMessage::NoteOff(channel, event) => (channel, event.key, KeyState::Off),
+        _ => return None,
+    };
+
+    for control in controls {
+        if let Control::Key(key) = control {
+            for (layer, key_layer) in &key.layers {
+                if key_layer.channel == Some(*channel) && key_layer.note == Some(note) {
+                    return Some(Resolved::Key {
+                        control: key,
+                        layer,
+                        layer_control: key_layer,
+                        state,
+                    });
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Finds the control and layer for an OSC message from its address. Keys are pressed by any
+/// positive value or a message without arguments.
+pub fn resolve_osc<'a>(message: &OscMessage, controls: &'a [Control]) -> Option<Resolved<'a>> {
+    let value = message.args.first().and_then(OscArg::as_f64);
+
+    for control in controls {
+        match control {
+            Control::Continuous(continuous) => {
+                for (layer, continuous_layer) in &continuous.layers {
+                    if continuous_layer.address.as_ref() == Some(&message.address) {
+                        return Some(Resolved::Continuous {
+                            control: continuous,
+                            layer,
+                            layer_control: continuous_layer,
+                            state: continuous_layer.state_from_value(value?),
+                        });
                     }
                 }
             }
-            MidiMessage::NoteOff(channel, event) => {
-                for control in controls {
-                    if let Control::Key(key) = control {
-                        for (layer, key_layer) in &key.layers {
-                            if key_layer.channel == *channel && key_layer.note == event.key {
-                                key_layer.set_value(KeyState::Off);
-
-                                sender.send(ControlMessage::KeyChange {
-                                    device_id,
-                                    control: key.name.clone(),
-                                    layer: String::from(layer),
-                                    state: KeyState::Off,
-                                })?;
-                                return Ok(());
-                            }
-                        }
+            Control::Key(key) => {
+                for (layer, key_layer) in &key.layers {
+                    if key_layer.address.as_ref() == Some(&message.address) {
+                        return Some(Resolved::Key {
+                            control: key,
+                            layer,
+                            layer_control: key_layer,
+                            state: (value.unwrap_or(1.0) > 0.0).into(),
+                        });
                     }
                 }
             }
-            _ => (),
         }
-
-        log::debug!(
-            "Saw unmapped MIDI message from {}: {:?}",
-            device_id,
-            message
-        );
-
-        Ok(())
     }
+
+    None
 }
 
-pub fn devices(sender: Sender<ControlMessage>, root: &Path) -> HashMap<String, Device> {
-    let mut devices = HashMap::new();
+pub fn read_devices(root: &Path, problems: &mut Problems) -> BTreeMap<String, DeviceConfig> {
+    let mut configs = BTreeMap::new();
 
     let dir = root.join("devices");
-    let entries = match iter_json::<DeviceConfig>(&dir) {
+    let entries = match iter_config::<DeviceConfig>(&dir) {
         Ok(entries) => entries,
         Err(e) => {
-            log::error!("Failed to read devices directory: {}", e);
-            return devices;
+            problems.error(format!("Failed to read devices directory: {}", e));
+            return configs;
         }
     };
 
-    let mut ports = HashSet::new();
     for entry in entries {
         match entry {
-            Ok((id, config)) => match Device::new(id.clone(), sender.clone(), config) {
-                Ok(device) => {
-                    ports.insert(device.port.clone());
-                    devices.insert(id, device);
+            Ok((id, path, config)) => match config.resolve() {
+                Ok(config) => {
+                    config.validate(&id, problems);
+                    configs.insert(id, config);
                 }
-                Err(e) => log::error!("Failed to connect to device: {}", e),
+                Err(e) => problems.error(format!(
+                    "Failed to load {}: {}",
+                    path.file_name().unwrap_or_default().to_string_lossy(),
+                    e
+                )),
             },
-            Err(e) => log::error!("Failed to parse device config: {}", e),
+            Err(e) => problems.error(e),
         }
     }
 
-    if let Ok(midi_input) =
-        MidiInput::new("MidiCtrl").map_err(|e| format!("Failed to open MIDI input: {e}"))
+    configs
+}
+
+pub fn devices(
+    sender: Sender<ControlMessage>,
+    root: &Path,
+    transport: &dyn Transport,
+) -> HashMap<String, Device> {
+    let mut devices = HashMap::new();
+
+    let mut problems = Problems::default();
+    let configs = read_devices(root, &mut problems);
+    problems.log();
+
+    let inputs = transport.inputs().unwrap_or_else(|e| {
+        log::error!("{}", e);
+        Vec::new()
+    });
+    let outputs = transport.outputs().unwrap_or_else(|e| {
+        log::error!("{}", e);
+        Vec::new()
+    });
+
+    // Each port can only be used by one device. Devices that ask for a specific port out of
+    // several that match go first, devices with an identity can then claim any ports left over.
+    let mut claimed_inputs = HashSet::new();
+    let mut claimed_outputs = HashSet::new();
+
+    let mut order: Vec<&String> = configs.keys().collect();
+    order.sort_by_key(|id| {
+        let config = &configs[*id];
+        !(config.input().is_some_and(PortSelector::is_indexed)
+            || config.output().is_some_and(PortSelector::is_indexed))
+    });
+
+    let mut selected: HashMap<&str, (Option<usize>, Option<usize>)> = HashMap::new();
+    for id in order {
+        let config = &configs[id];
+
+        let input = config
+            .input()
+            .and_then(|selector| selector.select(&inputs, &claimed_inputs));
+        if let Some(position) = input {
+            claimed_inputs.insert(position);
+        }
+
+        let output = config
+            .output()
+            .and_then(|selector| selector.select(&outputs, &claimed_outputs));
+        if let Some(position) = output {
+            claimed_outputs.insert(position);
+        }
+
+        selected.insert(id, (input, output));
+    }
+
+    let mut identities = Vec::new();
+    if configs
+        .iter()
+        .any(|(id, config)| config.identity.is_some() && selected[id.as_str()].0.is_none())
     {
-        for input_port in midi_input.ports() {
-            if let Ok(port_name) = midi_input
-                .port_name(&input_port)
-                .map_err(|e| format!("Failed to get MIDI port name: {e}"))
+        // Ports that a device names are left alone even if another device claimed them first.
+        let unclaimed: Vec<usize> = (0..inputs.len())
+            .filter(|position| {
+                !claimed_inputs.contains(position)
+                    && !configs.values().any(|config| {
+                        config
+                            .input()
+                            .is_some_and(|selector| selector.matches(&inputs[*position]))
+                    })
+            })
+            .collect();
+        identities = probe(transport, &inputs, &outputs, &unclaimed);
+
+        for (id, config) in &configs {
+            let (input, output) = selected.get_mut(id.as_str()).unwrap();
+            let identity = match &config.identity {
+                Some(identity) if input.is_none() => identity,
+                _ => continue,
+            };
+
+            if let Some((position, _)) = identities
+                .iter()
+                .find(|(position, found)| found == identity && !claimed_inputs.contains(position))
             {
-                if !ports.contains(&port_name) {
-                    log::info!("Found unused MIDI port: {}", port_name);
+                log::info!(
+                    "Identified MIDI port {} as device {}",
+                    inputs[*position],
+                    id
+                );
+                claimed_inputs.insert(*position);
+                *input = Some(*position);
+
+                if output.is_none() {
+                    *output = counterpart(&inputs, *position, &outputs)
+                        .filter(|position| !claimed_outputs.contains(position));
+                    if let Some(position) = output {
+                        claimed_outputs.insert(*position);
+                    }
+                }
+            }
+        }
+    }
+
+    for (id, config) in &configs {
+        if let Some(osc_config) = &config.osc {
+            match Device::osc(id.clone(), sender.clone(), config.clone(), osc_config) {
+                Ok(device) => {
+                    devices.insert(id.clone(), device);
                 }
+                Err(e) => log::error!("Failed to connect to device: {}", e),
+            }
+            continue;
+        }
+
+        let (input, output) = selected[id.as_str()];
+        if input.is_none() && output.is_none() {
+            log::debug!("No MIDI ports found for device {}", id);
+        }
+
+        match Device::new(
+            id.clone(),
+            sender.clone(),
+            config.clone(),
+            transport,
+            input.map(|position| (position, inputs[position].as_str())),
+            output.map(|position| (position, outputs[position].as_str())),
+        ) {
+            Ok(device) => {
+                devices.insert(id.clone(), device);
             }
+            Err(e) => log::error!("Failed to connect to device: {}", e),
+        }
+    }
+
+    for (position, port) in inputs.iter().enumerate() {
+        if claimed_inputs.contains(&position) {
+            continue;
+        }
+
+        let identity = identities
+            .iter()
+            .find(|(other, _)| *other == position)
+            .map(|(_, identity)| identity);
+        let templates = templates_for_port(port, identity);
+        if templates.is_empty() {
+            log::info!("Found unused MIDI port: {}", port);
+        } else {
+            log::info!(
+                "Found unused MIDI port: {}, it matches template {}",
+                port,
+                templates.join(", ")
+            );
         }
     }
 
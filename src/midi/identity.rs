@@ -0,0 +1,127 @@
+use std::{
+    sync::mpsc::channel,
+    time::{Duration, Instant},
+};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{port::counterpart, transport::Transport};
+
+/// The Universal SysEx Device Inquiry message.
+const INQUIRY: [u8; 6] = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
+
+/// How long to wait for devices to reply to the inquiry.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The identity a device reports in reply to a Device Inquiry.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Identity {
+    /// Either a single byte or three bytes starting with 0 for extended IDs.
+    pub manufacturer: Vec<u8>,
+    pub family: u16,
+    pub model: u16,
+}
+
+impl Identity {
+    pub fn from_reply(buffer: &[u8]) -> Option<Identity> {
+        let body = match buffer {
+            [0xF0, 0x7E, _, 0x06, 0x02, body @ ..] => body,
+            _ => return None,
+        };
+
+        let (manufacturer, rest) = match body {
+            [0, rest @ ..] if rest.len() >= 2 => body.split_at(3),
+            [_, ..] => body.split_at(1),
+            [] => return None,
+        };
+
+        match rest {
+            [family_lsb, family_msb, model_lsb, model_msb, ..] => Some(Identity {
+                manufacturer: manufacturer.to_vec(),
+                family: *family_lsb as u16 | (*family_msb as u16) << 7,
+                model: *model_lsb as u16 | (*model_msb as u16) << 7,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Sends a Device Inquiry to each of the input ports at the positions given and returns the
+/// identities of those that reply.
+pub fn probe(
+    transport: &dyn Transport,
+    inputs: &[String],
+    outputs: &[String],
+    positions: &[usize],
+) -> Vec<(usize, Identity)> {
+    let (sender, replies) = channel();
+    let mut connections = Vec::new();
+
+    for &position in positions {
+        let port = match inputs.get(position) {
+            Some(port) => port,
+            None => continue,
+        };
+
+        let output_position = match counterpart(inputs, position, outputs) {
+            Some(output_position) => output_position,
+            None => continue,
+        };
+
+        let mut output = match transport.connect_output(output_position, port) {
+            Ok(Some(output)) => output,
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("{}", e);
+                continue;
+            }
+        };
+
+        let sender = sender.clone();
+        let connection = match transport.connect_input(
+            position,
+            port,
+            Box::new(move |buffer| {
+                if let Some(identity) = Identity::from_reply(buffer) {
+                    let _ = sender.send((position, identity));
+                }
+            }),
+        ) {
+            Ok(Some(connection)) => connection,
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("{}", e);
+                continue;
+            }
+        };
+
+        if let Err(e) = output.send(&INQUIRY) {
+            log::warn!("Failed to send device inquiry to {}: {}", port, e);
+            continue;
+        }
+
+        connections.push(connection);
+    }
+
+    let mut found: Vec<(usize, Identity)> = Vec::new();
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    while found.len() < connections.len() {
+        let remaining = match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) => remaining,
+            None => break,
+        };
+
+        match replies.recv_timeout(remaining) {
+            Ok((position, identity)) => {
+                if !found.iter().any(|(other, _)| *other == position) {
+                    log::debug!("{} identified as {:?}", inputs[position], identity);
+                    found.push((position, identity));
+                }
+            }
+            Err(_) => break,
+        }
+    }
+
+    found
+}
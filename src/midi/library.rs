@@ -0,0 +1,83 @@
+use schemars::{json_schema, Schema, SchemaGenerator};
+
+use crate::diagnostics::{parse, suggest, Format};
+
+use super::{device::DeviceConfig, identity::Identity};
+
+const TEMPLATES: &[(&str, &str)] = &[
+    (
+        "arturia/beatstep",
+        include_str!("library/arturia/beatstep.json"),
+    ),
+    (
+        "behringer/bcr2000",
+        include_str!("library/behringer/bcr2000.json"),
+    ),
+    (
+        "behringer/x-touch-mini",
+        include_str!("library/behringer/x-touch-mini.json"),
+    ),
+    (
+        "behringer/x-touch-one",
+        include_str!("library/behringer/x-touch-one.json"),
+    ),
+    (
+        "djtechtools/midi-fighter-twister",
+        include_str!("library/djtechtools/midi-fighter-twister.json"),
+    ),
+    (
+        "korg/nanokontrol2",
+        include_str!("library/korg/nanokontrol2.json"),
+    ),
+    (
+        "loupedeck/midi",
+        include_str!("library/loupedeck/midi.json"),
+    ),
+    (
+        "novation/launch-control-xl",
+        include_str!("library/novation/launch-control-xl.json"),
+    ),
+];
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    TEMPLATES.iter().map(|(name, _)| *name)
+}
+
+/// Loads one of the built-in device definitions.
+pub fn template(name: &str) -> Result<DeviceConfig, String> {
+    let (_, text) = TEMPLATES
+        .iter()
+        .find(|(template, _)| *template == name)
+        .ok_or_else(|| match suggest(name, names()) {
+            Some(suggestion) => {
+                format!("Unknown device template {name} (closest match: {suggestion})")
+            }
+            None => format!("Unknown device template {name}"),
+        })?;
+
+    parse::<DeviceConfig>(text, Format::Json)
+        .map_err(|e| format!("Failed to parse device template {name} {e}"))
+}
+
+/// Finds the built-in device definitions for a MIDI port by its name or the identity it replied
+/// with.
+pub fn templates_for_port(port: &str, identity: Option<&Identity>) -> Vec<&'static str> {
+    names()
+        .filter(|name| {
+            template(name).is_ok_and(|config| {
+                config
+                    .input()
+                    .is_some_and(|selector| selector.matches(port))
+                    || (identity.is_some() && config.identity.as_ref() == identity)
+            })
+        })
+        .collect()
+}
+
+pub fn template_schema(_: &mut SchemaGenerator) -> Schema {
+    let names: Vec<&str> = names().collect();
+    json_schema!({
+        "type": "string",
+        "enum": names
+    })
+}
@@ -1,2 +1,7 @@
 pub mod controls;
 pub mod device;
+pub mod identity;
+pub mod library;
+pub mod osc;
+pub mod port;
+pub mod transport;
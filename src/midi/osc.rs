@@ -0,0 +1,317 @@
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::transport::{InputConnection, OutputConnection};
+
+/// How long the receiving thread waits for a packet before checking whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where to exchange OSC messages with a device such as TouchOSC or Open Stage Control.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+pub struct OscConfig {
+    /// The local address to receive messages on, e.g. `0.0.0.0:8000`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen: Option<String>,
+    /// The address of the device to send feedback to, e.g. `192.168.1.20:9000`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Double(f64),
+    String(String),
+    Bool(bool),
+    Nil,
+}
+
+impl OscArg {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            OscArg::Int(value) => Some(*value as f64),
+            OscArg::Float(value) => Some(*value as f64),
+            OscArg::Double(value) => Some(*value),
+            OscArg::Bool(true) => Some(1.0),
+            OscArg::Bool(false) => Some(0.0),
+            OscArg::String(_) | OscArg::Nil => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+fn pad(data: &mut Vec<u8>) {
+    while !data.len().is_multiple_of(4) {
+        data.push(0);
+    }
+}
+
+fn write_string(data: &mut Vec<u8>, string: &str) {
+    data.extend_from_slice(string.as_bytes());
+    data.push(0);
+    pad(data);
+}
+
+impl OscMessage {
+    pub fn new(address: &str, args: Vec<OscArg>) -> OscMessage {
+        OscMessage {
+            address: String::from(address),
+            args,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_string(&mut data, &self.address);
+
+        let mut tags = String::from(",");
+        for arg in &self.args {
+            tags.push(match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::Double(_) => 'd',
+                OscArg::String(_) => 's',
+                OscArg::Bool(true) => 'T',
+                OscArg::Bool(false) => 'F',
+                OscArg::Nil => 'N',
+            });
+        }
+        write_string(&mut data, &tags);
+
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(value) => data.extend_from_slice(&value.to_be_bytes()),
+                OscArg::Float(value) => data.extend_from_slice(&value.to_be_bytes()),
+                OscArg::Double(value) => data.extend_from_slice(&value.to_be_bytes()),
+                OscArg::String(value) => write_string(&mut data, value),
+                OscArg::Bool(_) | OscArg::Nil => (),
+            }
+        }
+
+        data
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self.position + length;
+        if end > self.data.len() {
+            return Err(String::from("OSC packet is truncated"));
+        }
+
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let remaining = &self.data[self.position..];
+        let length = remaining
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or_else(|| String::from("OSC string is not terminated"))?;
+        let string = String::from_utf8_lossy(&remaining[..length]).into_owned();
+
+        // The terminator is followed by padding to a multiple of four bytes.
+        self.take((length + 4) & !3)?;
+        Ok(string)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+}
+
+fn decode_message(data: &[u8]) -> Result<OscMessage, String> {
+    let mut reader = Reader { data, position: 0 };
+    let address = reader.string()?;
+
+    // Very old senders omit the type tags entirely.
+    if reader.is_empty() {
+        return Ok(OscMessage::new(&address, Vec::new()));
+    }
+
+    let tags = reader.string()?;
+    let tags = tags
+        .strip_prefix(',')
+        .ok_or_else(|| format!("OSC message for {address} has no type tags"))?;
+
+    let mut args = Vec::new();
+    for tag in tags.chars() {
+        args.push(match tag {
+            'i' => OscArg::Int(i32::from_be_bytes(reader.take_array()?)),
+            'f' => OscArg::Float(f32::from_be_bytes(reader.take_array()?)),
+            'd' => OscArg::Double(f64::from_be_bytes(reader.take_array()?)),
+            'h' => OscArg::Double(i64::from_be_bytes(reader.take_array()?) as f64),
+            's' | 'S' => OscArg::String(reader.string()?),
+            'T' => OscArg::Bool(true),
+            'F' => OscArg::Bool(false),
+            'N' | 'I' => OscArg::Nil,
+            _ => {
+                return Err(format!(
+                    "OSC message for {address} has an unsupported argument type {tag}"
+                ))
+            }
+        });
+    }
+
+    Ok(OscMessage { address, args })
+}
+
+/// Decodes a packet, which is either a single message or a bundle of them.
+pub fn decode(data: &[u8]) -> Result<Vec<OscMessage>, String> {
+    if !data.starts_with(b"#bundle\0") {
+        return Ok(vec![decode_message(data)?]);
+    }
+
+    let mut reader = Reader { data, position: 16 };
+    let mut messages = Vec::new();
+    while !reader.is_empty() {
+        let length = u32::from_be_bytes(reader.take_array()?) as usize;
+        messages.extend(decode(reader.take(length)?)?);
+    }
+
+    Ok(messages)
+}
+
+fn resolve_address(address: &str) -> Result<SocketAddr, String> {
+    address
+        .to_socket_addrs()
+        .map_err(|e| format!("Invalid OSC address {address}: {e}"))?
+        .next()
+        .ok_or_else(|| format!("Invalid OSC address {address}"))
+}
+
+/// Receives messages on a separate thread. Dropping it stops the thread.
+struct OscInput {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl InputConnection for OscInput {}
+
+impl Drop for OscInput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct OscOutput {
+    socket: UdpSocket,
+    target: SocketAddr,
+}
+
+impl OutputConnection for OscOutput {
+    fn send(&mut self, data: &[u8]) -> Result<(), String> {
+        self.socket
+            .send_to(data, self.target)
+            .map(|_| ())
+            .map_err(|e| format!("Failed to send OSC message to {}: {}", self.target, e))
+    }
+}
+
+pub type OscCallback = Box<dyn FnMut(OscMessage) + Send>;
+
+type Connections = (
+    Option<Box<dyn InputConnection>>,
+    Option<Box<dyn OutputConnection>>,
+);
+
+/// Opens the sockets for an OSC device, calling the callback with every message received.
+pub fn connect(config: &OscConfig, mut callback: OscCallback) -> Result<Connections, String> {
+    let listener = match &config.listen {
+        Some(address) => {
+            let socket = UdpSocket::bind(resolve_address(address)?)
+                .map_err(|e| format!("Failed to listen for OSC on {address}: {e}"))?;
+            socket
+                .set_read_timeout(Some(POLL_INTERVAL))
+                .map_err(|e| format!("Failed to configure OSC socket: {e}"))?;
+            Some(socket)
+        }
+        None => None,
+    };
+
+    let output: Option<Box<dyn OutputConnection>> = match &config.send {
+        Some(address) => {
+            let target = resolve_address(address)?;
+            // Sending from the listening socket lets devices that reply to the sender find us.
+            let socket = match &listener {
+                Some(socket) => socket.try_clone(),
+                None if target.is_ipv4() => UdpSocket::bind("0.0.0.0:0"),
+                None => UdpSocket::bind("[::]:0"),
+            }
+            .map_err(|e| format!("Failed to open OSC socket: {e}"))?;
+            Some(Box::new(OscOutput { socket, target }))
+        }
+        None => None,
+    };
+
+    let input: Option<Box<dyn InputConnection>> = match listener {
+        Some(socket) => {
+            let running = Arc::new(AtomicBool::new(true));
+            let thread_running = running.clone();
+
+            let thread = thread::spawn(move || {
+                let mut buffer = [0; 65536];
+                while thread_running.load(Ordering::Relaxed) {
+                    let length = match socket.recv(&mut buffer) {
+                        Ok(length) => length,
+                        Err(e)
+                            if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                        {
+                            continue
+                        }
+                        Err(e) => {
+                            log::error!("Failed to receive OSC message: {}", e);
+                            continue;
+                        }
+                    };
+
+                    match decode(&buffer[..length]) {
+                        Ok(messages) => messages.into_iter().for_each(&mut callback),
+                        Err(e) => log::warn!("{}", e),
+                    }
+                }
+            });
+
+            Some(Box::new(OscInput {
+                running,
+                thread: Some(thread),
+            }))
+        }
+        None => None,
+    };
+
+    Ok((input, output))
+}
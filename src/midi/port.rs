@@ -0,0 +1,190 @@
+use std::{collections::HashSet, fmt};
+
+use midir::MidiIO;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Selects a MIDI port either by its exact name or by a pattern.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum PortSelector {
+    #[schemars(title = "name")]
+    Name(String),
+    #[schemars(title = "pattern")]
+    Pattern(PortPattern),
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PortPattern {
+    /// The exact name of the port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Text that appears somewhere in the name of the port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contains: Option<String>,
+    /// A regular expression that matches the name of the port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    /// The ALSA client:port address of the port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Which of the matching ports to use, starting from 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = 1))]
+    pub index: Option<usize>,
+}
+
+impl PortPattern {
+    fn matches(&self, port: &str) -> bool {
+        if self.name.as_ref().is_some_and(|name| name != port) {
+            return false;
+        }
+
+        if self
+            .contains
+            .as_ref()
+            .is_some_and(|contains| !port.contains(contains.as_str()))
+        {
+            return false;
+        }
+
+        if let Some(regex) = &self.regex {
+            match Regex::new(regex) {
+                Ok(regex) if regex.is_match(port) => (),
+                _ => return false,
+            }
+        }
+
+        if let Some(address) = &self.address {
+            if port.rsplit(' ').next() != Some(address.as_str()) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl PortSelector {
+    pub fn matches(&self, port: &str) -> bool {
+        match self {
+            PortSelector::Name(name) => name == port,
+            PortSelector::Pattern(pattern) => pattern.matches(port),
+        }
+    }
+
+    /// Whether this selects a specific port out of several that may match.
+    pub fn is_indexed(&self) -> bool {
+        matches!(
+            self,
+            PortSelector::Pattern(PortPattern { index: Some(_), .. })
+        )
+    }
+
+    /// Picks the port to use from the available ports, skipping any already claimed by another
+    /// device. Returns the position of the port in the list.
+    pub fn select(&self, ports: &[String], claimed: &HashSet<usize>) -> Option<usize> {
+        let mut candidates = ports
+            .iter()
+            .enumerate()
+            .filter(|(_, port)| self.matches(port))
+            .map(|(position, _)| position);
+
+        match self {
+            PortSelector::Pattern(PortPattern {
+                index: Some(index), ..
+            }) => candidates
+                .nth(index.checked_sub(1)?)
+                .filter(|position| !claimed.contains(position)),
+            _ => candidates.find(|position| !claimed.contains(position)),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let PortSelector::Pattern(pattern) = self {
+            if let Some(regex) = &pattern.regex {
+                Regex::new(regex).map_err(|e| format!("Invalid port regex {regex}: {e}"))?;
+            }
+
+            if pattern.index == Some(0) {
+                return Err(String::from("Port indexes start from 1"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for PortSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pattern = match self {
+            PortSelector::Name(name) => return write!(f, "{name}"),
+            PortSelector::Pattern(pattern) => pattern,
+        };
+
+        let mut parts = Vec::new();
+        if let Some(name) = &pattern.name {
+            parts.push(format!("named {name}"));
+        }
+        if let Some(contains) = &pattern.contains {
+            parts.push(format!("containing {contains}"));
+        }
+        if let Some(regex) = &pattern.regex {
+            parts.push(format!("matching {regex}"));
+        }
+        if let Some(address) = &pattern.address {
+            parts.push(format!("at {address}"));
+        }
+        if let Some(index) = &pattern.index {
+            parts.push(format!("number {index}"));
+        }
+
+        if parts.is_empty() {
+            write!(f, "any port")
+        } else {
+            write!(f, "port {}", parts.join(", "))
+        }
+    }
+}
+
+/// Lists the names of the ports, ports whose name cannot be read are given an empty name so the
+/// positions still line up with `MidiIO::ports`.
+pub fn port_names<T: MidiIO>(io: &T) -> Vec<String> {
+    io.ports()
+        .iter()
+        .map(|port| io.port_name(port).unwrap_or_default())
+        .collect()
+}
+
+/// Finds the port at the position, or the first port with the name if the ports have changed.
+pub fn find_port<T: MidiIO>(io: &T, position: usize, name: &str) -> Option<T::Port> {
+    let ports = io.ports();
+    if let Some(port) = ports.get(position) {
+        if io.port_name(port).is_ok_and(|port_name| port_name == name) {
+            return Some(port.clone());
+        }
+    }
+
+    ports
+        .into_iter()
+        .find(|port| io.port_name(port).is_ok_and(|port_name| port_name == name))
+}
+
+/// Finds the output port that goes with an input port. When several ports have the same name
+/// they are paired up in order.
+pub fn counterpart(inputs: &[String], position: usize, outputs: &[String]) -> Option<usize> {
+    let name = inputs.get(position)?;
+    let rank = inputs[..position]
+        .iter()
+        .filter(|other| *other == name)
+        .count();
+
+    outputs
+        .iter()
+        .enumerate()
+        .filter(|(_, other)| *other == name)
+        .map(|(position, _)| position)
+        .nth(rank)
+}
@@ -0,0 +1,284 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+
+use super::port::{find_port, port_names};
+
+pub type InputCallback = Box<dyn FnMut(&[u8]) + Send>;
+
+/// A connection receiving messages from an input port. Dropping it disconnects.
+pub trait InputConnection {}
+
+/// A connection sending messages to an output port.
+pub trait OutputConnection {
+    fn send(&mut self, data: &[u8]) -> Result<(), String>;
+}
+
+/// Somewhere that MIDI ports come from. Ports are identified by their position in the list of
+/// ports along with their name in case the ports have changed since they were listed.
+pub trait Transport {
+    fn inputs(&self) -> Result<Vec<String>, String>;
+
+    fn outputs(&self) -> Result<Vec<String>, String>;
+
+    /// Connects to an input port, calling the callback with every message received. Returns
+    /// `None` if the port no longer exists.
+    fn connect_input(
+        &self,
+        position: usize,
+        name: &str,
+        callback: InputCallback,
+    ) -> Result<Option<Box<dyn InputConnection>>, String>;
+
+    /// Connects to an output port. Returns `None` if the port no longer exists.
+    fn connect_output(
+        &self,
+        position: usize,
+        name: &str,
+    ) -> Result<Option<Box<dyn OutputConnection>>, String>;
+}
+
+/// The system's MIDI ports.
+pub struct MidirTransport;
+
+impl InputConnection for MidiInputConnection<()> {}
+
+impl OutputConnection for MidiOutputConnection {
+    fn send(&mut self, data: &[u8]) -> Result<(), String> {
+        MidiOutputConnection::send(self, data)
+            .map_err(|e| format!("Failed to send MIDI message: {e}"))
+    }
+}
+
+impl Transport for MidirTransport {
+    fn inputs(&self) -> Result<Vec<String>, String> {
+        let midi_input =
+            MidiInput::new("MidiCtrl").map_err(|e| format!("Failed to open MIDI input: {e}"))?;
+        Ok(port_names(&midi_input))
+    }
+
+    fn outputs(&self) -> Result<Vec<String>, String> {
+        let midi_output =
+            MidiOutput::new("MidiCtrl").map_err(|e| format!("Failed to open MIDI output: {e}"))?;
+        Ok(port_names(&midi_output))
+    }
+
+    fn connect_input(
+        &self,
+        position: usize,
+        name: &str,
+        mut callback: InputCallback,
+    ) -> Result<Option<Box<dyn InputConnection>>, String> {
+        let mut midi_input =
+            MidiInput::new("MidiCtrl").map_err(|e| format!("Failed to open MIDI input: {e}"))?;
+        midi_input.ignore(Ignore::None);
+
+        let port = match find_port(&midi_input, position, name) {
+            Some(port) => port,
+            None => return Ok(None),
+        };
+
+        let connection = midi_input
+            .connect(&port, "MidiCtrl", move |_, buffer, _| callback(buffer), ())
+            .map_err(|e| format!("Failed to connect to MIDI device {name}: {e}"))?;
+
+        Ok(Some(Box::new(connection)))
+    }
+
+    fn connect_output(
+        &self,
+        position: usize,
+        name: &str,
+    ) -> Result<Option<Box<dyn OutputConnection>>, String> {
+        let midi_output =
+            MidiOutput::new("MidiCtrl").map_err(|e| format!("Failed to open MIDI output: {e}"))?;
+
+        let port = match find_port(&midi_output, position, name) {
+            Some(port) => port,
+            None => return Ok(None),
+        };
+
+        let connection = midi_output
+            .connect(&port, "MidiCtrl")
+            .map_err(|e| format!("Failed to connect to MIDI device {name}: {e}"))?;
+
+        Ok(Some(Box::new(connection)))
+    }
+}
+
+struct MemoryInput {
+    name: String,
+    /// Kept apart from the ports so that the callback can send to the output ports.
+    callback: Option<Arc<Mutex<InputCallback>>>,
+}
+
+struct MemoryOutput {
+    name: String,
+    sent: Vec<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct MemoryPorts {
+    inputs: Vec<MemoryInput>,
+    outputs: Vec<MemoryOutput>,
+}
+
+/// Simulated MIDI ports. Messages can be injected into the input ports as if a device had sent
+/// them and the messages sent to the output ports are recorded.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    ports: Arc<Mutex<MemoryPorts>>,
+}
+
+struct MemoryInputConnection {
+    ports: Arc<Mutex<MemoryPorts>>,
+    position: usize,
+}
+
+impl InputConnection for MemoryInputConnection {}
+
+impl Drop for MemoryInputConnection {
+    fn drop(&mut self) {
+        if let Ok(mut ports) = self.ports.lock() {
+            ports.inputs[self.position].callback = None;
+        }
+    }
+}
+
+struct MemoryOutputConnection {
+    ports: Arc<Mutex<MemoryPorts>>,
+    position: usize,
+}
+
+impl OutputConnection for MemoryOutputConnection {
+    fn send(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut ports = self
+            .ports
+            .lock()
+            .map_err(|e| format!("Failed to lock MIDI ports: {e}"))?;
+        ports.outputs[self.position].sent.push(data.to_vec());
+        Ok(())
+    }
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        Default::default()
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, MemoryPorts>, String> {
+        self.ports
+            .lock()
+            .map_err(|e| format!("Failed to lock MIDI ports: {e}"))
+    }
+
+    /// Adds an input and an output port with the same name, as most devices have.
+    pub fn add_port(&self, name: &str) -> Result<(), String> {
+        self.add_input(name)?;
+        self.add_output(name)
+    }
+
+    pub fn add_input(&self, name: &str) -> Result<(), String> {
+        self.lock()?.inputs.push(MemoryInput {
+            name: String::from(name),
+            callback: None,
+        });
+        Ok(())
+    }
+
+    pub fn add_output(&self, name: &str) -> Result<(), String> {
+        self.lock()?.outputs.push(MemoryOutput {
+            name: String::from(name),
+            sent: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Delivers a message to whatever is connected to the first input port with the name.
+    pub fn receive(&self, name: &str, data: &[u8]) -> Result<(), String> {
+        let callback = self
+            .lock()?
+            .inputs
+            .iter()
+            .find(|input| input.name == name)
+            .ok_or_else(|| format!("Unknown MIDI port {name}"))?
+            .callback
+            .clone()
+            .ok_or_else(|| format!("Nothing is connected to MIDI port {name}"))?;
+
+        let mut callback = callback
+            .lock()
+            .map_err(|e| format!("Failed to lock MIDI port {name}: {e}"))?;
+        callback(data);
+        Ok(())
+    }
+
+    /// Returns the messages sent to the first output port with the name since the last call.
+    pub fn take_sent(&self, name: &str) -> Result<Vec<Vec<u8>>, String> {
+        let mut ports = self.lock()?;
+        let output = ports
+            .outputs
+            .iter_mut()
+            .find(|output| output.name == name)
+            .ok_or_else(|| format!("Unknown MIDI port {name}"))?;
+
+        Ok(std::mem::take(&mut output.sent))
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn inputs(&self) -> Result<Vec<String>, String> {
+        Ok(self
+            .lock()?
+            .inputs
+            .iter()
+            .map(|input| input.name.clone())
+            .collect())
+    }
+
+    fn outputs(&self) -> Result<Vec<String>, String> {
+        Ok(self
+            .lock()?
+            .outputs
+            .iter()
+            .map(|output| output.name.clone())
+            .collect())
+    }
+
+    fn connect_input(
+        &self,
+        position: usize,
+        name: &str,
+        callback: InputCallback,
+    ) -> Result<Option<Box<dyn InputConnection>>, String> {
+        let mut ports = self.lock()?;
+        let input = match ports.inputs.get_mut(position) {
+            Some(input) if input.name == name => input,
+            _ => return Ok(None),
+        };
+
+        if input.callback.is_some() {
+            return Err(format!("MIDI port {name} is already connected"));
+        }
+        input.callback = Some(Arc::new(Mutex::new(callback)));
+
+        Ok(Some(Box::new(MemoryInputConnection {
+            ports: self.ports.clone(),
+            position,
+        })))
+    }
+
+    fn connect_output(
+        &self,
+        position: usize,
+        name: &str,
+    ) -> Result<Option<Box<dyn OutputConnection>>, String> {
+        match self.lock()?.outputs.get(position) {
+            Some(output) if output.name == name => Ok(Some(Box::new(MemoryOutputConnection {
+                ports: self.ports.clone(),
+                position,
+            }))),
+            _ => Ok(None),
+        }
+    }
+}
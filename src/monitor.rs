@@ -0,0 +1,259 @@
+use std::{collections::BTreeMap, fmt::Write, path::Path, sync::mpsc::channel};
+
+use midi_control::MidiMessage;
+
+use crate::{
+    midi::{
+        device::{read_devices, resolve_message, DeviceConfig},
+        identity::{probe, Identity},
+        library::templates_for_port,
+        port::PortSelector,
+        transport::{MidirTransport, Transport},
+    },
+    utils::Problems,
+};
+
+fn load_devices(root: &Path) -> BTreeMap<String, DeviceConfig> {
+    let mut problems = Problems::default();
+    let configs = read_devices(root, &mut problems);
+    problems.log();
+    configs
+}
+
+fn matching<'a>(
+    configs: &'a BTreeMap<String, DeviceConfig>,
+    port: &str,
+    identity: Option<&Identity>,
+    input: bool,
+) -> Vec<&'a str> {
+    configs
+        .iter()
+        .filter(|(_, config)| {
+            let selector = if input {
+                config.input()
+            } else {
+                config.output()
+            };
+
+            selector.is_some_and(|selector| selector.matches(port))
+                || (identity.is_some() && config.identity.as_ref() == identity)
+        })
+        .map(|(id, _)| id.as_str())
+        .collect()
+}
+
+fn print_ports(
+    title: &str,
+    ports: &[String],
+    identities: &[(usize, Identity)],
+    configs: &BTreeMap<String, DeviceConfig>,
+    input: bool,
+) {
+    println!("{title}:");
+    if ports.is_empty() {
+        println!("  (none)");
+    }
+
+    for (position, port) in ports.iter().enumerate() {
+        let identity = identities
+            .iter()
+            .find(|(other, _)| *other == position)
+            .map(|(_, identity)| identity);
+
+        let ids = matching(configs, port, identity, input);
+        if ids.is_empty() {
+            let templates = templates_for_port(port, identity);
+            if templates.is_empty() {
+                println!("  {port}");
+            } else {
+                println!("  {port} (template {})", templates.join(", "));
+            }
+        } else {
+            println!("  {port} (device {})", ids.join(", "));
+        }
+
+        if let Some(identity) = identity {
+            if let Ok(json) = serde_json::to_string(identity) {
+                println!("    identity {json}");
+            }
+        }
+    }
+}
+
+/// Lists the available MIDI ports along with the devices configured for them.
+pub fn ports(root: &Path) -> Result<(), String> {
+    let transport = MidirTransport;
+    let inputs = transport.inputs()?;
+    let outputs = transport.outputs()?;
+    let configs = load_devices(root);
+    // Only ports that nothing recognises by name need to be sent a Device Inquiry.
+    let positions: Vec<usize> = inputs
+        .iter()
+        .enumerate()
+        .filter(|(_, port)| {
+            matching(&configs, port, None, true).is_empty()
+                && templates_for_port(port, None).is_empty()
+        })
+        .map(|(position, _)| position)
+        .collect();
+    let identities = probe(&transport, &inputs, &outputs, &positions);
+
+    print_ports("Input ports", &inputs, &identities, &configs, true);
+    print_ports("Output ports", &outputs, &[], &configs, false);
+
+    let missing: Vec<&String> = configs
+        .iter()
+        .filter(|(_, config)| {
+            let found = |selector: Option<&PortSelector>, ports: &[String]| {
+                selector.is_some_and(|selector| ports.iter().any(|port| selector.matches(port)))
+            };
+
+            !found(config.input(), &inputs)
+                && !found(config.output(), &outputs)
+                && !identities
+                    .iter()
+                    .any(|(_, identity)| config.identity.as_ref() == Some(identity))
+        })
+        .map(|(id, _)| id)
+        .collect();
+    if !missing.is_empty() {
+        println!("Devices with no matching port:");
+        for id in missing {
+            println!("  {id}");
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn describe(message: &MidiMessage) -> String {
+    match message {
+        MidiMessage::NoteOn(channel, event) => format!(
+            "note on {} velocity {} channel {}",
+            event.key,
+            event.value,
+            *channel as u8 + 1
+        ),
+        MidiMessage::NoteOff(channel, event) => format!(
+            "note off {} velocity {} channel {}",
+            event.key,
+            event.value,
+            *channel as u8 + 1
+        ),
+        MidiMessage::PolyKeyPressure(channel, event) => format!(
+            "key pressure {} value {} channel {}",
+            event.key,
+            event.value,
+            *channel as u8 + 1
+        ),
+        MidiMessage::ControlChange(channel, event) => format!(
+            "cc {} value {} channel {}",
+            event.control,
+            event.value,
+            *channel as u8 + 1
+        ),
+        MidiMessage::ProgramChange(channel, program) => {
+            format!("program change {} channel {}", program, *channel as u8 + 1)
+        }
+        MidiMessage::ChannelPressure(channel, value) => {
+            format!("channel pressure {} channel {}", value, *channel as u8 + 1)
+        }
+        MidiMessage::PitchBend(channel, lsb, msb) => format!(
+            "pitch bend {} channel {}",
+            ((*msb as u16) << 7) | *lsb as u16,
+            *channel as u8 + 1
+        ),
+        MidiMessage::SysEx(_) => String::from("sysex"),
+        MidiMessage::Invalid => String::from("unknown message"),
+    }
+}
+
+fn annotate(port: &str, buffer: &[u8], devices: &[(String, DeviceConfig)]) -> String {
+    let message = MidiMessage::from(buffer);
+
+    let mut line = format!("[{port}] ");
+    for byte in buffer {
+        let _ = write!(line, "{byte:02x} ");
+    }
+    line.push_str(&describe(&message));
+
+    let resolved = devices.iter().find_map(|(id, config)| {
+        resolve_message(&message, &config.controls).map(|resolved| (id, resolved))
+    });
+
+    match resolved {
+        Some((id, resolved)) => {
+            let _ = write!(line, " -> {id}: {resolved}");
+        }
+        None => line.push_str(" -> unmapped"),
+    }
+
+    line
+}
+
+/// Prints every MIDI message received on the given port, or on all ports, along with the control
+/// it maps to.
+pub fn monitor(root: &Path, port: Option<&str>) -> Result<(), String> {
+    let configs = load_devices(root);
+
+    let transport = MidirTransport;
+    let names = transport.inputs()?;
+
+    let selected: Vec<usize> = match port {
+        Some(port) => {
+            let index = names.iter().position(|name| name == port).ok_or_else(|| {
+                format!(
+                    "Unknown MIDI port {}, the available ports are: {}",
+                    port,
+                    names.join(", ")
+                )
+            })?;
+            vec![index]
+        }
+        None => (0..names.len()).collect(),
+    };
+
+    if selected.is_empty() {
+        return Err(String::from("No MIDI input ports were found"));
+    }
+
+    let (sender, lines) = channel();
+    let mut connections = Vec::new();
+
+    for index in selected {
+        let name = names[index].clone();
+        let devices: Vec<(String, DeviceConfig)> = configs
+            .iter()
+            .filter(|(_, config)| {
+                config
+                    .input()
+                    .is_some_and(|selector| selector.matches(&name))
+            })
+            .map(|(id, config)| (id.clone(), config.clone()))
+            .collect();
+
+        let sender = sender.clone();
+        let port_name = name.clone();
+        let connection = match transport.connect_input(
+            index,
+            &name,
+            Box::new(move |buffer| {
+                let _ = sender.send(annotate(&port_name, buffer, &devices));
+            }),
+        )? {
+            Some(connection) => connection,
+            None => continue,
+        };
+
+        println!("Monitoring {name}");
+        connections.push(connection);
+    }
+
+    drop(sender);
+
+    for line in lines {
+        println!("{line}");
+    }
+
+    Ok(())
+}
@@ -1,16 +1,19 @@
+use std::fmt;
+
+use schemars::JsonSchema;
 use serde::Deserialize;
 
 use crate::{
     midi::controls::KeyState,
     state::{
         params::{BoolParam, FloatParam},
-        Condition, State,
+        Condition, Param, State,
     },
 };
 
 use super::Action;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 #[serde(untagged)]
 pub enum ContinuousAction {
     Parameter(FloatParam),
@@ -27,12 +30,26 @@ impl ContinuousAction {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+impl fmt::Display for ContinuousAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContinuousAction::Parameter(parameter) => {
+                write!(f, "set {}", Param::from(parameter.clone()))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 #[serde(untagged)]
 pub enum KeyAction {
+    #[schemars(title = "boolean parameter")]
     Parameter(BoolParam),
+    #[schemars(title = "toggle")]
     Toggle { toggle: BoolParam },
+    #[schemars(title = "action")]
     Action(Action),
+    #[schemars(title = "sequence")]
     Sequence { sequence: Vec<KeyAction> },
 }
 
@@ -63,18 +80,47 @@ impl KeyAction {
 
         actions
     }
+
+    /// Lists every action this could perform regardless of the current state.
+    pub fn all_actions(&self) -> Vec<&Action> {
+        match self {
+            KeyAction::Action(action) => vec![action],
+            KeyAction::Sequence { sequence } => {
+                sequence.iter().flat_map(KeyAction::all_actions).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for KeyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyAction::Parameter(parameter) => write!(f, "set {}", Param::from(parameter.clone())),
+            KeyAction::Toggle { toggle } => write!(f, "toggle {}", Param::from(toggle.clone())),
+            KeyAction::Action(action) => write!(f, "{action}"),
+            KeyAction::Sequence { sequence } => {
+                let list: Vec<String> = sequence.iter().map(ToString::to_string).collect();
+                write!(f, "{}", list.join(" then "))
+            }
+        }
+    }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 #[serde(untagged)]
 pub enum KeySource {
+    #[schemars(title = "boolean parameter")]
     Parameter(BoolParam),
+    #[schemars(title = "inverted parameter")]
     InvertedParameter {
         parameter: BoolParam,
         #[serde(default)]
         invert: bool,
     },
+    #[schemars(title = "constant")]
     Constant(bool),
+    #[schemars(title = "condition")]
     Condition {
         condition: Condition,
         #[serde(default)]
@@ -82,15 +128,73 @@ pub enum KeySource {
     },
 }
 
-#[derive(Deserialize, Debug, Clone)]
+impl KeySource {
+    pub fn value(&self, state: &State) -> bool {
+        match self {
+            KeySource::Constant(value) => *value,
+            KeySource::Parameter(parameter) => state.bools.get(parameter).copied().unwrap_or(false),
+            KeySource::InvertedParameter { parameter, invert } => state
+                .bools
+                .get(parameter)
+                .map(|value| *value != *invert)
+                .unwrap_or(false),
+            KeySource::Condition { condition, invert } => condition.matches(state) != *invert,
+        }
+    }
+}
+
+impl fmt::Display for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::Constant(true) => write!(f, "always on"),
+            KeySource::Constant(false) => write!(f, "always off"),
+            KeySource::Parameter(parameter)
+            | KeySource::InvertedParameter {
+                parameter,
+                invert: false,
+            } => write!(f, "{}", Param::from(parameter.clone())),
+            KeySource::InvertedParameter { parameter, .. } => {
+                write!(f, "not {}", Param::from(parameter.clone()))
+            }
+            KeySource::Condition {
+                condition,
+                invert: false,
+            } => write!(f, "whether {condition}"),
+            KeySource::Condition { condition, .. } => write!(f, "whether not {condition}"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 #[serde(untagged)]
 pub enum ContinuousSource {
     Parameter(FloatParam),
     Constant(f64),
 }
 
-#[derive(Deserialize, Debug, Clone)]
+impl ContinuousSource {
+    pub fn value(&self, state: &State) -> Option<f64> {
+        match self {
+            ContinuousSource::Constant(value) => Some(*value),
+            ContinuousSource::Parameter(parameter) => state.floats.get(parameter).copied(),
+        }
+    }
+}
+
+impl fmt::Display for ContinuousSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContinuousSource::Constant(value) => write!(f, "always {value}"),
+            ContinuousSource::Parameter(parameter) => {
+                write!(f, "{}", Param::from(parameter.clone()))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 #[serde(untagged)]
+#[schemars(rename = "Choice_for_{T}")]
 pub enum Choice<T>
 where
     T: Clone,
@@ -119,10 +223,25 @@ where
             Choice::Simple(result) => Some(result.clone()),
         }
     }
+
+    pub fn condition(&self) -> Option<&Condition> {
+        match self {
+            Choice::Conditional { when, .. } => Some(when),
+            Choice::Simple(_) => None,
+        }
+    }
+
+    pub fn value(&self) -> &T {
+        match self {
+            Choice::Conditional { then, .. } => then,
+            Choice::Simple(result) => result,
+        }
+    }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 #[serde(untagged)]
+#[schemars(rename = "Choices_for_{T}")]
 pub enum Choices<T>
 where
     T: Clone,
@@ -149,9 +268,73 @@ where
             }
         }
     }
+
+    pub fn choices(&self) -> Vec<&Choice<T>> {
+        match self {
+            Choices::Single(choice) => vec![choice],
+            Choices::Many(choices) => choices.iter().collect(),
+        }
+    }
+
+    pub fn conditions(&self) -> Vec<&Condition> {
+        self.choices()
+            .into_iter()
+            .filter_map(Choice::condition)
+            .collect()
+    }
+
+    pub fn values(&self) -> Vec<&T> {
+        self.choices().into_iter().map(Choice::value).collect()
+    }
+
+    /// Describes each choice in turn, which one is used for the state and why the ones before it
+    /// were not.
+    pub fn explain(&self, state: &State) -> Vec<String>
+    where
+        T: fmt::Display,
+    {
+        let mut chosen = false;
+        let mut lines: Vec<String> = self
+            .choices()
+            .into_iter()
+            .enumerate()
+            .map(|(index, choice)| {
+                let result = if chosen {
+                    String::from("not reached")
+                } else {
+                    match choice.condition() {
+                        Some(condition) if !condition.matches(state) => {
+                            format!("skipped, {}", condition.current_values(state).join(", "))
+                        }
+                        _ => {
+                            chosen = true;
+                            String::from("chosen")
+                        }
+                    }
+                };
+
+                match choice.condition() {
+                    Some(condition) => format!(
+                        "{}. {} if {}: {}",
+                        index + 1,
+                        choice.value(),
+                        condition,
+                        result
+                    ),
+                    None => format!("{}. {}: {}", index + 1, choice.value(), result),
+                }
+            })
+            .collect();
+
+        if !chosen {
+            lines.push(String::from("nothing was chosen"));
+        }
+
+        lines
+    }
 }
 
-#[derive(Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+#[derive(Deserialize, PartialEq, Eq, Hash, Debug, Clone, JsonSchema)]
 pub struct ControlLayerInfo {
     #[serde(rename = "device")]
     pub device_id: String,
@@ -159,7 +342,7 @@ pub struct ControlLayerInfo {
     pub layer: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 pub struct ContinuousProfile {
     #[serde(flatten)]
     pub info: ControlLayerInfo,
@@ -178,14 +361,14 @@ impl ContinuousProfile {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 pub struct KeyProfile {
     #[serde(flatten)]
     pub info: ControlLayerInfo,
     #[serde(rename = "onPress")]
     pub on_press: Choices<KeyAction>,
     #[serde(default)]
-    #[serde(rename = "onPress")]
+    #[serde(rename = "onRelease")]
     pub on_release: Option<Choices<KeyAction>>,
     #[serde(default)]
     #[serde(rename = "noteSource")]
@@ -207,10 +390,12 @@ impl KeyProfile {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 #[serde(untagged)]
 pub enum ControlProfile {
+    #[schemars(title = "continuous control")]
     Continuous(ContinuousProfile),
+    #[schemars(title = "key control")]
     Key(KeyProfile),
 }
 
@@ -1,26 +1,36 @@
 pub mod controls;
 
-use midir::MidiOutputConnection;
-use serde::Deserialize;
-use std::collections::BTreeMap;
-use std::fs::File;
+use schemars::{schema_for, JsonSchema, Schema};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::{read_to_string, write};
+use std::io::ErrorKind;
+use std::path::PathBuf;
 use std::{collections::HashMap, path::Path};
 
 use crate::actions::InternalAction;
+use crate::diagnostics::{parse, Format};
 use crate::lightroom::LightroomAction;
 use crate::midi::controls::KeyLayer;
 use crate::midi::controls::{ContinuousLayer, KeyState};
 use crate::midi::device::get_layer_control;
+use crate::midi::transport::OutputConnection;
 use crate::profile::controls::ContinuousSource;
 use crate::state::deserialize_string_param;
 use crate::state::params::BoolParam;
 use crate::state::params::FloatParam;
 use crate::state::params::StringParam;
+use crate::state::serialize_string_param;
+use crate::state::string_param_schema;
 use crate::state::Condition;
+use crate::state::Param;
+use crate::state::SetMapEntry;
 use crate::{
     midi::{controls::LayerControl, device::Device},
     state::State,
-    utils::iter_json,
+    utils::{iter_config, Problems},
 };
 
 use self::controls::ContinuousProfile;
@@ -31,47 +41,106 @@ use self::controls::KeyProfile;
 use self::controls::KeySource;
 use self::controls::{Choices, ContinuousAction};
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, JsonSchema)]
 #[serde(untagged)]
 pub enum Action {
-    SetFloatParameter {
-        parameter: FloatParam,
-        value: f64,
-    },
-    SetBoolParameter {
-        parameter: BoolParam,
-        value: bool,
-    },
+    #[schemars(title = "float parameter action")]
+    SetFloatParameter { parameter: FloatParam, value: f64 },
+    #[schemars(title = "boolean parameter action")]
+    SetBoolParameter { parameter: BoolParam, value: bool },
+    #[schemars(title = "string parameter action")]
     SetStringParameter {
-        #[serde(deserialize_with = "deserialize_string_param")]
+        #[serde(
+            deserialize_with = "deserialize_string_param",
+            serialize_with = "serialize_string_param"
+        )]
+        #[schemars(schema_with = "string_param_schema")]
         parameter: StringParam,
         value: String,
     },
+    #[schemars(title = "Lightroom action")]
     Lightroom(LightroomAction),
+    #[schemars(title = "internal action")]
     Internal(InternalAction),
 }
 
-#[derive(Deserialize, Debug)]
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::SetFloatParameter { parameter, value } => {
+                write!(f, "set {} to {}", Param::from(parameter.clone()), value)
+            }
+            Action::SetBoolParameter { parameter, value } => {
+                write!(f, "set {} to {}", Param::from(parameter.clone()), value)
+            }
+            Action::SetStringParameter { parameter, value } => {
+                write!(f, "set {} to {}", Param::from(parameter.clone()), value)
+            }
+            Action::Lightroom(action) => write!(f, "{action:?}"),
+            Action::Internal(action) => write!(f, "{action}"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 #[serde(untagged)]
 enum ControlConfig {
-    Control(ControlProfile),
+    #[schemars(title = "control")]
+    Control(Box<ControlProfile>),
+    #[schemars(title = "include")]
     Include { include: String },
+    #[schemars(title = "unmap")]
+    Unmap { unmap: ControlLayerInfo },
+}
+
+/// TOML files cannot be a bare list so included files may also hold the list in a `controls`
+/// property.
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+#[serde(untagged)]
+enum IncludedControls {
+    List(Vec<ControlConfig>),
+    Table { controls: Vec<ControlConfig> },
+}
+
+impl IncludedControls {
+    fn controls(self) -> Vec<ControlConfig> {
+        match self {
+            IncludedControls::List(controls) => controls,
+            IncludedControls::Table { controls } => controls,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Profile {
     pub id: String,
     name: Option<String>,
+    extends: Option<String>,
+    priority: i32,
+    sticky: bool,
+    devices: Option<Vec<String>>,
     when: Option<Condition>,
     on_enter: Option<Choices<KeyAction>>,
     on_leave: Option<Choices<KeyAction>>,
     controls: HashMap<ControlLayerInfo, ControlProfile>,
+    /// Controls unmapped by this profile or the profiles it extends, also removed from the
+    /// profile beneath when this is pushed as an overlay.
+    unmapped: HashSet<ControlLayerInfo>,
+    file: PathBuf,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 struct ProfileConfig {
     #[serde(default)]
     name: Option<String>,
+    #[serde(default)]
+    extends: Option<String>,
+    #[serde(default)]
+    priority: i32,
+    #[serde(default = "default_sticky")]
+    sticky: bool,
+    #[serde(default)]
+    devices: Option<Vec<String>>,
     #[serde(rename = "if")]
     #[serde(default)]
     when: Option<Condition>,
@@ -84,52 +153,91 @@ struct ProfileConfig {
     controls: Vec<ControlConfig>,
 }
 
+pub fn profile_schema() -> Schema {
+    schema_for!(ProfileConfig)
+}
+
+fn default_sticky() -> bool {
+    true
+}
+
+fn included_from(files: &[PathBuf]) -> String {
+    let chain: Vec<String> = files
+        .iter()
+        .rev()
+        .map(|file| format!("included from {}", file.display()))
+        .collect();
+    chain.join(", ")
+}
+
+/// `files` is the chain of files that led to these controls, starting with the profile itself.
 fn add_controls(
     profile: &str,
     devices: &HashMap<String, Device>,
-    map: &mut HashMap<ControlLayerInfo, ControlProfile>,
-    path: &Path,
+    map: &mut HashMap<ControlLayerInfo, Option<ControlProfile>>,
+    files: &mut Vec<PathBuf>,
     controls: Vec<ControlConfig>,
+    problems: &mut Problems,
 ) -> Result<(), String> {
     for control in controls {
         match control {
             ControlConfig::Include { include } => {
-                let new_path = path.join(include);
-                let file = File::open(&new_path).map_err(|e| {
-                    format!("Failed to open included file {}: {}", new_path.display(), e)
+                let new_path = match files.last().and_then(|file| file.parent()) {
+                    Some(dir) => dir.join(include),
+                    None => PathBuf::from(include),
+                };
+                let text = read_to_string(&new_path).map_err(|e| {
+                    format!(
+                        "Failed to open included file {}, {}: {}",
+                        new_path.display(),
+                        included_from(files),
+                        e
+                    )
                 })?;
-                let included: Vec<ControlConfig> = serde_json::from_reader(file).map_err(|e| {
+                let format = Format::from_path(&new_path).unwrap_or(Format::Json);
+                let included: IncludedControls = parse(&text, format).map_err(|e| {
                     format!(
-                        "Failed to parse included file {} at line {}, column {}: {}",
+                        "Failed to parse included file {}, {}, {}",
                         new_path.display(),
-                        e.line(),
-                        e.column(),
+                        included_from(files),
                         e
                     )
                 })?;
-                add_controls(profile, devices, map, new_path.parent().unwrap(), included)?;
+
+                files.push(new_path);
+                let result =
+                    add_controls(profile, devices, map, files, included.controls(), problems);
+                files.pop();
+                result?;
+            }
+            ControlConfig::Unmap { unmap: info } => {
+                if map.contains_key(&info) {
+                    problems.warning(format!("Found duplicate definition for control {} in layer {} on device {} in profile {}", info.control, info.layer, info.device_id, profile));
+                }
+
+                map.insert(info, None);
             }
             ControlConfig::Control(control) => {
                 let info = control.info();
                 if map.contains_key(&info) {
-                    log::warn!("Found duplicate definition for control {} in layer {} on device {} in profile {}", info.control, info.layer, info.device_id, profile);
+                    problems.warning(format!("Found duplicate definition for control {} in layer {} on device {} in profile {}", info.control, info.layer, info.device_id, profile));
                 }
 
                 match (
-                    control,
+                    *control,
                     get_layer_control(devices, &info.device_id, &info.control, &info.layer),
                 ) {
                     (ControlProfile::Continuous(control), Some(LayerControl::Continuous(_))) => {
-                        map.insert(info, ControlProfile::Continuous(control));
+                        map.insert(info, Some(ControlProfile::Continuous(control)));
                     }
                     (ControlProfile::Key(control), Some(LayerControl::Key(_))) => {
-                        map.insert(info, ControlProfile::Key(control));
+                        map.insert(info, Some(ControlProfile::Key(control)));
                     }
                     (control_profile, Some(device_control)) => {
                         return Err(format!("Profile {} configuration for control {} in device {}, layer {} did not match the control type from the device, {:?} {:?}", profile, info.control, info.device_id, info.layer, control_profile, device_control));
                     }
                     (_, _) => {
-                        return Err(format!("Profile {} configuration contained control {} in layer {}that does not exist in device {}", profile, info.control, info.layer, info.device_id));
+                        return Err(format!("Profile {} configuration contained control {} in layer {} that does not exist in device {}", profile, info.control, info.layer, info.device_id));
                     }
                 }
             }
@@ -145,30 +253,97 @@ impl ProfileConfig {
         path: &Path,
         id: &str,
         devices: &HashMap<String, Device>,
+        parent: Option<&Profile>,
+        problems: &mut Problems,
     ) -> Result<Profile, String> {
         let mut map = HashMap::new();
 
-        add_controls(id, devices, &mut map, path, self.controls)?;
+        let mut files = vec![path.to_path_buf()];
+        add_controls(id, devices, &mut map, &mut files, self.controls, problems)?;
+
+        let mut controls = parent
+            .map(|parent| parent.controls.clone())
+            .unwrap_or_default();
+        let mut unmapped = parent
+            .map(|parent| parent.unmapped.clone())
+            .unwrap_or_default();
+
+        for (info, control) in map {
+            match control {
+                Some(control) => {
+                    unmapped.remove(&info);
+                    controls.insert(info, control);
+                }
+                None => {
+                    unmapped.insert(info.clone());
+                    // Without a parent the unmap can only apply when pushed as an overlay.
+                    if controls.remove(&info).is_none() && parent.is_some() {
+                        problems.warning(format!(
+                            "Profile {} unmaps control {} in layer {} on device {} which was not mapped",
+                            id,
+                            info.control,
+                            info.layer,
+                            info.device_id
+                        ));
+                    }
+                }
+            }
+        }
+
+        let profile_devices = self
+            .devices
+            .or_else(|| parent.and_then(|parent| parent.devices.clone()));
+
+        if let Some(ref profile_devices) = profile_devices {
+            for device in profile_devices {
+                if !devices.contains_key(device) {
+                    problems.warning(format!(
+                        "Profile {} applies to unknown device {}",
+                        id, device
+                    ));
+                }
+            }
+
+            for info in controls.keys() {
+                if !profile_devices.contains(&info.device_id) {
+                    problems.warning(format!(
+                        "Profile {} maps control {} on device {} but does not apply to that device",
+                        id, info.control, info.device_id
+                    ));
+                }
+            }
+        }
 
         Ok(Profile {
+            file: path.to_path_buf(),
             id: String::from(id),
             name: self.name,
-            when: self.when,
-            on_enter: self.on_enter,
-            on_leave: self.on_leave,
-            controls: map,
+            extends: self.extends,
+            priority: self.priority,
+            sticky: self.sticky,
+            devices: profile_devices,
+            when: self
+                .when
+                .or_else(|| parent.and_then(|parent| parent.when.clone())),
+            on_enter: self
+                .on_enter
+                .or_else(|| parent.and_then(|parent| parent.on_enter.clone())),
+            on_leave: self
+                .on_leave
+                .or_else(|| parent.and_then(|parent| parent.on_leave.clone())),
+            controls,
+            unmapped,
         })
     }
 }
 
-fn perform_continuous_update(
-    connection: &mut MidiOutputConnection,
-    state: &State,
-    control: &ContinuousLayer,
+/// Picks where the value shown by a continuous control comes from. Without a `valueSource` it
+/// shows the parameter it changes.
+fn continuous_source(
     control_profile: &ContinuousProfile,
-    force: bool,
-) {
-    let source = match &control_profile.value_source {
+    state: &State,
+) -> Option<ContinuousSource> {
+    match &control_profile.value_source {
         Some(source) => source.resolve(state),
         None => match control_profile.on_change.resolve(state) {
             Some(ContinuousAction::Parameter(parameter)) => {
@@ -176,74 +351,70 @@ fn perform_continuous_update(
             }
             _ => None,
         },
-    };
+    }
+}
 
-    if let Some(source) = source {
-        let value = match source {
-            ContinuousSource::Constant(value) => value,
-            ContinuousSource::Parameter(parameter) => {
-                if let Some(value) = state.floats.get(&parameter) {
-                    *value
-                } else {
-                    return;
-                }
+/// Picks where the state shown by a key comes from. Without a `noteSource` it shows the
+/// parameter it sets or toggles.
+fn key_source(control_profile: &KeyProfile, state: &State) -> Option<KeySource> {
+    match &control_profile.note_source {
+        Some(source) => source.resolve(state),
+        None => match &control_profile.on_press.resolve(state) {
+            Some(KeyAction::Parameter(parameter)) => Some(KeySource::Parameter(parameter.clone())),
+            Some(KeyAction::Toggle { toggle: parameter }) => {
+                Some(KeySource::Parameter(parameter.clone()))
             }
-        };
+            _ => None,
+        },
+    }
+}
+
+/// Names the parameter a continuous control changes, for devices that can show a label.
+fn continuous_label(control_profile: &ContinuousProfile, state: &State) -> String {
+    match control_profile.on_change.resolve(state) {
+        Some(ContinuousAction::Parameter(parameter)) => Param::from(parameter).to_string(),
+        None => String::new(),
+    }
+}
 
+/// Names the parameter a key changes or the action it performs.
+fn key_label(control_profile: &KeyProfile, state: &State) -> String {
+    match control_profile.on_press.resolve(state) {
+        Some(KeyAction::Parameter(parameter)) | Some(KeyAction::Toggle { toggle: parameter }) => {
+            Param::from(parameter).to_string()
+        }
+        Some(action) => action.to_string(),
+        None => String::new(),
+    }
+}
+
+fn perform_continuous_update(
+    connection: &mut dyn OutputConnection,
+    state: &State,
+    control: &ContinuousLayer,
+    control_profile: &ContinuousProfile,
+    force: bool,
+) {
+    control.update_label(connection, &continuous_label(control_profile, state), force);
+
+    if let Some(value) =
+        continuous_source(control_profile, state).and_then(|source| source.value(state))
+    {
         control.update(connection, control.state_from_value(value), force);
     }
 }
 
 fn perform_key_update(
-    connection: &mut MidiOutputConnection,
+    connection: &mut dyn OutputConnection,
     state: &State,
     control: &KeyLayer,
     control_profile: &KeyProfile,
     force: bool,
 ) {
-    let source = match &control_profile.note_source {
-        Some(source) => source.resolve(state),
-        None => match &control_profile.on_press.resolve(state) {
-            Some(KeyAction::Parameter(parameter)) => Some(KeySource::Parameter(parameter.clone())),
-            Some(KeyAction::Toggle { toggle: parameter }) => {
-                Some(KeySource::Parameter(parameter.clone()))
-            }
-            _ => None,
-        },
-    };
-
-    if let Some(source) = source {
-        let value = match source {
-            KeySource::Constant(value) => value,
-            KeySource::Parameter(parameter) => {
-                if let Some(value) = state.bools.get(&parameter) {
-                    *value
-                } else {
-                    false
-                }
-            }
-            KeySource::InvertedParameter { parameter, invert } => {
-                if let Some(value) = state.bools.get(&parameter) {
-                    if invert {
-                        !*value
-                    } else {
-                        *value
-                    }
-                } else {
-                    false
-                }
-            }
-            KeySource::Condition { condition, invert } => {
-                let result = condition.matches(state);
-                if invert {
-                    !result
-                } else {
-                    result
-                }
-            }
-        };
+    control.update_label(connection, &key_label(control_profile, state), force);
 
-        control.update(connection, value.into(), force);
+    if let Some(source) = key_source(control_profile, state) {
+        control.update(connection, source.value(state).into(), force);
     }
 }
 
@@ -326,7 +497,7 @@ impl Profile {
     #[allow(clippy::too_many_arguments)]
     pub fn update_layer_control(
         &self,
-        connection: &mut MidiOutputConnection,
+        connection: &mut dyn OutputConnection,
         state: &State,
         device_id: &str,
         control_name: &str,
@@ -347,6 +518,7 @@ impl Profile {
                         force,
                     );
                 } else {
+                    layer_control.update_label(connection, "", force);
                     layer_control.update(connection, layer_control.min, force);
                 }
             }
@@ -356,35 +528,232 @@ impl Profile {
                 {
                     perform_key_update(connection, state, layer_control, control_profile, force);
                 } else {
+                    layer_control.update_label(connection, "", force);
                     layer_control.update(connection, KeyState::Off, true);
                 }
             }
         }
     }
 
-    pub fn update_devices(
+    pub fn update_device(&self, id: &str, device: &mut Device, state: &State, force: bool) {
+        if let Some(ref mut output) = device.output {
+            for control in device.controls.values() {
+                for (layer, layer_control) in control.layers() {
+                    self.update_layer_control(
+                        output.as_mut(),
+                        state,
+                        id,
+                        control.name(),
+                        &layer,
+                        &layer_control,
+                        force,
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn applies_to(&self, device_id: &str) -> bool {
+        match &self.devices {
+            Some(devices) => devices.iter().any(|device| device == device_id),
+            None => true,
+        }
+    }
+
+    /// Returns a copy of this profile with the controls of the overlay replacing its own and
+    /// the controls it unmaps removed.
+    fn overlaid(mut self, overlay: &Profile) -> Profile {
+        self.controls
+            .retain(|info, _| !overlay.unmapped.contains(info));
+        self.controls.extend(
+            overlay
+                .controls
+                .iter()
+                .map(|(info, control)| (info.clone(), control.clone())),
+        );
+        self
+    }
+
+    pub fn extends(&self) -> Option<&str> {
+        self.extends.as_deref()
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Whether this profile is available regardless of the state.
+    pub fn is_unconditional(&self) -> bool {
+        self.when.is_none()
+    }
+
+    /// Lists every condition this profile may test.
+    pub fn conditions(&self) -> Vec<&Condition> {
+        let mut conditions: Vec<&Condition> = self.when.iter().collect();
+
+        for choices in self.on_enter.iter().chain(self.on_leave.iter()) {
+            conditions.extend(choices.conditions());
+        }
+
+        for control in self.controls.values() {
+            match control {
+                ControlProfile::Continuous(control) => {
+                    conditions.extend(control.on_change.conditions());
+                    if let Some(ref source) = control.value_source {
+                        conditions.extend(source.conditions());
+                    }
+                }
+                ControlProfile::Key(control) => {
+                    conditions.extend(control.on_press.conditions());
+                    if let Some(ref choices) = control.on_release {
+                        conditions.extend(choices.conditions());
+                    }
+                    if let Some(ref source) = control.note_source {
+                        conditions.extend(source.conditions());
+                        for source in source.values() {
+                            if let KeySource::Condition { condition, .. } = source {
+                                conditions.push(condition);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        conditions
+    }
+
+    /// Lists every action this profile may perform.
+    pub fn actions(&self) -> Vec<&Action> {
+        let mut choices: Vec<&Choices<KeyAction>> =
+            self.on_enter.iter().chain(self.on_leave.iter()).collect();
+
+        for control in self.controls.values() {
+            if let ControlProfile::Key(control) = control {
+                choices.push(&control.on_press);
+                choices.extend(control.on_release.iter());
+            }
+        }
+
+        choices
+            .into_iter()
+            .flat_map(Choices::values)
+            .flat_map(KeyAction::all_actions)
+            .collect()
+    }
+
+    /// Describes what the control would do in the current state and what it shows.
+    pub fn explain_control(
         &self,
-        devices: &mut HashMap<String, Device>,
         state: &State,
-        force: bool,
-    ) {
-        for (id, device) in devices {
-            if let Some(ref mut output) = device.output {
-                for control in device.controls.values() {
-                    for (layer, layer_control) in control.layers() {
-                        self.update_layer_control(
-                            output,
-                            state,
-                            id,
-                            control.name(),
-                            &layer,
-                            &layer_control,
-                            force,
-                        );
+        device_id: &str,
+        control_name: &str,
+        layer: &str,
+        layer_control: &LayerControl,
+    ) -> Vec<String> {
+        let control_profile = match self.get_control_profile(device_id, control_name, layer) {
+            Some(control_profile) => control_profile,
+            None => {
+                return vec![format!(
+                    "{} in layer {} is not mapped in profile {}",
+                    control_name, layer, self.id
+                )]
+            }
+        };
+
+        let indent = |lines: Vec<String>| lines.into_iter().map(|line| format!("  {line}"));
+        let list = |actions: Option<Vec<Action>>| match actions {
+            Some(actions) if !actions.is_empty() => actions
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<String>>()
+                .join(", "),
+            _ => String::from("nothing"),
+        };
+
+        let mut lines = Vec::new();
+        match (control_profile, layer_control) {
+            (
+                ControlProfile::Continuous(control_profile),
+                LayerControl::Continuous(layer_control),
+            ) => {
+                let value = layer_control
+                    .state
+                    .lock()
+                    .map(|state| layer_control.value_from_state(*state))
+                    .unwrap_or_default();
+
+                lines.push(String::from("onChange:"));
+                lines.extend(indent(control_profile.on_change.explain(state)));
+                lines.push(format!(
+                    "Moving it to {:.3} does: {}",
+                    value,
+                    list(control_profile.change_action(state, value))
+                ));
+
+                match &control_profile.value_source {
+                    Some(source) => {
+                        lines.push(String::from("valueSource:"));
+                        lines.extend(indent(source.explain(state)));
                     }
+                    None => lines.push(String::from(
+                        "There is no valueSource so it shows the parameter it changes",
+                    )),
                 }
+                lines.push(match continuous_source(control_profile, state) {
+                    Some(source) => format!(
+                        "It shows {} = {}",
+                        source,
+                        source
+                            .value(state)
+                            .map(|value| value.to_string())
+                            .unwrap_or_else(|| String::from("unset"))
+                    ),
+                    None => String::from("It shows nothing"),
+                });
             }
+            (ControlProfile::Key(control_profile), LayerControl::Key(_)) => {
+                lines.push(String::from("onPress:"));
+                lines.extend(indent(control_profile.on_press.explain(state)));
+                lines.push(format!(
+                    "Pressing it does: {}",
+                    list(control_profile.press_actions(state))
+                ));
+
+                if let Some(ref on_release) = control_profile.on_release {
+                    lines.push(String::from("onRelease:"));
+                    lines.extend(indent(on_release.explain(state)));
+                    lines.push(format!(
+                        "Releasing it does: {}",
+                        list(control_profile.release_actions(state))
+                    ));
+                }
+
+                match &control_profile.note_source {
+                    Some(source) => {
+                        lines.push(String::from("noteSource:"));
+                        lines.extend(indent(source.explain(state)));
+                    }
+                    None => lines.push(String::from(
+                        "There is no noteSource so it shows the parameter it sets or toggles",
+                    )),
+                }
+                lines.push(match key_source(control_profile, state) {
+                    Some(source) => format!(
+                        "It shows {} = {}",
+                        source,
+                        KeyState::from(source.value(state))
+                    ),
+                    None => String::from("It shows nothing"),
+                });
+            }
+            _ => lines.push(format!(
+                "{} in layer {} is mapped as the wrong type of control in profile {}",
+                control_name, layer, self.id
+            )),
         }
+
+        lines
     }
 
     pub fn is_enabled(&self, state: &State) -> bool {
@@ -401,41 +770,156 @@ impl PartialEq for Profile {
     }
 }
 
+/// Adds a control to a profile file, replacing any existing definition of the same control in
+/// the file. The file is created if necessary.
+pub fn write_control(path: &Path, control: JsonValue) -> Result<(), String> {
+    if Format::from_path(path) != Some(Format::Json) {
+        return Err(format!(
+            "Cannot add controls to {}, only JSON profiles can be updated",
+            path.display()
+        ));
+    }
+
+    let mut profile = match read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => json!({ "controls": [] }),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+
+    let controls = profile
+        .as_object_mut()
+        .map(|profile| profile.entry("controls").or_insert_with(|| json!([])))
+        .and_then(JsonValue::as_array_mut)
+        .ok_or_else(|| format!("{} does not contain a list of controls", path.display()))?;
+
+    controls.retain(|existing| {
+        !["device", "control", "layer"]
+            .iter()
+            .all(|key| existing.get(key) == control.get(key))
+    });
+    controls.push(control);
+
+    let mut data = serde_json::to_string_pretty(&profile)
+        .map_err(|e| format!("Failed to encode profile: {e}"))?;
+    data.push('\n');
+
+    write(path, data).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Tracks the profile selected for each device along with any profiles pushed over them.
 pub struct Profiles {
-    current_profile: Option<String>,
+    devices: Vec<String>,
+    current_profiles: BTreeMap<String, String>,
+    overlays: Vec<String>,
     profiles: BTreeMap<String, Profile>,
 }
 
-fn read_profiles(root: &Path, devices: &HashMap<String, Device>) -> BTreeMap<String, Profile> {
-    let mut profiles = BTreeMap::new();
+fn resolve_profile(
+    id: &str,
+    devices: &HashMap<String, Device>,
+    configs: &BTreeMap<String, (PathBuf, ProfileConfig)>,
+    profiles: &mut BTreeMap<String, Profile>,
+    chain: &mut Vec<String>,
+    problems: &mut Problems,
+) -> Result<Profile, String> {
+    if let Some(profile) = profiles.get(id) {
+        return Ok(profile.clone());
+    }
+
+    if chain.iter().any(|parent| parent == id) {
+        return Err(format!(
+            "Profile {} has circular inheritance: {} -> {}",
+            chain[0],
+            chain.join(" -> "),
+            id
+        ));
+    }
+
+    let (path, config) = match configs.get(id) {
+        Some(entry) => entry,
+        None => {
+            return Err(format!(
+                "Profile {} extends unknown profile {}",
+                chain.last().map(String::as_str).unwrap_or(id),
+                id
+            ))
+        }
+    };
+
+    let parent = match config.extends {
+        Some(ref parent) => {
+            chain.push(String::from(id));
+            let parent = resolve_profile(parent, devices, configs, profiles, chain, problems);
+            chain.pop();
+            Some(parent?)
+        }
+        None => None,
+    };
+
+    let profile = config
+        .clone()
+        .into_profile(path, id, devices, parent.as_ref(), problems)?;
+    profiles.insert(String::from(id), profile.clone());
 
+    Ok(profile)
+}
+
+pub fn read_profiles(
+    root: &Path,
+    devices: &HashMap<String, Device>,
+    problems: &mut Problems,
+) -> BTreeMap<String, Profile> {
     let dir = root.join("profiles");
-    let entries = match iter_json::<ProfileConfig>(&dir) {
+    let entries = match iter_config::<ProfileConfig>(&dir) {
         Ok(entries) => entries,
         Err(e) => {
-            log::error!("Error reading profiles directory: {}", e);
-            return profiles;
+            problems.error(format!("Error reading profiles directory: {}", e));
+            return BTreeMap::new();
         }
     };
 
+    let mut configs = BTreeMap::new();
     for entry in entries {
         match entry {
-            Ok((id, config)) => match config.into_profile(&dir, &id, devices) {
-                Ok(profile) => {
-                    profiles.insert(id, profile);
-                }
-                Err(e) => log::error!("{}", e),
-            },
-            Err(e) => log::error!("{}", e),
+            Ok((id, path, config)) => {
+                configs.insert(id, (path, config));
+            }
+            Err(e) => problems.error(e),
         };
     }
 
+    resolve_profiles(devices, &configs, problems)
+}
+
+fn resolve_profiles(
+    devices: &HashMap<String, Device>,
+    configs: &BTreeMap<String, (PathBuf, ProfileConfig)>,
+    problems: &mut Problems,
+) -> BTreeMap<String, Profile> {
+    let mut profiles = BTreeMap::new();
+
+    for id in configs.keys() {
+        if let Err(e) = resolve_profile(
+            id,
+            devices,
+            configs,
+            &mut profiles,
+            &mut Vec::new(),
+            problems,
+        ) {
+            problems.error(e);
+        }
+    }
+
     profiles
 }
 
 impl Profiles {
     pub fn new(root: &Path, devices: &HashMap<String, Device>) -> Profiles {
-        let profile_list = read_profiles(root, devices);
+        let mut problems = Problems::default();
+        let profile_list = read_profiles(root, devices, &mut problems);
+        problems.log();
 
         if !profile_list.is_empty() {
             log::info!("Loaded {} profiles", profile_list.len());
@@ -443,8 +927,13 @@ impl Profiles {
             log::warn!("Found no profiles");
         }
 
+        let mut device_ids: Vec<String> = devices.keys().cloned().collect();
+        device_ids.sort();
+
         let mut profiles = Profiles {
-            current_profile: None,
+            devices: device_ids,
+            current_profiles: BTreeMap::new(),
+            overlays: Vec::new(),
             profiles: profile_list,
         };
 
@@ -452,48 +941,750 @@ impl Profiles {
         profiles
     }
 
-    pub fn set_profile(&mut self, id: &str, state: &State) -> Option<Profile> {
-        if let Some(profile) = self.profiles.get(id) {
-            if profile.is_enabled(state) {
-                self.current_profile = Some(String::from(id));
-                Some(profile.clone())
-            } else {
+    /// Reads the profiles again, keeping the current selections where the profiles still exist.
+    pub fn reload(&mut self, root: &Path, devices: &HashMap<String, Device>, state: &State) {
+        let mut problems = Problems::default();
+        self.profiles = read_profiles(root, devices, &mut problems);
+        problems.log();
+        log::info!("Reloaded {} profiles", self.profiles.len());
+
+        let profiles = &self.profiles;
+        self.current_profiles
+            .retain(|_, id| profiles.contains_key(id.as_str()));
+        self.overlays.retain(|id| profiles.contains_key(id));
+        self.state_update(state);
+    }
+
+    /// The file a profile was loaded from.
+    pub fn file(&self, id: &str) -> Option<&Path> {
+        self.profiles.get(id).map(|profile| profile.file.as_path())
+    }
+
+    /// Selects the profile for the given device, or for every device it applies to when the
+    /// selection didn't come from a device. Returns false if the profile could not be selected.
+    pub fn set_profile(&mut self, id: &str, device_id: Option<&str>, state: &State) -> bool {
+        match self.profiles.get(id) {
+            Some(profile) if device_id.is_some_and(|device| !profile.applies_to(device)) => {
+                log::warn!(
+                    "Attempted to select profile {} for device {} but it does not apply to it",
+                    id,
+                    device_id.unwrap_or_default()
+                );
+                false
+            }
+            Some(profile) if profile.is_enabled(state) => {
+                for device in &self.devices {
+                    if profile.applies_to(device)
+                        && device_id.is_none_or(|device_id| device_id == device)
+                    {
+                        log::info!(
+                            "Switched device {} to profile {}: it was selected by an action",
+                            device,
+                            id
+                        );
+                        self.current_profiles
+                            .insert(device.clone(), String::from(id));
+                    }
+                }
+
+                true
+            }
+            Some(_) => {
                 log::warn!("Attempted to select profile {} but it is not available", id);
-                None
+                false
             }
-        } else {
-            None
+            None => false,
         }
     }
 
-    pub fn state_update(&mut self, state: &State) -> Option<Profile> {
-        if let Some(profile) = self
-            .current_profile
-            .as_ref()
+    /// Reselects a previously chosen profile without checking whether it is enabled, the next
+    /// state update will switch away from it if necessary.
+    pub fn restore_profile(&mut self, id: &str) -> bool {
+        match self.profiles.get(id) {
+            Some(profile) => {
+                for device in &self.devices {
+                    if profile.applies_to(device) {
+                        self.current_profiles
+                            .insert(device.clone(), String::from(id));
+                    }
+                }
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reselects a previously chosen profile for a single device, as with `restore_profile`.
+    pub fn restore_device_profile(&mut self, device_id: &str, id: &str) -> bool {
+        match self.profiles.get(id) {
+            Some(profile)
+                if profile.applies_to(device_id)
+                    && self.devices.iter().any(|device| device == device_id) =>
+            {
+                self.current_profiles
+                    .insert(String::from(device_id), String::from(id));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The ID of the profile selected for each device.
+    pub fn selections(&self) -> &BTreeMap<String, String> {
+        &self.current_profiles
+    }
+
+    /// Finds the enabled profile for the device with the highest priority. Profiles with the same
+    /// priority are ordered by their ID.
+    fn best_profile(&self, device_id: &str, state: &State) -> Option<&Profile> {
+        let mut best: Option<&Profile> = None;
+
+        for profile in self.profiles.values() {
+            if !profile.applies_to(device_id) || !profile.is_enabled(state) {
+                continue;
+            }
+
+            match best {
+                Some(current) if current.priority >= profile.priority => (),
+                _ => best = Some(profile),
+            }
+        }
+
+        best
+    }
+
+    fn select_profile(&mut self, device_id: &str, state: &State) {
+        let best = self.best_profile(device_id, state);
+
+        let reason = match self
+            .current_profiles
+            .get(device_id)
             .and_then(|id| self.profiles.get(id))
         {
-            if profile.is_enabled(state) {
-                return Some(profile.clone());
+            Some(current) if current.is_enabled(state) => match best {
+                Some(best) if !current.sticky && best.priority > current.priority => format!(
+                    "it has a higher priority ({}) than profile {} ({})",
+                    best.priority, current.id, current.priority
+                ),
+                _ => return,
+            },
+            Some(current) => format!("profile {} is no longer available", current.id),
+            None => String::from("no profile was selected"),
+        };
+
+        match best {
+            Some(best) => {
+                log::info!(
+                    "Switched device {} to profile {} (priority {}): {}",
+                    device_id,
+                    best.id,
+                    best.priority,
+                    reason
+                );
+                let id = best.id.clone();
+                self.current_profiles.insert(String::from(device_id), id);
+            }
+            None => {
+                if self.current_profiles.remove(device_id).is_some() {
+                    log::info!("There are no valid profiles for device {}", device_id);
+                }
             }
         }
+    }
 
-        for (id, profile) in &self.profiles {
-            if profile.is_enabled(state) {
-                log::info!("Switched to profile {}", id);
-                self.current_profile = Some(id.clone());
-                return Some(profile.clone());
+    /// Describes which profile is selected for the device and why the others are not.
+    pub fn explain_selection(&self, device_id: &str, state: &State) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        let current = self
+            .current_profiles
+            .get(device_id)
+            .and_then(|id| self.profiles.get(id));
+        match current {
+            Some(current) => {
+                let reason = if !current.is_enabled(state) {
+                    "it is no longer available and will be replaced on the next state update"
+                } else if self.best_profile(device_id, state) == Some(current) {
+                    "it is the available profile with the highest priority"
+                } else if current.sticky {
+                    "it is still available and stays selected until it is not"
+                } else {
+                    "it is still available and no other profile has a higher priority"
+                };
+                lines.push(format!(
+                    "Profile {} is selected for device {}, {}",
+                    current.id, device_id, reason
+                ));
             }
+            None => lines.push(format!("No profile is selected for device {device_id}")),
+        }
+
+        for profile in self.profiles.values() {
+            let status = if !profile.applies_to(device_id) {
+                String::from("does not apply to this device")
+            } else {
+                match &profile.when {
+                    Some(condition) if !condition.matches(state) => format!(
+                        "not available, {} failed: {}",
+                        condition,
+                        condition.current_values(state).join(", ")
+                    ),
+                    Some(condition) => format!("available, {condition} matched"),
+                    None => String::from("always available"),
+                }
+            };
+
+            lines.push(format!(
+                "  {} (priority {}): {}",
+                profile.id, profile.priority, status
+            ));
         }
 
-        log::info!("There are no valid profiles");
-        self.current_profile = None;
-        None
+        for overlay in &self.overlays {
+            if let Some(profile) = self.profiles.get(overlay) {
+                if profile.applies_to(device_id) {
+                    lines.push(format!("Profile {overlay} is pushed over it"));
+                }
+            }
+        }
+
+        lines
     }
 
-    pub fn current_profile(&self) -> Option<Profile> {
-        self.current_profile
-            .as_ref()
-            .and_then(|id| self.profiles.get(id))
+    pub fn state_update(&mut self, state: &State) {
+        // Conditions on `Profile` see the profile of the device being updated.
+        let mut device_state = state.clone();
+        for device_id in self.devices.clone() {
+            device_state.strings.set(
+                StringParam::Profile,
+                self.current_profiles.get(&device_id).cloned(),
+            );
+            self.select_profile(&device_id, &device_state);
+        }
+    }
+
+    /// Returns the profiles currently selected for any device.
+    pub fn active_profiles(&self) -> Vec<Profile> {
+        let mut active: Vec<Profile> = Vec::new();
+
+        for id in self.current_profiles.values() {
+            if active.iter().any(|profile| &profile.id == id) {
+                continue;
+            }
+
+            if let Some(profile) = self.profiles.get(id) {
+                active.push(profile.clone());
+            }
+        }
+
+        active
+    }
+
+    pub fn push_overlay(&mut self, id: &str, state: &State) -> Option<Profile> {
+        if self.overlays.iter().any(|overlay| overlay == id) {
+            log::warn!("Attempted to push profile {} but it is already active", id);
+            return None;
+        }
+
+        match self.profiles.get(id) {
+            Some(profile) if profile.is_enabled(state) => {
+                log::info!("Pushed profile {}", id);
+                self.overlays.push(String::from(id));
+                Some(profile.clone())
+            }
+            Some(_) => {
+                log::warn!("Attempted to push profile {} but it is not available", id);
+                None
+            }
+            None => {
+                log::warn!("Attempted to push unknown profile {}", id);
+                None
+            }
+        }
+    }
+
+    /// Pushes a previously pushed profile again without checking whether it is enabled, the
+    /// next state update pops it if necessary.
+    pub fn restore_overlay(&mut self, id: &str) -> bool {
+        if !self.profiles.contains_key(id) || self.overlays.iter().any(|overlay| overlay == id) {
+            return false;
+        }
+
+        self.overlays.push(String::from(id));
+        true
+    }
+
+    /// The IDs of the pushed profiles in the order they were pushed.
+    pub fn overlays(&self) -> &[String] {
+        &self.overlays
+    }
+
+    /// The pushed profiles in the order they were pushed.
+    pub fn overlay_profiles(&self) -> Vec<Profile> {
+        self.overlays
+            .iter()
+            .filter_map(|id| self.profiles.get(id))
             .cloned()
+            .collect()
+    }
+
+    /// Removes the named overlay or the most recently pushed overlay if no name is given.
+    pub fn pop_overlay(&mut self, id: Option<&str>) -> Option<Profile> {
+        let index = match id {
+            Some(id) => match self.overlays.iter().position(|overlay| overlay == id) {
+                Some(index) => index,
+                None => {
+                    log::warn!("Attempted to pop profile {} but it was not pushed", id);
+                    return None;
+                }
+            },
+            None => self.overlays.len().checked_sub(1)?,
+        };
+
+        let id = self.overlays.remove(index);
+        log::info!("Popped profile {}", id);
+        self.profiles.get(&id).cloned()
+    }
+
+    /// Pops every overlay that is no longer available, returning them in the order they were
+    /// pushed.
+    pub fn pop_disabled_overlays(&mut self, state: &State) -> Vec<Profile> {
+        let mut popped = Vec::new();
+        let profiles = &self.profiles;
+
+        self.overlays.retain(|id| match profiles.get(id) {
+            Some(profile) if !profile.is_enabled(state) => {
+                log::info!("Popped profile {}: it is no longer available", id);
+                popped.push(profile.clone());
+                false
+            }
+            _ => true,
+        });
+
+        popped
+    }
+
+    /// Returns the profile selected for the device with the controls of any pushed profiles
+    /// that apply to the device mapped over it.
+    pub fn current_profile(&self, device_id: &str) -> Option<Profile> {
+        let profile = self
+            .current_profiles
+            .get(device_id)
+            .and_then(|id| self.profiles.get(id))
+            .cloned()?;
+
+        Some(
+            self.overlays
+                .iter()
+                .filter_map(|id| self.profiles.get(id))
+                .filter(|overlay| overlay.applies_to(device_id))
+                .fold(profile, |profile, overlay| profile.overlaid(overlay)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{create_dir_all, remove_dir_all},
+        sync::mpsc::channel,
+    };
+
+    use super::*;
+    use crate::{midi::device::DeviceConfig, MemoryTransport};
+
+    /// Two devices that each have a knob and a button in layer A.
+    fn devices() -> HashMap<String, Device> {
+        let (sender, _) = channel();
+        ["faders", "pads"]
+            .iter()
+            .map(|id| {
+                let config: DeviceConfig = serde_json::from_value(json!({
+                    "port": id,
+                    "controls": [
+                        { "type": "cc", "name": "Knob", "layers": { "A": { "channel": 1, "control": 1 } } },
+                        { "type": "key", "name": "Button", "layers": { "A": { "channel": 1, "note": 8 } } }
+                    ]
+                }))
+                .unwrap();
+                let device = Device::new(
+                    String::from(*id),
+                    sender.clone(),
+                    config,
+                    &MemoryTransport::new(),
+                    None,
+                    None,
+                )
+                .unwrap();
+                (String::from(*id), device)
+            })
+            .collect()
+    }
+
+    fn knob(device: &str) -> JsonValue {
+        json!({ "device": device, "layer": "A", "control": "Knob", "onChange": "Exposure" })
+    }
+
+    fn button(device: &str) -> JsonValue {
+        json!({ "device": device, "layer": "A", "control": "Button", "onPress": { "toggle": "Picked" } })
+    }
+
+    fn info(device: &str, control: &str) -> ControlLayerInfo {
+        ControlLayerInfo {
+            device_id: String::from(device),
+            control: String::from(control),
+            layer: String::from("A"),
+        }
+    }
+
+    /// Resolves profiles given as a map of their IDs to their configuration.
+    fn resolve(
+        devices: &HashMap<String, Device>,
+        configs: JsonValue,
+    ) -> (BTreeMap<String, Profile>, Problems) {
+        let configs: BTreeMap<String, ProfileConfig> = serde_json::from_value(configs).unwrap();
+        let configs = configs
+            .into_iter()
+            .map(|(id, config)| {
+                let path = PathBuf::from(format!("{id}.json"));
+                (id, (path, config))
+            })
+            .collect();
+
+        let mut problems = Problems::default();
+        let profiles = resolve_profiles(devices, &configs, &mut problems);
+        (profiles, problems)
+    }
+
+    /// Tracks the profiles for both devices, selecting them as for an empty state.
+    fn profiles(configs: JsonValue) -> Profiles {
+        let devices = devices();
+        let (profiles, problems) = resolve(&devices, configs);
+        assert!(problems.errors.is_empty(), "{:?}", problems.errors);
+
+        let mut device_ids: Vec<String> = devices.keys().cloned().collect();
+        device_ids.sort();
+        let mut profiles = Profiles {
+            devices: device_ids,
+            current_profiles: BTreeMap::new(),
+            overlays: Vec::new(),
+            profiles,
+        };
+        profiles.state_update(&State::new());
+        profiles
+    }
+
+    fn with_mode(mode: &str) -> State {
+        let mut state = State::new();
+        state.strings.set(
+            StringParam::Custom(String::from("Mode")),
+            Some(String::from(mode)),
+        );
+        state
+    }
+
+    fn selected<'a>(profiles: &'a Profiles, device: &str) -> Option<&'a str> {
+        profiles.selections().get(device).map(String::as_str)
+    }
+
+    #[test]
+    fn circular_extends() {
+        let (profiles, problems) = resolve(
+            &devices(),
+            json!({
+                "first": { "extends": "second", "controls": [knob("pads")] },
+                "second": { "extends": "first", "controls": [] },
+                "other": { "controls": [] }
+            }),
+        );
+
+        assert_eq!(profiles.keys().collect::<Vec<_>>(), ["other"]);
+        assert_eq!(problems.errors.len(), 2);
+        assert!(problems.errors[0].contains("first -> second -> first"));
+        assert!(problems.errors[1].contains("second -> first -> second"));
+    }
+
+    #[test]
+    fn diamond_include() {
+        let dir = env::temp_dir().join(format!("midi-ctrl-include-{}", std::process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        write(
+            dir.join("left.json"),
+            json!([{ "include": "shared.json" }, knob("faders")]).to_string(),
+        )
+        .unwrap();
+        write(
+            dir.join("right.json"),
+            json!({ "controls": [{ "include": "shared.json" }, button("faders")] }).to_string(),
+        )
+        .unwrap();
+        write(dir.join("shared.json"), json!([knob("pads")]).to_string()).unwrap();
+
+        let config: ProfileConfig = serde_json::from_value(json!({
+            "controls": [{ "include": "left.json" }, { "include": "right.json" }]
+        }))
+        .unwrap();
+        let mut problems = Problems::default();
+        let profile = config.into_profile(
+            &dir.join("top.json"),
+            "top",
+            &devices(),
+            None,
+            &mut problems,
+        );
+        let _ = remove_dir_all(&dir);
+
+        let profile = profile.unwrap();
+        assert!(problems.errors.is_empty());
+        let mut controls: Vec<_> = profile.controls.keys().collect();
+        controls.sort_by_key(|info| (&info.device_id, &info.control));
+        assert_eq!(
+            controls,
+            [
+                &info("faders", "Button"),
+                &info("faders", "Knob"),
+                &info("pads", "Knob")
+            ]
+        );
+    }
+
+    #[test]
+    fn unmap_parent_control() {
+        let (profiles, problems) = resolve(
+            &devices(),
+            json!({
+                "parent": { "controls": [knob("pads"), button("pads")] },
+                "child": {
+                    "extends": "parent",
+                    "controls": [{ "unmap": { "device": "pads", "layer": "A", "control": "Button" } }]
+                }
+            }),
+        );
+
+        assert!(problems.errors.is_empty() && problems.warnings.is_empty());
+        let child = &profiles["child"];
+        assert!(child.controls.contains_key(&info("pads", "Knob")));
+        assert!(!child.controls.contains_key(&info("pads", "Button")));
+        assert!(child.unmapped.contains(&info("pads", "Button")));
+        assert!(profiles["parent"]
+            .controls
+            .contains_key(&info("pads", "Button")));
+    }
+
+    #[test]
+    fn highest_priority_wins() {
+        let profiles = profiles(json!({
+            "a": { "priority": 1, "controls": [] },
+            "b": { "priority": 5, "controls": [] },
+            "c": { "priority": 2, "controls": [] }
+        }));
+
+        assert_eq!(selected(&profiles, "pads"), Some("b"));
+    }
+
+    #[test]
+    fn priority_ties_use_id_order() {
+        let profiles = profiles(json!({
+            "b": { "controls": [] },
+            "a": { "controls": [] },
+            "c": { "controls": [] }
+        }));
+
+        assert_eq!(selected(&profiles, "pads"), Some("a"));
+    }
+
+    #[test]
+    fn sticky_profile_stays_selected() {
+        let mut profiles = profiles(json!({
+            "base": { "controls": [] },
+            "masking": { "priority": 1, "if": { "parameter": "Mode", "value": "masking" }, "controls": [] }
+        }));
+        assert_eq!(selected(&profiles, "pads"), Some("base"));
+
+        profiles.state_update(&with_mode("masking"));
+        assert_eq!(selected(&profiles, "pads"), Some("base"));
+    }
+
+    #[test]
+    fn non_sticky_profile_gives_way() {
+        let mut profiles = profiles(json!({
+            "base": { "sticky": false, "controls": [] },
+            "masking": { "priority": 1, "if": { "parameter": "Mode", "value": "masking" }, "controls": [] }
+        }));
+        assert_eq!(selected(&profiles, "pads"), Some("base"));
+
+        profiles.state_update(&with_mode("masking"));
+        assert_eq!(selected(&profiles, "pads"), Some("masking"));
+
+        // Once the condition fails the best remaining profile is selected again.
+        profiles.state_update(&with_mode("develop"));
+        assert_eq!(selected(&profiles, "pads"), Some("base"));
+    }
+
+    /// The parameter the knob on the pads changes in the device's current profile.
+    fn knob_parameter(profiles: &Profiles) -> Option<FloatParam> {
+        let actions = profiles.current_profile("pads")?.continuous_actions(
+            &State::new(),
+            "pads",
+            "Knob",
+            "A",
+            0.5,
+        )?;
+
+        match actions.first() {
+            Some(Action::SetFloatParameter { parameter, .. }) => Some(parameter.clone()),
+            _ => None,
+        }
+    }
+
+    fn has_button(profiles: &Profiles) -> bool {
+        profiles
+            .current_profile("pads")
+            .is_some_and(|profile| profile.controls.contains_key(&info("pads", "Button")))
+    }
+
+    fn overlay_profiles() -> Profiles {
+        profiles(json!({
+            "base": { "controls": [knob("pads"), button("pads")] },
+            "contrast": {
+                "priority": -1,
+                "controls": [{ "device": "pads", "layer": "A", "control": "Knob", "onChange": "Contrast" }]
+            },
+            "shadows": {
+                "priority": -1,
+                "controls": [
+                    { "device": "pads", "layer": "A", "control": "Knob", "onChange": "Shadows" },
+                    { "unmap": { "device": "pads", "layer": "A", "control": "Button" } }
+                ]
+            },
+            "masking": {
+                "priority": -1,
+                "if": { "parameter": "Mode", "value": "masking" },
+                "controls": [{ "device": "pads", "layer": "A", "control": "Knob", "onChange": "Highlights" }]
+            }
+        }))
+    }
+
+    #[test]
+    fn push_and_pop_overlay() {
+        let mut profiles = overlay_profiles();
+        let state = State::new();
+
+        assert_eq!(
+            profiles
+                .push_overlay("contrast", &state)
+                .map(|profile| profile.id),
+            Some(String::from("contrast"))
+        );
+        assert_eq!(knob_parameter(&profiles), Some(FloatParam::Contrast));
+        // Controls the overlay doesn't map fall through to the profile beneath.
+        assert!(has_button(&profiles));
+        assert_eq!(
+            profiles.selections().get("pads").map(String::as_str),
+            Some("base")
+        );
+
+        // Pushing the same profile twice does nothing.
+        assert!(profiles.push_overlay("contrast", &state).is_none());
+
+        assert_eq!(
+            profiles.pop_overlay(None).map(|profile| profile.id),
+            Some(String::from("contrast"))
+        );
+        assert_eq!(knob_parameter(&profiles), Some(FloatParam::Exposure));
+        assert!(profiles.pop_overlay(None).is_none());
+    }
+
+    #[test]
+    fn stacked_overlays() {
+        let mut profiles = overlay_profiles();
+        let state = State::new();
+
+        profiles.push_overlay("contrast", &state).unwrap();
+        profiles.push_overlay("shadows", &state).unwrap();
+        assert_eq!(profiles.overlays(), ["contrast", "shadows"]);
+        assert_eq!(knob_parameter(&profiles), Some(FloatParam::Shadows));
+        assert!(!has_button(&profiles));
+
+        // Popping by name leaves the other overlay in place.
+        profiles.pop_overlay(Some("shadows")).unwrap();
+        assert_eq!(knob_parameter(&profiles), Some(FloatParam::Contrast));
+        assert!(has_button(&profiles));
+
+        profiles.push_overlay("shadows", &state).unwrap();
+        profiles.pop_overlay(Some("contrast")).unwrap();
+        assert_eq!(profiles.overlays(), ["shadows"]);
+        assert_eq!(knob_parameter(&profiles), Some(FloatParam::Shadows));
+    }
+
+    #[test]
+    fn pop_unknown_overlay() {
+        let mut profiles = overlay_profiles();
+        profiles.push_overlay("contrast", &State::new()).unwrap();
+
+        assert!(profiles.pop_overlay(Some("shadows")).is_none());
+        assert!(profiles.pop_overlay(Some("missing")).is_none());
+        assert_eq!(profiles.overlays(), ["contrast"]);
+    }
+
+    #[test]
+    fn conditional_overlay() {
+        let mut profiles = overlay_profiles();
+
+        assert!(profiles.push_overlay("masking", &State::new()).is_none());
+
+        let masking = with_mode("masking");
+        profiles.push_overlay("masking", &masking).unwrap();
+        profiles.push_overlay("contrast", &masking).unwrap();
+        assert!(profiles.pop_disabled_overlays(&masking).is_empty());
+
+        let popped = profiles.pop_disabled_overlays(&with_mode("develop"));
+        assert_eq!(
+            popped
+                .iter()
+                .map(|profile| profile.id.as_str())
+                .collect::<Vec<_>>(),
+            ["masking"]
+        );
+        assert_eq!(profiles.overlays(), ["contrast"]);
+    }
+
+    #[test]
+    fn profile_devices() {
+        let mut profiles = profiles(json!({
+            "develop": { "controls": [] },
+            "culling": { "priority": 1, "devices": ["pads"], "controls": [] }
+        }));
+
+        assert_eq!(selected(&profiles, "pads"), Some("culling"));
+        assert_eq!(selected(&profiles, "faders"), Some("develop"));
+
+        // A profile can't be selected for a device it doesn't apply to.
+        assert!(!profiles.set_profile("culling", Some("faders"), &State::new()));
+        assert_eq!(selected(&profiles, "faders"), Some("develop"));
+    }
+
+    #[test]
+    fn select_profile_per_device() {
+        let mut profiles = profiles(json!({
+            "develop": { "controls": [] },
+            "library": { "controls": [] }
+        }));
+        let state = State::new();
+
+        assert!(profiles.set_profile("library", Some("pads"), &state));
+        assert_eq!(selected(&profiles, "pads"), Some("library"));
+        assert_eq!(selected(&profiles, "faders"), Some("develop"));
+
+        profiles.state_update(&state);
+        assert_eq!(selected(&profiles, "pads"), Some("library"));
+
+        // Without a device every device the profile applies to switches.
+        assert!(profiles.set_profile("develop", None, &state));
+        assert_eq!(selected(&profiles, "pads"), Some("develop"));
+        assert_eq!(selected(&profiles, "faders"), Some("develop"));
     }
 }
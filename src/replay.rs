@@ -0,0 +1,224 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::mpsc::channel,
+    time::Instant,
+};
+
+use midi_control::MidiMessage;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    control::Request,
+    midi::{
+        device::devices,
+        transport::{MemoryTransport, Transport},
+    },
+    monitor::describe,
+    state::persist::PersistedState,
+    ControlMessage, Controller,
+};
+
+/// The first line of a recording, describing what the controller started with.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    #[serde(default)]
+    state: PersistedState,
+}
+
+/// Something the controller received, requests are recorded apart from the other messages as
+/// they carry the channel to respond on.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Event<M, R> {
+    Message(M),
+    Request(R),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry<M, R> {
+    /// Milliseconds since the recording started.
+    time: u128,
+    #[serde(flatten)]
+    event: Event<M, R>,
+}
+
+/// Writes the messages a controller receives to a file, one JSON object per line.
+pub struct Recorder {
+    file: File,
+    header: Header,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Creates the recording. This must happen before the controller connects to the MIDI ports
+    /// so the same ports are available when replaying.
+    pub fn create(path: &Path, transport: &dyn Transport) -> Result<Recorder, String> {
+        Ok(Recorder {
+            file: File::create(path)
+                .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?,
+            header: Header {
+                inputs: transport.inputs().unwrap_or_default(),
+                outputs: transport.outputs().unwrap_or_default(),
+                state: PersistedState::default(),
+            },
+            start: Instant::now(),
+        })
+    }
+
+    pub(crate) fn start(&mut self, state: PersistedState) -> Result<(), String> {
+        self.header.state = state;
+        self.start = Instant::now();
+
+        let mut data = serde_json::to_vec(&self.header)
+            .map_err(|e| format!("Failed to encode recording header: {e}"))?;
+        data.push(0x0a);
+
+        self.file
+            .write_all(&data)
+            .map_err(|e| format!("Failed to write recording: {e}"))
+    }
+
+    pub fn record(&mut self, message: &ControlMessage) -> Result<(), String> {
+        let event = match message {
+            ControlMessage::Request { request, .. } => Event::Request(request),
+            message => Event::Message(message),
+        };
+
+        let mut data = serde_json::to_vec(&Entry {
+            time: self.start.elapsed().as_millis(),
+            event,
+        })
+        .map_err(|e| format!("Failed to encode recorded message: {e}"))?;
+        data.push(0x0a);
+
+        self.file
+            .write_all(&data)
+            .map_err(|e| format!("Failed to write recording: {e}"))
+    }
+}
+
+fn print_feedback(transport: &MemoryTransport, outputs: &[String]) -> Result<(), String> {
+    let mut seen = Vec::new();
+    for port in outputs {
+        if seen.contains(&port) {
+            continue;
+        }
+        seen.push(port);
+
+        for data in transport.take_sent(port)? {
+            let mut line = format!("  midi [{port}] ");
+            for byte in &data {
+                let _ = write!(line, "{byte:02x} ");
+            }
+            line.push_str(&describe(&MidiMessage::from(data.as_slice())));
+            println!("{line}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Feeds a recording through a controller using simulated MIDI ports and prints what it sends to
+/// Lightroom and the devices in response.
+pub fn replay(root: &Path, path: &Path) -> Result<(), String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let mut lines = BufReader::new(file).lines().enumerate();
+
+    let parse_error = |line: usize, e: serde_json::Error| {
+        format!(
+            "Failed to parse {} at line {}: {}",
+            path.display(),
+            line + 1,
+            e
+        )
+    };
+    let read_error = |e: std::io::Error| format!("Failed to read {}: {}", path.display(), e);
+
+    let header: Header = match lines.next() {
+        Some((index, line)) => {
+            serde_json::from_str(&line.map_err(read_error)?).map_err(|e| parse_error(index, e))?
+        }
+        None => return Err(format!("{} is empty", path.display())),
+    };
+
+    let transport = MemoryTransport::new();
+    for input in &header.inputs {
+        transport.add_input(input)?;
+    }
+    for output in &header.outputs {
+        transport.add_output(output)?;
+    }
+
+    let (mut controller, outgoing) = Controller::detached(root, header.state, |sender| {
+        devices(sender, root, &transport)
+    })?;
+
+    println!("Initial feedback");
+    for message in outgoing.try_iter() {
+        println!(
+            "  > {}",
+            serde_json::to_string(&message).unwrap_or_default()
+        );
+    }
+    print_feedback(&transport, &header.outputs)?;
+
+    for (index, line) in lines {
+        let line = line.map_err(read_error)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry: Entry<ControlMessage, Request> =
+            serde_json::from_str(&line).map_err(|e| parse_error(index, e))?;
+        let time = format!("[{}.{:03}]", entry.time / 1000, entry.time % 1000);
+
+        let running = match entry.event {
+            Event::Message(message) => {
+                println!(
+                    "{} {}",
+                    time,
+                    serde_json::to_string(&message).unwrap_or_default()
+                );
+
+                controller.sync_control(&message);
+                controller.handle(message)
+            }
+            Event::Request(request) => {
+                println!(
+                    "{} request {}",
+                    time,
+                    serde_json::to_string(&request).unwrap_or_default()
+                );
+
+                let (reply, response) = channel();
+                let running = controller.handle(ControlMessage::Request { request, reply });
+                if let Ok(response) = response.try_recv() {
+                    println!(
+                        "  response {}",
+                        serde_json::to_string(&response).unwrap_or_default()
+                    );
+                }
+                running
+            }
+        };
+
+        for message in outgoing.try_iter() {
+            println!(
+                "  > {}",
+                serde_json::to_string(&message).unwrap_or_default()
+            );
+        }
+        print_feedback(&transport, &header.outputs)?;
+
+        if !running {
+            break;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,460 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::read_to_string,
+    path::Path,
+    sync::mpsc::{Receiver, Sender},
+};
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+use crate::{
+    diagnostics::{parse, Format},
+    lightroom::OutgoingMessage,
+    midi::{
+        controls::{KeyState, LayerControl},
+        device::{devices, get_layer_control, read_devices, Device},
+        transport::{MemoryTransport, Transport},
+    },
+    profile::Action,
+    state::{params::StringParam, persist::PersistedState, StateValue},
+    utils::Problems,
+    ControlMessage, Controller,
+};
+
+/// How many times Lightroom is allowed to report back changes for a single step. Stops profiles
+/// that keep changing parameters in response to each other from running forever.
+const MAX_ROUNDS: usize = 10;
+
+#[derive(Deserialize, Clone, Copy, Debug, JsonSchema)]
+#[serde(untagged)]
+enum ControlValue {
+    Key(bool),
+    Continuous(f64),
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+#[serde(untagged)]
+enum Event {
+    Control {
+        device: String,
+        control: String,
+        layer: String,
+        value: ControlValue,
+    },
+    State {
+        state: BTreeMap<String, JsonValue>,
+    },
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+struct Feedback {
+    device: String,
+    control: String,
+    layer: String,
+    value: ControlValue,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+#[serde(untagged)]
+enum ExpectedProfile {
+    /// The `Profile` parameter.
+    #[schemars(title = "profile")]
+    Profile(String),
+    /// The profile selected for each listed device.
+    #[schemars(title = "profile for each device")]
+    Devices(BTreeMap<String, String>),
+}
+
+#[derive(Deserialize, Debug, Default, JsonSchema)]
+struct Expect {
+    /// Every action performed, in order.
+    #[serde(default)]
+    actions: Option<Vec<Action>>,
+    /// The selected profile.
+    #[serde(default)]
+    profile: Option<ExpectedProfile>,
+    /// The values last sent to the controls.
+    #[serde(default)]
+    feedback: Vec<Feedback>,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+struct Step {
+    #[serde(flatten)]
+    event: Event,
+    #[serde(default)]
+    expect: Expect,
+}
+
+/// A sequence of changes to run through the profiles along with what should happen.
+#[derive(Deserialize, Debug, JsonSchema)]
+struct Scenario {
+    /// The state reported by Lightroom before the first step.
+    #[serde(default)]
+    state: BTreeMap<String, JsonValue>,
+    #[serde(default)]
+    steps: Vec<Step>,
+}
+
+pub(crate) fn state_values(state: &BTreeMap<String, JsonValue>) -> Result<Vec<StateValue>, String> {
+    state
+        .iter()
+        .map(|(parameter, value)| {
+            if let Ok(value) =
+                serde_json::from_value(json!({ "parameter": parameter, "value": value }))
+            {
+                return Ok(value);
+            }
+
+            match value {
+                JsonValue::String(value) => Ok(StateValue::String {
+                    parameter: StringParam::Custom(parameter.clone()),
+                    value: Some(value.clone()),
+                }),
+                _ => Err(format!(
+                    "Unknown parameter {parameter} or bad value {value}"
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Connects every device to a simulated output port named after the device so the feedback sent
+/// to it can be checked.
+pub(crate) fn simulated_devices(
+    root: &Path,
+    sender: Sender<ControlMessage>,
+) -> HashMap<String, Device> {
+    let mut problems = Problems::default();
+    let configs = read_devices(root, &mut problems);
+    problems.log();
+
+    let transport = MemoryTransport::new();
+    let mut devices = HashMap::new();
+    for (position, (id, config)) in configs.into_iter().enumerate() {
+        if let Err(e) = transport.add_output(&id) {
+            log::error!("{}", e);
+            continue;
+        }
+
+        match Device::new(
+            id.clone(),
+            sender.clone(),
+            config,
+            &transport,
+            None,
+            Some((position, id.as_str())),
+        ) {
+            Ok(device) => {
+                devices.insert(id, device);
+            }
+            Err(e) => log::error!("Failed to create device {}: {}", id, e),
+        }
+    }
+
+    devices
+}
+
+fn actions_match(expected: &Action, actual: &Action) -> bool {
+    match (expected, actual) {
+        (
+            Action::SetFloatParameter {
+                parameter: expected_parameter,
+                value: expected_value,
+            },
+            Action::SetFloatParameter { parameter, value },
+        ) => expected_parameter == parameter && (expected_value - value).abs() < 0.0001,
+        _ => expected == actual,
+    }
+}
+
+/// Sends the messages meant for Lightroom back as state changes, as Lightroom would after
+/// applying them.
+fn echo(controller: &mut Controller, outgoing: &Receiver<OutgoingMessage>) -> Result<(), String> {
+    for _ in 0..MAX_ROUNDS {
+        let values: Vec<StateValue> = outgoing
+            .try_iter()
+            .filter_map(|message| match message {
+                OutgoingMessage::SetValue { parameter, value } => {
+                    serde_json::from_value(json!({ "parameter": parameter, "value": value })).ok()
+                }
+                _ => None,
+            })
+            .collect();
+
+        if values.is_empty() {
+            return Ok(());
+        }
+
+        controller.handle(ControlMessage::StateChange { values });
+    }
+
+    Err(String::from(
+        "Lightroom parameters were still changing after several rounds",
+    ))
+}
+
+fn check_feedback(controller: &Controller, feedback: &Feedback) -> Result<(), String> {
+    let name = format!(
+        "{} in layer {} on {}",
+        feedback.control, feedback.layer, feedback.device
+    );
+
+    match (
+        get_layer_control(
+            &controller.devices,
+            &feedback.device,
+            &feedback.control,
+            &feedback.layer,
+        ),
+        feedback.value,
+    ) {
+        (Some(LayerControl::Continuous(layer_control)), ControlValue::Continuous(expected)) => {
+            let state = *layer_control
+                .state
+                .lock()
+                .map_err(|e| format!("Failed to lock state: {e}"))?;
+            if state == layer_control.state_from_value(expected) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "expected {} to show {} but it shows {:.3}",
+                    name,
+                    expected,
+                    layer_control.value_from_state(state)
+                ))
+            }
+        }
+        (Some(LayerControl::Key(layer_control)), ControlValue::Key(expected)) => {
+            let state = *layer_control
+                .state
+                .lock()
+                .map_err(|e| format!("Failed to lock state: {e}"))?;
+            if state == KeyState::from(expected) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "expected {} to be {} but it is {}",
+                    name,
+                    KeyState::from(expected),
+                    state
+                ))
+            }
+        }
+        (Some(LayerControl::Continuous(_)), ControlValue::Key(_)) => {
+            Err(format!("{name} is a continuous control, expected a number"))
+        }
+        (Some(LayerControl::Key(_)), ControlValue::Continuous(_)) => {
+            Err(format!("{name} is a key, expected true or false"))
+        }
+        (None, _) => Err(format!("Unknown control {name}")),
+    }
+}
+
+fn check(controller: &Controller, expect: &Expect, performed: &[Action]) -> Vec<String> {
+    let mut failures = Vec::new();
+
+    if let Some(ref expected) = expect.actions {
+        let matches = expected.len() == performed.len()
+            && expected
+                .iter()
+                .zip(performed)
+                .all(|(expected, actual)| actions_match(expected, actual));
+
+        if !matches {
+            let list = |actions: &[Action]| {
+                if actions.is_empty() {
+                    String::from("nothing")
+                } else {
+                    actions
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                }
+            };
+
+            failures.push(format!(
+                "expected actions: {}\n    actual actions: {}",
+                list(expected),
+                list(performed)
+            ));
+        }
+    }
+
+    match expect.profile {
+        Some(ExpectedProfile::Profile(ref expected)) => {
+            let actual = controller.state.strings.get(&StringParam::Profile);
+            if actual != Some(expected) {
+                failures.push(format!(
+                    "expected profile {} but it is {}",
+                    expected,
+                    actual.map(String::as_str).unwrap_or("not set")
+                ));
+            }
+        }
+        Some(ExpectedProfile::Devices(ref expected)) => {
+            for (device, expected) in expected {
+                let actual = controller
+                    .profiles
+                    .current_profile(device)
+                    .map(|profile| profile.id);
+                if actual.as_ref() != Some(expected) {
+                    failures.push(format!(
+                        "expected profile {} for {} but it is {}",
+                        expected,
+                        device,
+                        actual.as_deref().unwrap_or("not set")
+                    ));
+                }
+            }
+        }
+        None => (),
+    }
+
+    for feedback in &expect.feedback {
+        if let Err(e) = check_feedback(controller, feedback) {
+            failures.push(e);
+        }
+    }
+
+    failures
+}
+
+/// Runs a scenario against the profiles and devices in the settings directory without any
+/// devices or Lightroom. Returns whether every step had the expected outcome.
+pub fn run_scenario(root: &Path, path: &Path) -> Result<bool, String> {
+    let text =
+        read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let format = Format::from_path(path).unwrap_or(Format::Json);
+    let scenario: Scenario = parse(&text, format).map_err(|e| {
+        format!(
+            "Failed to parse {} {}",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            e
+        )
+    })?;
+
+    let (mut controller, outgoing) =
+        Controller::detached(root, PersistedState::default(), |sender| {
+            simulated_devices(root, sender)
+        })?;
+
+    controller.handle(ControlMessage::StateChange {
+        values: state_values(&scenario.state)?,
+    });
+    echo(&mut controller, &outgoing)?;
+
+    let mut failed = 0;
+    for (index, step) in scenario.steps.iter().enumerate() {
+        let (description, message) = match &step.event {
+            Event::Control {
+                device,
+                control,
+                layer,
+                value: ControlValue::Continuous(value),
+            } => (
+                format!("{control} in layer {layer} on {device} = {value}"),
+                ControlMessage::ContinuousChange {
+                    device_id: device.clone(),
+                    control: control.clone(),
+                    layer: layer.clone(),
+                    value: *value,
+                },
+            ),
+            Event::Control {
+                device,
+                control,
+                layer,
+                value: ControlValue::Key(value),
+            } => (
+                format!(
+                    "{control} in layer {layer} on {device} = {}",
+                    KeyState::from(*value)
+                ),
+                ControlMessage::KeyChange {
+                    device_id: device.clone(),
+                    control: control.clone(),
+                    layer: layer.clone(),
+                    state: KeyState::from(*value),
+                },
+            ),
+            Event::State { state } => (
+                String::from("state change"),
+                ControlMessage::StateChange {
+                    values: state_values(state)?,
+                },
+            ),
+        };
+
+        controller.performed = Some(Vec::new());
+        controller.sync_control(&message);
+        controller.handle(message);
+        let mut failures = match echo(&mut controller, &outgoing) {
+            Ok(()) => Vec::new(),
+            Err(e) => vec![e],
+        };
+        let performed = controller.performed.take().unwrap_or_default();
+        failures.extend(check(&controller, &step.expect, &performed));
+
+        if failures.is_empty() {
+            println!("ok     step {}: {}", index + 1, description);
+        } else {
+            failed += 1;
+            println!("FAILED step {}: {}", index + 1, description);
+            for failure in failures {
+                println!("    {failure}");
+            }
+        }
+    }
+
+    println!(
+        "{} of {} steps passed",
+        scenario.steps.len() - failed,
+        scenario.steps.len()
+    );
+
+    Ok(failed == 0)
+}
+
+/// Drives a controller by hand, with its devices found through a transport such as a
+/// `MemoryTransport`. Nothing is persisted and the parameters sent to Lightroom are reported back
+/// as Lightroom would, so tests can send MIDI messages in and check the feedback sent out.
+pub struct Simulation {
+    controller: Controller,
+    outgoing: Receiver<OutgoingMessage>,
+}
+
+impl Simulation {
+    pub fn new(root: &Path, transport: &dyn Transport) -> Result<Simulation, String> {
+        let (controller, outgoing) =
+            Controller::detached(root, PersistedState::default(), |sender| {
+                devices(sender, root, transport)
+            })?;
+
+        Ok(Simulation {
+            controller,
+            outgoing,
+        })
+    }
+
+    /// Reports parameter values as Lightroom would, in the same form as a scenario's `state`.
+    pub fn set_state(&mut self, state: &BTreeMap<String, JsonValue>) -> Result<(), String> {
+        self.controller.handle(ControlMessage::StateChange {
+            values: state_values(state)?,
+        });
+        echo(&mut self.controller, &self.outgoing)
+    }
+
+    /// Handles every message the devices have sent since the last call.
+    pub fn process(&mut self) -> Result<(), String> {
+        while let Ok(message) = self.controller.receiver.try_recv() {
+            self.controller.handle(message);
+            echo(&mut self.controller, &self.outgoing)?;
+        }
+
+        Ok(())
+    }
+}
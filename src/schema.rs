@@ -0,0 +1,21 @@
+use std::{fs::File, io::Write, path::Path};
+
+use schemars::Schema;
+
+use crate::{midi::device::DeviceConfig, profile::profile_schema};
+
+fn write_schema(path: &Path, schema: &Schema) -> Result<(), String> {
+    let data = serde_json::to_vec_pretty(schema)
+        .map_err(|e| format!("Failed to encode schema {}: {}", path.display(), e))?;
+
+    let mut file =
+        File::create(path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    file.write_all(&data)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Writes JSON schemas for the device and profile configuration files to the directory.
+pub fn write_schemas(dir: &Path) -> Result<(), String> {
+    write_schema(&dir.join("device.schema.json"), &DeviceConfig::schema())?;
+    write_schema(&dir.join("profile.schema.json"), &profile_schema())
+}
@@ -1,9 +1,17 @@
 pub mod params;
+pub mod persist;
 
 use std::hash::Hash;
-use std::{collections::HashMap, convert::TryFrom, fmt::Display};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fmt,
+};
 
-use serde::{Deserialize, Deserializer, Serialize};
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value as JsonValue;
 
 use self::params::{BoolParam, FloatParam, StringParam};
 
@@ -24,6 +32,29 @@ where
     }
 }
 
+/// Writes custom parameters as just their name, the inverse of `deserialize_string_param`.
+pub fn serialize_string_param<S>(param: &StringParam, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match param {
+        StringParam::Custom(name) => serializer.serialize_str(name),
+        param => param.serialize(serializer),
+    }
+}
+
+/// Any of the known string parameters or the name of a custom parameter.
+pub fn string_param_schema(generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "anyOf": [
+            generator.subschema_for::<StringParam>(),
+            {
+                "type": "string"
+            }
+        ]
+    })
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Module {
     Internal,
@@ -38,6 +69,17 @@ pub enum Param {
     String(StringParam),
 }
 
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Param::Float(param) => write!(f, "{:?}", param),
+            Param::Bool(param) => write!(f, "{:?}", param),
+            Param::String(StringParam::Custom(name)) => write!(f, "{}", name),
+            Param::String(param) => write!(f, "{:?}", param),
+        }
+    }
+}
+
 impl From<FloatParam> for Param {
     fn from(val: FloatParam) -> Self {
         Param::Float(val)
@@ -62,13 +104,14 @@ where
 {
     let param: Param = param.clone().into();
     match param {
+        Param::Bool(BoolParam::Connected) => Module::Internal,
         Param::String(StringParam::Profile) => Module::Internal,
         Param::String(StringParam::Custom(_)) => Module::Internal,
         _ => Module::Lightroom,
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum StateValue {
     Float {
@@ -85,6 +128,26 @@ pub enum StateValue {
     },
 }
 
+impl StateValue {
+    /// The parameter's name and its value, null if it was removed.
+    pub fn to_json(&self) -> (String, JsonValue) {
+        match self {
+            StateValue::Float { parameter, value } => (
+                Param::from(parameter.clone()).to_string(),
+                JsonValue::from(*value),
+            ),
+            StateValue::String { parameter, value } => (
+                Param::from(parameter.clone()).to_string(),
+                JsonValue::from(value.clone()),
+            ),
+            StateValue::Bool { parameter, value } => (
+                Param::from(parameter.clone()).to_string(),
+                JsonValue::from(*value),
+            ),
+        }
+    }
+}
+
 pub trait SetMapEntry {
     type Key;
     type Value;
@@ -107,25 +170,7 @@ where
     }
 }
 
-#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
-#[serde(untagged)]
-pub enum Value {
-    String(String),
-    Float(f64),
-    Boolean(bool),
-}
-
-impl Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::String(val) => write!(f, "\"{val}\""),
-            Value::Boolean(val) => write!(f, "{val}"),
-            Value::Float(val) => write!(f, "{val}"),
-        }
-    }
-}
-
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct State {
     pub bools: HashMap<BoolParam, bool>,
     pub floats: HashMap<FloatParam, f64>,
@@ -137,10 +182,39 @@ impl State {
         Default::default()
     }
 
+    /// Clears the parameters that come from Lightroom. Internal parameters such as custom
+    /// variables are kept as they belong to MidiCtrl and should survive Lightroom restarting.
     pub fn clear(&mut self) {
-        self.bools.clear();
-        self.floats.clear();
-        self.strings.clear();
+        self.bools
+            .retain(|param, _| matches!(param_module(param), Module::Internal));
+        self.floats
+            .retain(|param, _| matches!(param_module(param), Module::Internal));
+        self.strings
+            .retain(|param, _| matches!(param_module(param), Module::Internal));
+    }
+
+    /// Every parameter by name.
+    pub fn to_json(&self) -> BTreeMap<String, JsonValue> {
+        let mut values = BTreeMap::new();
+        for (param, value) in &self.bools {
+            values.insert(
+                Param::from(param.clone()).to_string(),
+                JsonValue::from(*value),
+            );
+        }
+        for (param, value) in &self.floats {
+            values.insert(
+                Param::from(param.clone()).to_string(),
+                JsonValue::from(*value),
+            );
+        }
+        for (param, value) in &self.strings {
+            values.insert(
+                Param::from(param.clone()).to_string(),
+                JsonValue::from(value.clone()),
+            );
+        }
+        values
     }
 
     pub fn update(&mut self, values: Vec<StateValue>) {
@@ -154,16 +228,24 @@ impl State {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default)]
 #[serde(try_from = "String")]
 pub enum GeneralComparison {
+    #[default]
     Equal,
     NotEqual,
 }
 
-impl Default for GeneralComparison {
-    fn default() -> Self {
-        GeneralComparison::Equal
+impl JsonSchema for GeneralComparison {
+    fn schema_name() -> Cow<'static, str> {
+        "GeneralComparison".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "enum": ["==", "!="]
+        })
     }
 }
 
@@ -179,9 +261,10 @@ impl TryFrom<String> for GeneralComparison {
     }
 }
 
-#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[derive(Deserialize, PartialEq, Debug, Clone, Default)]
 #[serde(try_from = "String")]
 pub enum NumericComparison {
+    #[default]
     Equal,
     NotEqual,
     LessThan,
@@ -190,9 +273,16 @@ pub enum NumericComparison {
     GreaterThanEqual,
 }
 
-impl Default for NumericComparison {
-    fn default() -> Self {
-        NumericComparison::Equal
+impl JsonSchema for NumericComparison {
+    fn schema_name() -> Cow<'static, str> {
+        "NumericComparison".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "enum": ["==", "!=", "<", "<=", ">", ">="]
+        })
     }
 }
 
@@ -212,34 +302,39 @@ impl TryFrom<String> for NumericComparison {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 #[serde(untagged)]
-#[serde(rename = "camelCase")]
 pub enum Condition {
+    #[schemars(title = "any condition")]
     Any {
         any: Vec<Condition>,
         #[serde(default)]
         invert: bool,
     },
+    #[schemars(title = "all condition")]
     All {
         all: Vec<Condition>,
         #[serde(default)]
         invert: bool,
     },
+    #[schemars(title = "numeric comparison")]
     NumericComparison {
         parameter: FloatParam,
         #[serde(default)]
         comparison: NumericComparison,
         value: Option<f64>,
     },
+    #[schemars(title = "boolean comparison")]
     BoolComparison {
         parameter: BoolParam,
         #[serde(default)]
         comparison: GeneralComparison,
         value: Option<bool>,
     },
+    #[schemars(title = "string comparison")]
     StringComparison {
         #[serde(deserialize_with = "deserialize_string_param")]
+        #[schemars(schema_with = "string_param_schema")]
         parameter: StringParam,
         #[serde(default)]
         comparison: GeneralComparison,
@@ -248,6 +343,23 @@ pub enum Condition {
 }
 
 impl Condition {
+    /// Lists the string parameters this condition tests.
+    pub fn string_parameters(&self) -> Vec<&StringParam> {
+        match self {
+            Condition::Any {
+                any: conditions, ..
+            }
+            | Condition::All {
+                all: conditions, ..
+            } => conditions
+                .iter()
+                .flat_map(Condition::string_parameters)
+                .collect(),
+            Condition::StringComparison { parameter, .. } => vec![parameter],
+            _ => Vec::new(),
+        }
+    }
+
     pub fn matches(&self, state: &State) -> bool {
         match self {
             Condition::Any { any, invert } => {
@@ -325,3 +437,125 @@ impl Condition {
         }
     }
 }
+
+impl fmt::Display for GeneralComparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeneralComparison::Equal => write!(f, "=="),
+            GeneralComparison::NotEqual => write!(f, "!="),
+        }
+    }
+}
+
+impl fmt::Display for NumericComparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NumericComparison::Equal => write!(f, "=="),
+            NumericComparison::NotEqual => write!(f, "!="),
+            NumericComparison::LessThan => write!(f, "<"),
+            NumericComparison::LessThanEqual => write!(f, "<="),
+            NumericComparison::GreaterThan => write!(f, ">"),
+            NumericComparison::GreaterThanEqual => write!(f, ">="),
+        }
+    }
+}
+
+fn unset_or<T: fmt::Display>(value: Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => String::from("unset"),
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, conditions, invert) = match self {
+            Condition::Any { any, invert } => ("any", any, invert),
+            Condition::All { all, invert } => ("all", all, invert),
+            Condition::NumericComparison {
+                parameter,
+                comparison,
+                value,
+            } => {
+                return write!(
+                    f,
+                    "{} {} {}",
+                    Param::from(parameter.clone()),
+                    comparison,
+                    unset_or(*value)
+                )
+            }
+            Condition::BoolComparison {
+                parameter,
+                comparison,
+                value,
+            } => {
+                return write!(
+                    f,
+                    "{} {} {}",
+                    Param::from(parameter.clone()),
+                    comparison,
+                    unset_or(*value)
+                )
+            }
+            Condition::StringComparison {
+                parameter,
+                comparison,
+                value,
+            } => {
+                return write!(
+                    f,
+                    "{} {} {}",
+                    Param::from(parameter.clone()),
+                    comparison,
+                    unset_or(value.as_ref())
+                )
+            }
+        };
+
+        let list: Vec<String> = conditions.iter().map(ToString::to_string).collect();
+        if *invert {
+            write!(f, "not ")?;
+        }
+        write!(f, "{} of ({})", name, list.join(", "))
+    }
+}
+
+impl Condition {
+    /// Describes the current value of each parameter this condition tests.
+    pub fn current_values(&self, state: &State) -> Vec<String> {
+        let mut values = Vec::new();
+
+        match self {
+            Condition::Any {
+                any: conditions, ..
+            }
+            | Condition::All {
+                all: conditions, ..
+            } => {
+                for value in conditions.iter().flat_map(|c| c.current_values(state)) {
+                    if !values.contains(&value) {
+                        values.push(value);
+                    }
+                }
+            }
+            Condition::NumericComparison { parameter, .. } => values.push(format!(
+                "{} is {}",
+                Param::from(parameter.clone()),
+                unset_or(state.floats.get(parameter))
+            )),
+            Condition::BoolComparison { parameter, .. } => values.push(format!(
+                "{} is {}",
+                Param::from(parameter.clone()),
+                unset_or(state.bools.get(parameter))
+            )),
+            Condition::StringComparison { parameter, .. } => values.push(format!(
+                "{} is {}",
+                Param::from(parameter.clone()),
+                unset_or(state.strings.get(parameter))
+            )),
+        }
+
+        values
+    }
+}
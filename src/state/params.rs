@@ -1,6 +1,7 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone, JsonSchema)]
 pub enum FloatParam {
     Temperature,
     Tint,
@@ -108,17 +109,20 @@ pub enum FloatParam {
     Rating,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone, JsonSchema)]
 pub enum BoolParam {
     Rejected,
     Picked,
     CanUndo,
     CanRedo,
+    /// Whether the Lightroom plugin is connected.
+    Connected,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone, JsonSchema)]
 pub enum StringParam {
     Module,
     Profile,
+    #[schemars(skip)]
     Custom(String),
 }
@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{rename, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use super::{params::StringParam, State};
+
+const STATE_FILE: &str = "state.json";
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The internal parameters that survive a restart.
+#[derive(Serialize, Deserialize, Default, PartialEq, Clone, Debug)]
+pub struct PersistedState {
+    /// The profile selected for each device.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, String>,
+    /// The profiles pushed over the selected profiles, in the order they were pushed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overlays: Vec<String>,
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+    /// The single profile saved before profiles were selected per device, only read.
+    #[serde(default, skip_serializing)]
+    pub profile: Option<String>,
+}
+
+impl PersistedState {
+    pub fn new(
+        state: &State,
+        profiles: &BTreeMap<String, String>,
+        overlays: &[String],
+    ) -> PersistedState {
+        let mut persisted = PersistedState {
+            profiles: profiles.clone(),
+            overlays: overlays.to_vec(),
+            ..Default::default()
+        };
+
+        for (param, value) in &state.strings {
+            if let StringParam::Custom(name) = param {
+                persisted.variables.insert(name.clone(), value.clone());
+            }
+        }
+
+        persisted
+    }
+
+    pub fn read(root: &Path) -> Result<Option<PersistedState>, String> {
+        let path = root.join(STATE_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to open {}: {}", path.display(), e)),
+        };
+
+        serde_json::from_reader(file).map(Some).map_err(|e| {
+            format!(
+                "Failed to parse {} at line {}, column {}: {}",
+                path.display(),
+                e.line(),
+                e.column(),
+                e
+            )
+        })
+    }
+
+    /// Restores the custom variables into the state. The profiles are restored separately as
+    /// they must be selected through `Profiles`.
+    pub fn apply(&self, state: &mut State) {
+        for (name, value) in &self.variables {
+            state
+                .strings
+                .insert(StringParam::Custom(name.clone()), value.clone());
+        }
+    }
+
+    fn write(&self, root: &Path) -> Result<(), String> {
+        let path = root.join(STATE_FILE);
+        let temp = path.with_extension("json.tmp");
+
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(|e| format!("Failed to encode persisted state: {e}"))?;
+
+        let mut file = File::create(&temp)
+            .map_err(|e| format!("Failed to create {}: {}", temp.display(), e))?;
+        file.write_all(&data)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("Failed to write {}: {}", temp.display(), e))?;
+
+        rename(&temp, &path).map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+    }
+}
+
+/// Writes the internal parameters to the state file whenever they change. Writes happen on a
+/// background thread once the state has been stable for a short time.
+pub struct StatePersister {
+    last: PersistedState,
+    sender: Option<Sender<PersistedState>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StatePersister {
+    pub fn new(root: &Path, initial: PersistedState) -> StatePersister {
+        let root: PathBuf = root.to_path_buf();
+        let (sender, receiver) = channel::<PersistedState>();
+
+        let thread = thread::spawn(move || {
+            while let Ok(mut pending) = receiver.recv() {
+                loop {
+                    match receiver.recv_timeout(DEBOUNCE) {
+                        Ok(state) => pending = state,
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+
+                if let Err(e) = pending.write(&root) {
+                    log::error!("{}", e);
+                } else {
+                    log::trace!("Persisted internal state");
+                }
+            }
+        });
+
+        StatePersister {
+            last: initial,
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    /// A persister that never writes anything.
+    pub fn disabled(initial: PersistedState) -> StatePersister {
+        StatePersister {
+            last: initial,
+            sender: None,
+            thread: None,
+        }
+    }
+
+    pub fn update(&mut self, persisted: PersistedState) {
+        if persisted == self.last {
+            return;
+        }
+
+        if let Some(ref sender) = self.sender {
+            if let Err(e) = sender.send(persisted.clone()) {
+                log::error!("Failed to queue state for persisting: {}", e);
+            }
+        }
+
+        self.last = persisted;
+    }
+}
+
+impl Drop for StatePersister {
+    fn drop(&mut self) {
+        // Closing the channel flushes any pending write.
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -1,83 +1,161 @@
+use schemars::JsonSchema;
 use std::{
-    fs::{read_dir, File, ReadDir},
+    collections::BTreeMap,
+    env::current_dir,
+    fs::{read_dir, read_to_string},
     io,
     marker::PhantomData,
-    path::Path,
+    path::{Path, PathBuf},
+    vec::IntoIter,
 };
 
 use serde::de::DeserializeOwned;
 
-pub struct IterJson<T>
+use crate::diagnostics::{parse, Format};
+
+/// The settings directory used when none is given.
+pub fn default_settings_dir() -> Result<PathBuf, String> {
+    match dirs::config_dir() {
+        Some(mut dir) => {
+            dir.push("midi-ctrl");
+            Ok(dir)
+        }
+        None => match current_dir() {
+            Ok(dir) => Ok(dir),
+            Err(e) => Err(format!("Failed to find settings directory: {e}")),
+        },
+    }
+}
+
+pub struct IterConfig<T>
 where
-    T: DeserializeOwned,
+    T: DeserializeOwned + JsonSchema,
 {
     data_type: PhantomData<T>,
-    dir_reader: ReadDir,
+    entries: IntoIter<Result<(String, PathBuf, Format), String>>,
 }
 
-impl<T> Iterator for IterJson<T>
+impl<T> Iterator for IterConfig<T>
 where
-    T: DeserializeOwned,
+    T: DeserializeOwned + JsonSchema,
 {
-    type Item = Result<(String, T), String>;
+    type Item = Result<(String, PathBuf, T), String>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            let entry = match self.dir_reader.next() {
-                None => return None,
-                Some(Ok(entry)) => entry,
-                Some(Err(_)) => continue,
-            };
-
-            let file_type = match entry.file_type() {
-                Ok(file_type) => file_type,
-                Err(_) => continue,
-            };
-
-            if !file_type.is_file() {
-                continue;
-            }
-
-            let file_name = match entry.file_name().into_string() {
-                Ok(name) => name,
-                Err(_) => continue,
+            let (id, path, format) = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
             };
 
-            if !file_name.ends_with(".json") {
-                continue;
-            }
-
-            let reader = match File::open(entry.path()) {
-                Ok(reader) => reader,
+            let text = match read_to_string(&path) {
+                Ok(text) => text,
                 Err(_) => continue,
             };
 
-            let mut name = file_name.clone();
-            name.truncate(name.len() - 5);
-
-            match serde_json::from_reader(reader) {
-                Ok(data) => return Some(Ok((name, data))),
-                Err(e) => {
-                    return Some(Err(format!(
-                        "Failed to parse {} at line {}, column {}: {}",
-                        file_name,
-                        e.line(),
-                        e.column(),
-                        e
-                    )))
-                }
-            }
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            return Some(match parse(&text, format) {
+                Ok(data) => Ok((id, path.clone(), data)),
+                Err(e) => Err(format!("Failed to parse {} {}", file_name, e)),
+            });
         }
     }
 }
 
-pub fn iter_json<T>(path: &Path) -> Result<IterJson<T>, io::Error>
+/// Iterates over the configuration files in a directory in any of the supported formats. Files
+/// are identified by their name without the extension, if more than one file has the same name
+/// then none of them are used.
+pub fn iter_config<T>(path: &Path) -> Result<IterConfig<T>, io::Error>
 where
-    T: DeserializeOwned,
+    T: DeserializeOwned + JsonSchema,
 {
-    let dir_reader = read_dir(path)?;
-    Ok(IterJson {
+    let mut files: BTreeMap<String, Vec<(PathBuf, Format)>> = BTreeMap::new();
+
+    for entry in read_dir(path)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_file() => (),
+            _ => continue,
+        }
+
+        let path = entry.path();
+        let format = match Format::from_path(&path) {
+            Some(format) => format,
+            None => continue,
+        };
+
+        let id = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(id) => String::from(id),
+            None => continue,
+        };
+
+        files.entry(id).or_default().push((path, format));
+    }
+
+    let entries: Vec<Result<(String, PathBuf, Format), String>> = files
+        .into_iter()
+        .map(|(id, mut paths)| {
+            if paths.len() == 1 {
+                let (path, format) = paths.remove(0);
+                Ok((id, path, format))
+            } else {
+                let mut names: Vec<String> = paths
+                    .iter()
+                    .map(|(path, _)| {
+                        path.file_name()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                            .into()
+                    })
+                    .collect();
+                names.sort();
+                Err(format!(
+                    "Found multiple files for {}, ignoring {}",
+                    id,
+                    names.join(", ")
+                ))
+            }
+        })
+        .collect();
+
+    Ok(IterConfig {
         data_type: PhantomData,
-        dir_reader,
+        entries: entries.into_iter(),
     })
 }
+
+/// Collects the problems found while loading the configuration.
+#[derive(Default, Debug)]
+pub struct Problems {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Problems {
+    pub fn error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    pub fn warning(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+
+    pub fn append(&mut self, mut other: Problems) {
+        self.errors.append(&mut other.errors);
+        self.warnings.append(&mut other.warnings);
+    }
+
+    pub fn log(&self) {
+        for error in &self.errors {
+            log::error!("{}", error);
+        }
+
+        for warning in &self.warnings {
+            log::warn!("{}", warning);
+        }
+    }
+}
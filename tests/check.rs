@@ -0,0 +1,129 @@
+use std::{
+    env,
+    fs::{create_dir_all, remove_dir_all, write},
+    path::PathBuf,
+    process::Command,
+};
+
+use serde_json::{json, Value as JsonValue};
+
+/// A settings directory with a device and the given profiles, removed when dropped.
+struct Settings {
+    root: PathBuf,
+}
+
+impl Settings {
+    fn new(name: &str, profiles: JsonValue) -> Settings {
+        Settings::with_layer(name, json!({ "channel": 1, "note": 8 }), profiles)
+    }
+
+    /// Uses the given layer for the device's button.
+    fn with_layer(name: &str, layer: JsonValue, profiles: JsonValue) -> Settings {
+        let root = env::temp_dir().join(format!("midi-ctrl-check-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&root);
+        create_dir_all(root.join("devices")).unwrap();
+        create_dir_all(root.join("profiles")).unwrap();
+
+        let device = json!({
+            "port": "Test Controller",
+            "controls": [
+                { "type": "key", "name": "Button", "layers": { "A": layer } }
+            ]
+        });
+        write(root.join("devices/test.json"), device.to_string()).unwrap();
+
+        for (id, profile) in profiles.as_object().unwrap() {
+            write(
+                root.join(format!("profiles/{id}.json")),
+                profile.to_string(),
+            )
+            .unwrap();
+        }
+
+        Settings { root }
+    }
+
+    /// Runs `midi-ctrl check` and returns whether it succeeded.
+    fn check(&self, strict: bool) -> bool {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_midi-ctrl"));
+        command.arg("check");
+        if strict {
+            command.arg("--strict");
+        }
+
+        command.arg(&self.root).output().unwrap().status.success()
+    }
+}
+
+impl Drop for Settings {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.root);
+    }
+}
+
+fn button(on_press: JsonValue) -> JsonValue {
+    json!({ "device": "test", "layer": "A", "control": "Button", "onPress": on_press })
+}
+
+#[test]
+fn no_problems() {
+    let settings = Settings::new(
+        "clean",
+        json!({ "base": { "controls": [button(json!({ "toggle": "Picked" }))] } }),
+    );
+
+    assert!(settings.check(false));
+    assert!(settings.check(true));
+}
+
+#[test]
+fn unset_parameter() {
+    let settings = Settings::new(
+        "unset",
+        json!({
+            "base": { "controls": [button(json!({ "toggle": "Picked" }))] },
+            "masking": { "if": { "parameter": "Mode", "value": "masking" }, "priority": 1, "controls": [] }
+        }),
+    );
+
+    assert!(settings.check(false));
+    assert!(!settings.check(true));
+}
+
+#[test]
+fn unreachable_profile() {
+    let settings = Settings::new(
+        "unreachable",
+        json!({
+            "base": { "controls": [] },
+            "hidden": { "priority": -1, "controls": [] }
+        }),
+    );
+
+    assert!(settings.check(false));
+    assert!(!settings.check(true));
+}
+
+#[test]
+fn unknown_profile() {
+    let settings = Settings::new(
+        "unknown",
+        json!({
+            "base": { "controls": [button(json!({ "parameter": "Profile", "value": "missing" }))] }
+        }),
+    );
+
+    assert!(!settings.check(false));
+    assert!(!settings.check(true));
+}
+
+#[test]
+fn osc_label_on_midi_device() {
+    let settings = Settings::with_layer(
+        "label",
+        json!({ "channel": 1, "note": 8, "label": "/1/label1" }),
+        json!({ "base": { "controls": [button(json!({ "toggle": "Picked" }))] } }),
+    );
+
+    assert!(!settings.check(false));
+}
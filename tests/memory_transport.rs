@@ -0,0 +1,124 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{create_dir_all, remove_dir_all, write},
+    path::PathBuf,
+};
+
+use midi_ctrl::{scenario::Simulation, MemoryTransport};
+use serde_json::json;
+
+const PORT: &str = "Test Controller";
+
+/// A settings directory with a device that has a knob and a button, removed when dropped.
+struct Settings {
+    root: PathBuf,
+}
+
+impl Settings {
+    fn new(name: &str) -> Settings {
+        let root = env::temp_dir().join(format!("midi-ctrl-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&root);
+        create_dir_all(root.join("devices")).unwrap();
+        create_dir_all(root.join("profiles")).unwrap();
+
+        let device = json!({
+            "port": PORT,
+            "controls": [
+                { "type": "cc", "name": "Knob", "layers": { "A": { "channel": 1, "control": 1 } } },
+                { "type": "key", "name": "Button", "layers": { "A": { "channel": 1, "note": 8 } } }
+            ]
+        });
+        write(root.join("devices/test.json"), device.to_string()).unwrap();
+
+        let profile = json!({
+            "controls": [
+                { "device": "test", "layer": "A", "control": "Knob", "onChange": "Exposure", "valueSource": "Exposure" },
+                { "device": "test", "layer": "A", "control": "Button", "onPress": { "toggle": "Picked" } }
+            ]
+        });
+        write(root.join("profiles/base.json"), profile.to_string()).unwrap();
+
+        Settings { root }
+    }
+}
+
+impl Drop for Settings {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.root);
+    }
+}
+
+fn setup(name: &str) -> (Settings, MemoryTransport, Simulation) {
+    let settings = Settings::new(name);
+    let transport = MemoryTransport::new();
+    transport.add_port(PORT).unwrap();
+
+    let mut simulation = Simulation::new(&settings.root, &transport).unwrap();
+    let state: BTreeMap<String, serde_json::Value> = serde_json::from_value(json!({
+        "Module": "develop",
+        "Exposure": 0.0,
+        "Picked": false
+    }))
+    .unwrap();
+    simulation.set_state(&state).unwrap();
+    transport.take_sent(PORT).unwrap();
+
+    (settings, transport, simulation)
+}
+
+#[test]
+fn initial_feedback() {
+    let settings = Settings::new("initial");
+    let transport = MemoryTransport::new();
+    transport.add_port(PORT).unwrap();
+
+    let _simulation = Simulation::new(&settings.root, &transport).unwrap();
+
+    // Every control is turned off when the device is connected.
+    assert_eq!(
+        transport.take_sent(PORT).unwrap(),
+        vec![vec![0xB0, 1, 0], vec![0x80, 8, 0]]
+    );
+}
+
+#[test]
+fn key_feedback() {
+    let (_settings, transport, mut simulation) = setup("key");
+
+    transport.receive(PORT, &[0x90, 8, 127]).unwrap();
+    transport.receive(PORT, &[0x80, 8, 0]).unwrap();
+    simulation.process().unwrap();
+
+    // The button lights up once Lightroom reports that the photo is picked.
+    assert_eq!(transport.take_sent(PORT).unwrap(), vec![vec![0x90, 8, 127]]);
+
+    transport.receive(PORT, &[0x90, 8, 127]).unwrap();
+    transport.receive(PORT, &[0x80, 8, 0]).unwrap();
+    simulation.process().unwrap();
+
+    assert!(transport.take_sent(PORT).unwrap().is_empty());
+}
+
+#[test]
+fn continuous_feedback() {
+    let (_settings, transport, mut simulation) = setup("continuous");
+
+    let state: BTreeMap<String, serde_json::Value> =
+        serde_json::from_value(json!({ "Exposure": 1.0 })).unwrap();
+    simulation.set_state(&state).unwrap();
+
+    assert_eq!(transport.take_sent(PORT).unwrap(), vec![vec![0xB0, 1, 127]]);
+
+    // Moving the knob changes the parameter but doesn't echo the same position back.
+    transport.receive(PORT, &[0xB0, 1, 64]).unwrap();
+    simulation.process().unwrap();
+
+    assert!(transport.take_sent(PORT).unwrap().is_empty());
+
+    let state: BTreeMap<String, serde_json::Value> =
+        serde_json::from_value(json!({ "Exposure": 0.0 })).unwrap();
+    simulation.set_state(&state).unwrap();
+
+    assert_eq!(transport.take_sent(PORT).unwrap(), vec![vec![0xB0, 1, 0]]);
+}